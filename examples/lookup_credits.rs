@@ -26,7 +26,7 @@ fn main() {
     println!("Looking up credits");
 
     // Look up ID
-    let api = ApiBuilder::new(from, secret).into_simple();
+    let api = ApiBuilder::new(from, secret).into_simple().unwrap();
     match api.lookup_credits() {
         Err(e) => {
             println!("Could not look up credits: {}", e);
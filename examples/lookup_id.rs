@@ -45,12 +45,13 @@ fn main() {
     });
 
     // Look up ID
-    let api = ApiBuilder::new(from, secret).into_simple();
+    let api = ApiBuilder::new(from, secret).into_simple().unwrap();
     match api.lookup_id(&criterion) {
         Err(e) => {
             println!("Could not look up id: {:?}", e);
             process::exit(1);
         },
-        Ok(id) => println!("The id is {}", id),
+        Ok(Some(id)) => println!("The id is {}", id),
+        Ok(None) => println!("No id found"),
     }
 }
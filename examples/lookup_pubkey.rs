@@ -24,7 +24,7 @@ fn main() {
     let secret = args.get_str("<secret>");
 
     // Fetch public key
-    let api = ApiBuilder::new(our_id, secret).into_simple();
+    let api = ApiBuilder::new(our_id, secret).into_simple().unwrap();
     let pubkey = api.lookup_pubkey(their_id);
 
     // Show result
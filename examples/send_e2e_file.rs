@@ -10,7 +10,6 @@ use std::path::Path;
 use std::process;
 use docopt::Docopt;
 use mime_guess::guess_mime_type;
-use sodiumoxide::crypto::secretbox;
 use threema_gateway::{ApiBuilder, RecipientKey};
 
 
@@ -84,18 +83,11 @@ fn main() {
     // Make sure to init sodiumoxide library
     sodiumoxide::init();
 
-    // Generate a random encryption key
-    let key = secretbox::gen_key();
-    let file_nonce = secretbox::Nonce([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,1]);
-    let thumb_nonce = secretbox::Nonce([0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,2]);
-
-    // Encrypt files
-    let encrypted_file = secretbox::seal(&file_data, &file_nonce, &key);
-    let encrypted_thumb = thumb_data.map(|t| secretbox::seal(&t, &thumb_nonce, &key));
-
-    // Upload files to blob server
-    let file_blob_id = etry!(api.blob_upload_raw(&encrypted_file), "Could not upload file to blob server");
-    let thumb_blob_id = encrypted_thumb.map(|t| etry!(api.blob_upload_raw(&t), "Could not upload thumbnail to blob server"));
+    // Encrypt files with a random blob key and upload them to blob server
+    let (file_blob_id, thumb_blob_id, key) = etry!(
+        api.upload_file_data(&file_data, thumb_data.as_ref().map(|t| &t[..])),
+        "Could not upload file to blob server"
+    );
 
     // Create file message
     let mime_type = guess_mime_type(&filepath);
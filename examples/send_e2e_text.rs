@@ -44,7 +44,10 @@ fn main() {
         println!("{}", e);
         process::exit(1);
     });
-    let encrypted = api.encrypt_text_msg(&text, &recipient_key);
+    let encrypted = api.encrypt_text_msg(&text, &recipient_key).unwrap_or_else(|e| {
+        println!("Could not encrypt message: {}", e);
+        process::exit(1);
+    });
     let msg_id = api.send(&to, &encrypted);
 
     match msg_id {
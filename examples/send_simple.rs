@@ -39,7 +39,7 @@ fn main() {
     };
 
     // Send
-    let api = ApiBuilder::new(from, secret).into_simple();
+    let api = ApiBuilder::new(from, secret).into_simple().unwrap();
     let msg_id = api.send(&recipient, &text);
     match msg_id {
         Ok(id) => println!("Sent. Message id is {}.", id),
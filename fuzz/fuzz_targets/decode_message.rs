@@ -0,0 +1,9 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate threema_gateway;
+
+use threema_gateway::Message;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Message::decode(data);
+});
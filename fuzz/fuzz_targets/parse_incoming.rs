@@ -0,0 +1,11 @@
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate threema_gateway;
+
+use threema_gateway::IncomingMessage;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(msg) = IncomingMessage::from_urlencoded_bytes(data) {
+        let _ = msg.timestamp();
+    }
+});
@@ -1,19 +1,40 @@
-use std::borrow::{Borrow, Cow};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
-use data_encoding::HEXLOWER_PERMISSIVE;
 use mime::Mime;
+use reqwest::{Certificate, Proxy};
 use sodiumoxide::crypto::box_::SecretKey;
-use sodiumoxide::crypto::secretbox::Key;
+use sodiumoxide::crypto::hash::sha256;
 
 use ::MSGAPI_URL;
-use ::connection::{Recipient, send_e2e, send_simple, blob_upload};
-use ::crypto::{encrypt, encrypt_raw, encrypt_image_msg, encrypt_file_msg};
-use ::crypto::{EncryptedMessage, RecipientKey};
+use ::chunked::FileChunk;
+use ::connection::{ClientConfig, Connection, DEFAULT_USER_AGENT, Recipient, send_e2e_encrypted, send_simple, blob_upload, split_text};
+use ::connection::{blob_download, blob_info, send_e2e, send_e2e_dated, BlobInfo, RawResponse, SentMessage};
+use ::connection::blob_upload_with_progress;
+use ::connection::{MAX_SIMPLE_MESSAGE_LEN, RedirectPolicy, check_control_chars, check_e2e_text, check_simple_text};
+use ::connection::is_valid_threema_id;
+use ::crypto::{encrypt, encrypt_raw, encrypt_image_msg, encrypt_file_msg, encrypt_delete_msg, decrypt_with_any_key};
+use ::crypto::{encrypt_precomputed, encrypt_raw_precomputed, encrypt_file_data, decrypt_raw};
+use ::crypto::{decrypt_thumbnail_blob, decrypt_blob_in_place, encrypt_blob};
+use ::crypto::{encrypt_group_create_msg, encrypt_group_rename_msg, encrypt_group_set_photo_msg};
+use ::crypto::{encrypt_delivery_receipt, decrypt_precomputed, encrypt_voip_reject, encrypt_voip_hangup};
+use ::crypto::encrypt_typing_indicator;
+use ::credentials::{CredentialSource, DirCredentials};
+use ::crypto::{EncryptedMessage, PreparedMessage, RecipientKey, PrecomputedKey};
 use ::errors::{ApiBuilderError, ApiError};
-use ::lookup::{LookupCriterion, Capabilities};
+use ::hex::decode_hex_fixed;
+use ::lookup::{LookupCriterion, Capabilities, GatewayStatus, hash_phone, hash_email};
 use ::lookup::{lookup_id, lookup_pubkey, lookup_capabilities, lookup_credits};
-use ::types::{MessageType, BlobId};
+use ::middleware::RequestMiddleware;
+use ::message::{decode, Message, ReceiptType, VoipRejectReason};
+use ::receive::IncomingMessage;
+use ::semaphore::Semaphore;
+use ::types::{MessageType, MessageFlags, BlobId, BlobKey, BlobDownloadMode, GroupId, MessageId};
 
 /// Implement methods available on both the simple and the e2e API objects.
 macro_rules! impl_common_functionality {
@@ -29,8 +50,22 @@ macro_rules! impl_common_functionality {
         ///
         /// It is strongly recommended that you cache the public keys to avoid querying
         /// the API for each message.
+        ///
+        /// If the negative lookup cache is enabled (see
+        /// [`ApiBuilder::with_negative_cache_ttl`](struct.ApiBuilder.html#method.with_negative_cache_ttl)),
+        /// an ID that was recently not found results in
+        /// `ApiError::IdNotFound` without querying the gateway.
         pub fn lookup_pubkey(&self, id: &str) -> Result<String, ApiError> {
-            lookup_pubkey(self.endpoint.borrow(), &self.id, id, &self.secret)
+            if let Some(ref cache) = self.settings.negative_lookup_cache {
+                if cache.contains("pubkey", id) {
+                    return Err(ApiError::IdNotFound);
+                }
+            }
+            let result = self.settings.timed("lookup_pubkey", || lookup_pubkey(&self.conn, &self.id, id, &self.secret));
+            if let (&Err(ApiError::IdNotFound), &Some(ref cache)) = (&result, &self.settings.negative_lookup_cache) {
+                cache.insert("pubkey", id);
+            }
+            result
         }
 
         /// Look up a Threema ID in the directory.
@@ -39,8 +74,45 @@ macro_rules! impl_common_functionality {
         /// address, in plaintext or hashed form. You can specify one of those
         /// criteria using the [`LookupCriterion`](enum.LookupCriterion.html)
         /// enum.
-        pub fn lookup_id(&self, criterion: &LookupCriterion) -> Result<String, ApiError> {
-            lookup_id(self.endpoint.borrow(), criterion, &self.id, &self.secret)
+        ///
+        /// A phone number or e-mail address is linked to at most one Threema
+        /// ID, so the gateway returns exactly one ID. If there is no match,
+        /// `Ok(None)` is returned, errors are reserved for failed lookups.
+        ///
+        /// If the negative lookup cache is enabled (see
+        /// [`ApiBuilder::with_negative_cache_ttl`](struct.ApiBuilder.html#method.with_negative_cache_ttl)),
+        /// a criterion without a match returns `Ok(None)` without querying
+        /// the gateway until the entry expires.
+        pub fn lookup_id(&self, criterion: &LookupCriterion) -> Result<Option<String>, ApiError> {
+            let query = criterion.to_string();
+            if let Some(ref cache) = self.settings.negative_lookup_cache {
+                if cache.contains("id", &query) {
+                    return Ok(None);
+                }
+            }
+            let id = self.settings.timed("lookup_id", || lookup_id(&self.conn, criterion, &self.id, &self.secret))?;
+            if let (&None, &Some(ref cache)) = (&id, &self.settings.negative_lookup_cache) {
+                cache.insert("id", &query);
+            }
+            Ok(id)
+        }
+
+        /// Look up a Threema ID by phone number.
+        ///
+        /// The phone number is hashed with
+        /// [`hash_phone`](fn.hash_phone.html) before the lookup, so only the
+        /// hash is sent to the gateway.
+        pub fn lookup_id_by_phone(&self, phone: &str) -> Result<Option<String>, ApiError> {
+            self.lookup_id(&LookupCriterion::PhoneHash(hash_phone(phone)))
+        }
+
+        /// Look up a Threema ID by e-mail address.
+        ///
+        /// The address is normalized and hashed with
+        /// [`hash_email`](fn.hash_email.html) before the lookup, so only the
+        /// hash is sent to the gateway.
+        pub fn lookup_id_by_email(&self, email: &str) -> Result<Option<String>, ApiError> {
+            self.lookup_id(&LookupCriterion::EmailHash(hash_email(email)))
         }
 
         /// Look up the capabilities of a certain Threema ID.
@@ -50,76 +122,868 @@ macro_rules! impl_common_functionality {
         /// Threema version that supports receiving files. The receiver may be
         /// using an old version, or a platform where file reception is not
         /// supported.
+        ///
+        /// If the capability cache is enabled (see
+        /// [`ApiBuilder::with_capability_cache`](struct.ApiBuilder.html#method.with_capability_cache)),
+        /// cached capabilities are returned until they expire.
         pub fn lookup_capabilities(&self, id: &str) -> Result<Capabilities, ApiError> {
-            lookup_capabilities(self.endpoint.borrow(), &self.id, id, &self.secret)
+            if let Some(ref cache) = self.settings.capability_cache {
+                if let Some(capabilities) = cache.get(id) {
+                    return Ok(capabilities);
+                }
+            }
+            let capabilities = self.settings.timed("lookup_capabilities", || {
+                lookup_capabilities(&self.conn, &self.id, id, &self.secret)
+            })?;
+            if let Some(ref cache) = self.settings.capability_cache {
+                cache.insert(id, capabilities.clone());
+            }
+            Ok(capabilities)
+        }
+
+        /// Look up whether a certain Threema ID supports group messaging.
+        ///
+        /// This is a shortcut for
+        /// [`lookup_capabilities`](#method.lookup_capabilities) followed by
+        /// [`Capabilities::supports_groups`](struct.Capabilities.html#method.supports_groups),
+        /// useful before adding the ID to a group.
+        pub fn supports_groups(&self, id: &str) -> Result<bool, ApiError> {
+            Ok(self.lookup_capabilities(id)?.supports_groups())
         }
 
         /// Look up a remaining gateway credits.
         pub fn lookup_credits(&self) -> Result<i64, ApiError> {
-            lookup_credits(self.endpoint.borrow(), &self.id, &self.secret)
+            self.settings.timed("lookup_credits", || lookup_credits(&self.conn, &self.id, &self.secret))
+        }
+
+        /// Check that the gateway is reachable and the credentials are valid.
+        ///
+        /// This performs a credits lookup, the cheapest authenticated
+        /// request, and can be used for readiness or liveness probes. If the
+        /// gateway cannot be reached, an
+        /// [`ApiError::Network`](errors/enum.ApiError.html#variant.Network)
+        /// is returned. Invalid credentials result in an
+        /// [`ApiError::BadCredentials`](errors/enum.ApiError.html#variant.BadCredentials).
+        pub fn ping(&self) -> Result<(), ApiError> {
+            self.lookup_credits().map(|_| ())
+        }
+
+        /// If the credit precheck is enabled, check that credits are left
+        /// before sending a message.
+        fn precheck_credits(&self) -> Result<(), ApiError> {
+            let cache = match self.settings.credit_precheck {
+                Some(ref cache) => cache,
+                None => return Ok(()),
+            };
+            let credits = match cache.get() {
+                Some(credits) => credits,
+                None => {
+                    let credits = self.lookup_credits()?;
+                    cache.set(credits);
+                    credits
+                },
+            };
+            if credits <= 0 {
+                return Err(ApiError::NoCredits);
+            }
+            Ok(())
+        }
+
+        /// Query the status of the own gateway ID.
+        ///
+        /// This looks up the capabilities of the own ID and the remaining
+        /// credits, and can be used as a health check on startup. Note that
+        /// this makes two requests to the gateway.
+        pub fn status(&self) -> Result<GatewayStatus, ApiError> {
+            Ok(GatewayStatus {
+                id: self.id.clone(),
+                capabilities: self.lookup_capabilities(&self.id)?,
+                credits: self.lookup_credits()?,
+            })
+        }
+
+        /// Remove all entries from the capability cache, if it is enabled
+        /// (see
+        /// [`ApiBuilder::with_capability_cache`](struct.ApiBuilder.html#method.with_capability_cache)).
+        ///
+        /// The cache is shared between clones of this API object, so they
+        /// are affected as well. It is safe to call this while requests are
+        /// in flight, but a lookup that is in flight may add its result to
+        /// the cache again afterwards.
+        pub fn clear_capability_cache(&self) {
+            if let Some(ref cache) = self.settings.capability_cache {
+                cache.clear();
+            }
+        }
+
+        /// Remove all entries from the negative lookup cache, if it is
+        /// enabled (see
+        /// [`ApiBuilder::with_negative_cache_ttl`](struct.ApiBuilder.html#method.with_negative_cache_ttl)).
+        ///
+        /// The cache is shared between clones of this API object, so they
+        /// are affected as well.
+        pub fn clear_negative_lookup_cache(&self) {
+            if let Some(ref cache) = self.settings.negative_lookup_cache {
+                cache.clear();
+            }
+        }
+
+        /// Remove all entries from the deduplication cache, if it is enabled
+        /// (see
+        /// [`ApiBuilder::with_dedup_window`](struct.ApiBuilder.html#method.with_dedup_window)),
+        /// so that the next send of a text is not treated as a duplicate.
+        ///
+        /// The cache is shared between clones of this API object, so they
+        /// are affected as well.
+        pub fn clear_dedup_cache(&self) {
+            if let Some(ref cache) = self.settings.dedup_cache {
+                cache.clear();
+            }
+        }
+
+        /// Return the body of the last successful text response of the
+        /// gateway, e.g. the message ID of a sent message or the response
+        /// to a lookup.
+        ///
+        /// Returns `None` unless recording is enabled with
+        /// [`ApiBuilder::with_raw_response_recording`](struct.ApiBuilder.html#method.with_raw_response_recording),
+        /// or if no response has been received yet. The last response is
+        /// shared between clones of this API object, so with concurrent
+        /// requests it may belong to another thread.
+        pub fn last_raw_response(&self) -> Option<RawResponse> {
+            match self.conn.last_response {
+                Some(ref last_response) => last_response.lock().unwrap().clone(),
+                None => None,
+            }
+        }
+
+        /// Return the number of messages sent successfully by this API
+        /// object and its clones.
+        ///
+        /// Sends that are in progress are included, see
+        /// [`ApiBuilder::with_send_limit`](struct.ApiBuilder.html#method.with_send_limit).
+        pub fn sends_performed(&self) -> usize {
+            self.settings.send_counter.count.load(Ordering::SeqCst)
+        }
+
+        /// Forget the cached credit count of the credit precheck, if it is
+        /// enabled (see
+        /// [`ApiBuilder::with_precheck_credits`](struct.ApiBuilder.html#method.with_precheck_credits)).
+        /// The credits are looked up again before the next message is sent.
+        pub fn clear_credit_cache(&self) {
+            if let Some(ref cache) = self.settings.credit_precheck {
+                cache.clear();
+            }
+        }
+    }
+}
+
+/// The recipient IDs that messages may be sent to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct RecipientFilter {
+    allowlist: Option<HashSet<String>>,
+    denylist: HashSet<String>,
+}
+
+impl RecipientFilter {
+    /// Check whether sending to the specified ID is allowed.
+    fn check_id(&self, id: &str) -> Result<(), ApiError> {
+        if self.denylist.contains(id) {
+            return Err(ApiError::RecipientNotAllowed(id.to_string()));
+        }
+        match self.allowlist {
+            Some(ref allowlist) if !allowlist.contains(id) => Err(ApiError::RecipientNotAllowed(id.to_string())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check whether sending to the specified recipient is allowed.
+    ///
+    /// If an allowlist is configured, phone and e-mail recipients are always
+    /// rejected, since their ID is not known before sending.
+    fn check_recipient(&self, recipient: &Recipient) -> Result<(), ApiError> {
+        match *recipient {
+            Recipient::Id(ref id) => self.check_id(id),
+            Recipient::Phone(ref val) | Recipient::Email(ref val) => match self.allowlist {
+                Some(_) => Err(ApiError::RecipientNotAllowed(val.to_string())),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+/// A callback that is invoked after every request to the gateway.
+#[derive(Clone)]
+struct RequestHook(Arc<Fn(&str, Duration, Result<(), &ApiError>) + Send + Sync>);
+
+impl fmt::Debug for RequestHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RequestHook")
+    }
+}
+
+impl PartialEq for RequestHook {
+    fn eq(&self, other: &RequestHook) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for RequestHook {}
+
+/// A token that can be used to cancel requests from another thread.
+///
+/// Once cancelled, all further requests made by API objects using this token
+/// fail with [`ApiError::Cancelled`](errors/enum.ApiError.html#variant.Cancelled)
+/// before being sent. Requests that are already in flight are not aborted.
+/// Multi-part sends like
+/// [`SimpleApi::send_chunked`](struct.SimpleApi.html#method.send_chunked)
+/// stop before sending the next part.
+///
+/// Clones of a token share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// Create a new token that is not cancelled.
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Cancel all further requests.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Return whether the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &CancellationToken) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancellationToken {}
+
+/// The default number of concurrent blob uploads.
+const DEFAULT_MAX_CONCURRENT_UPLOADS: usize = 4;
+
+/// Limits the number of concurrent blob uploads. Shared between clones of an
+/// API object.
+#[derive(Debug, Clone)]
+struct UploadLimit(Arc<Semaphore>, usize);
+
+impl UploadLimit {
+    fn new(max_concurrent: usize) -> Self {
+        UploadLimit(Arc::new(Semaphore::new(max_concurrent)), max_concurrent)
+    }
+}
+
+impl Default for UploadLimit {
+    fn default() -> Self {
+        UploadLimit::new(DEFAULT_MAX_CONCURRENT_UPLOADS)
+    }
+}
+
+impl PartialEq for UploadLimit {
+    fn eq(&self, other: &UploadLimit) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for UploadLimit {}
+
+/// Whether recipient capabilities are checked before sending media, see
+/// [`ApiBuilder::with_capability_policy`](struct.ApiBuilder.html#method.with_capability_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapabilityPolicy {
+    /// Do not check capabilities.
+    Ignore,
+    /// Check capabilities, and fail with
+    /// [`ApiError::MissingCapability`](errors/enum.ApiError.html#variant.MissingCapability)
+    /// if the recipient cannot receive the media type.
+    CheckAndFail,
+    /// Check capabilities, and log a warning if the recipient cannot receive
+    /// the media type (or if the lookup fails). The message is sent anyway.
+    CheckAndWarn,
+}
+
+impl Default for CapabilityPolicy {
+    fn default() -> Self {
+        CapabilityPolicy::Ignore
+    }
+}
+
+/// Timing information about a blob upload, see
+/// [`E2eApi::blob_upload_with_stats`](struct.E2eApi.html#method.blob_upload_with_stats).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadStats {
+    /// The number of uploaded bytes
+    pub bytes: usize,
+    /// The time the upload request took, excluding the time spent waiting
+    /// for a free upload slot
+    pub duration: Duration,
+}
+
+impl UploadStats {
+    /// Return the upload throughput in bytes per second.
+    pub fn bytes_per_sec(&self) -> f64 {
+        let secs = self.duration.as_secs() as f64 + f64::from(self.duration.subsec_nanos()) / 1e9;
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// How long the credit count looked up for the credit precheck is used.
+const CREDIT_PRECHECK_TTL_SECS: u64 = 60;
+
+/// The credit count looked up for the credit precheck. Shared between clones
+/// of an API object.
+#[derive(Debug, Clone, Default)]
+struct CreditCache(Arc<Mutex<Option<(Instant, i64)>>>);
+
+impl CreditCache {
+    /// Return the cached credit count, unless it has expired.
+    fn get(&self) -> Option<i64> {
+        match *self.0.lock().unwrap() {
+            Some((looked_up, credits)) if looked_up.elapsed() < Duration::from_secs(CREDIT_PRECHECK_TTL_SECS) => Some(credits),
+            _ => None,
+        }
+    }
+
+    fn set(&self, credits: i64) {
+        *self.0.lock().unwrap() = Some((Instant::now(), credits));
+    }
+
+    /// Subtract a spent credit from the cached credit count, if any.
+    fn spend(&self) {
+        if let Some((_, ref mut credits)) = *self.0.lock().unwrap() {
+            *credits -= 1;
+        }
+    }
+
+    fn clear(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+impl PartialEq for CreditCache {
+    fn eq(&self, other: &CreditCache) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CreditCache {}
+
+/// Capabilities looked up by ID, with the time of the lookup. Shared between
+/// clones of an API object.
+#[derive(Debug, Clone)]
+struct CapabilityCache {
+    entries: Arc<Mutex<HashMap<String, (Instant, Capabilities)>>>,
+    ttl: Duration,
+}
+
+impl CapabilityCache {
+    fn new(ttl: Duration) -> Self {
+        CapabilityCache {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            ttl: ttl,
+        }
+    }
+
+    /// Return the cached capabilities of the ID, unless they have expired.
+    fn get(&self, id: &str) -> Option<Capabilities> {
+        match self.entries.lock().unwrap().get(id) {
+            Some(&(looked_up, ref capabilities)) if looked_up.elapsed() < self.ttl => Some(capabilities.clone()),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, id: &str, capabilities: Capabilities) {
+        self.entries.lock().unwrap().insert(id.to_string(), (Instant::now(), capabilities));
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+impl PartialEq for CapabilityCache {
+    fn eq(&self, other: &CapabilityCache) -> bool {
+        Arc::ptr_eq(&self.entries, &other.entries) && self.ttl == other.ttl
+    }
+}
+
+impl Eq for CapabilityCache {}
+
+/// Return the SHA-256 digest of the parts, each prefixed with its length.
+///
+/// Used as the key of caches that should not keep recipients or texts in
+/// memory in plaintext.
+fn cache_digest(parts: &[&str]) -> [u8; 32] {
+    let mut data = Vec::new();
+    for part in parts {
+        data.extend_from_slice(format!("{}:", part.len()).as_bytes());
+        data.extend_from_slice(part.as_bytes());
+    }
+    let sha256::Digest(digest) = sha256::hash(&data);
+    digest
+}
+
+/// Lookups that did not find a match, with the time of the lookup, keyed by
+/// a digest of the lookup. Shared between clones of an API object.
+#[derive(Clone)]
+struct NegativeLookupCache {
+    entries: Arc<Mutex<HashMap<[u8; 32], Instant>>>,
+    ttl: Duration,
+}
+
+impl NegativeLookupCache {
+    fn new(ttl: Duration) -> Self {
+        NegativeLookupCache {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            ttl: ttl,
+        }
+    }
+
+    /// Return whether the lookup recently did not find a match. Expired
+    /// entries are removed.
+    fn contains(&self, kind: &str, query: &str) -> bool {
+        let key = cache_digest(&[kind, query]);
+        let mut entries = self.entries.lock().unwrap();
+        let fresh = match entries.get(&key) {
+            Some(looked_up) => looked_up.elapsed() < self.ttl,
+            None => return false,
+        };
+        if !fresh {
+            entries.remove(&key);
+        }
+        fresh
+    }
+
+    /// Store a lookup without a match, and remove expired entries.
+    fn insert(&self, kind: &str, query: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+        entries.retain(|_, looked_up| looked_up.elapsed() < ttl);
+        entries.insert(cache_digest(&[kind, query]), Instant::now());
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+impl PartialEq for NegativeLookupCache {
+    fn eq(&self, other: &NegativeLookupCache) -> bool {
+        Arc::ptr_eq(&self.entries, &other.entries) && self.ttl == other.ttl
+    }
+}
+
+impl Eq for NegativeLookupCache {}
+
+impl fmt::Debug for NegativeLookupCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("NegativeLookupCache")
+            .field("entries", &self.entries.lock().unwrap().len())
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
+
+/// The key of a text sent to a recipient: a digest of the recipient kind,
+/// the recipient and the text.
+type DedupKey = [u8; 32];
+
+/// The message IDs of recently sent texts, keyed by a digest of the
+/// recipient and the text. Shared between clones of an API object.
+#[derive(Clone)]
+struct DedupCache {
+    entries: Arc<Mutex<HashMap<DedupKey, (Instant, String)>>>,
+    window: Duration,
+}
+
+impl DedupCache {
+    fn new(window: Duration) -> Self {
+        DedupCache {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            window: window,
+        }
+    }
+
+    /// Return the key of a text sent to a recipient. `kind` distinguishes
+    /// IDs, phone numbers and e-mail addresses.
+    fn key(kind: &str, to: &str, text: &str) -> DedupKey {
+        cache_digest(&[kind, to, text])
+    }
+
+    /// Return the message ID of the text, if it was sent within the window.
+    fn get(&self, key: &DedupKey) -> Option<String> {
+        match self.entries.lock().unwrap().get(key) {
+            Some(&(sent, ref msg_id)) if sent.elapsed() < self.window => Some(msg_id.clone()),
+            _ => None,
+        }
+    }
+
+    /// Store the message ID of a sent text, and remove expired entries.
+    fn insert(&self, key: DedupKey, msg_id: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let window = self.window;
+        entries.retain(|_, &mut (sent, _)| sent.elapsed() < window);
+        entries.insert(key, (Instant::now(), msg_id.to_string()));
+    }
+
+    fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+impl PartialEq for DedupCache {
+    fn eq(&self, other: &DedupCache) -> bool {
+        Arc::ptr_eq(&self.entries, &other.entries) && self.window == other.window
+    }
+}
+
+impl Eq for DedupCache {}
+
+impl fmt::Debug for DedupCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DedupCache")
+            .field("entries", &self.entries.lock().unwrap().len())
+            .field("window", &self.window)
+            .finish()
+    }
+}
+
+/// The number of messages sent, with an optional limit. Shared between clones
+/// of an API object.
+#[derive(Debug, Clone, Default)]
+struct SendCounter {
+    count: Arc<AtomicUsize>,
+    limit: Option<usize>,
+}
+
+impl SendCounter {
+    /// Reserve a send, unless the limit has been reached.
+    fn reserve(&self) -> Result<(), ApiError> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                self.count.fetch_add(1, Ordering::SeqCst);
+                return Ok(());
+            },
+        };
+        let mut count = self.count.load(Ordering::SeqCst);
+        loop {
+            if count >= limit {
+                return Err(ApiError::SpendLimitReached(limit));
+            }
+            match self.count.compare_exchange(count, count + 1, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return Ok(()),
+                Err(current) => count = current,
+            }
+        }
+    }
+
+    /// Release a reserved send that failed.
+    fn release(&self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl PartialEq for SendCounter {
+    fn eq(&self, other: &SendCounter) -> bool {
+        Arc::ptr_eq(&self.count, &other.count) && self.limit == other.limit
+    }
+}
+
+impl Eq for SendCounter {}
+
+/// Settings shared by the simple and the e2e API objects.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Settings {
+    recipient_filter: RecipientFilter,
+    request_hook: Option<RequestHook>,
+    max_message_age: Option<Duration>,
+    clock_skew_tolerance: Option<Duration>,
+    upload_limit: UploadLimit,
+    cancellation_token: Option<CancellationToken>,
+    reject_control_chars: bool,
+    credit_precheck: Option<CreditCache>,
+    capability_cache: Option<CapabilityCache>,
+    negative_lookup_cache: Option<NegativeLookupCache>,
+    capability_policy: CapabilityPolicy,
+    send_counter: SendCounter,
+    dedup_cache: Option<DedupCache>,
+}
+
+/// The default tolerance for incoming message dates in the future.
+const DEFAULT_CLOCK_SKEW_TOLERANCE_SECS: u64 = 60;
+
+impl Settings {
+    /// Check the age of an incoming message, if a maximum age is set.
+    fn check_message_age(&self, incoming: &IncomingMessage) -> Result<(), ApiError> {
+        match self.max_message_age {
+            Some(max_age) => {
+                let skew_tolerance = self.clock_skew_tolerance
+                    .unwrap_or(Duration::from_secs(DEFAULT_CLOCK_SKEW_TOLERANCE_SECS));
+                incoming.check_age(max_age, skew_tolerance)
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Run the specified request and report its duration and outcome to the
+    /// request hook, if one is set.
+    ///
+    /// If the cancellation token has been cancelled, the request is not run.
+    fn timed<T, F>(&self, endpoint_name: &str, request: F) -> Result<T, ApiError>
+            where F: FnOnce() -> Result<T, ApiError> {
+        if let Some(ref token) = self.cancellation_token {
+            if token.is_cancelled() {
+                return Err(ApiError::Cancelled);
+            }
+        }
+        let hook = match self.request_hook {
+            Some(ref hook) => hook,
+            None => return request(),
+        };
+        let start = Instant::now();
+        let result = request();
+        let outcome = match result {
+            Ok(_) => Ok(()),
+            Err(ref e) => Err(e),
+        };
+        (hook.0)(endpoint_name, start.elapsed(), outcome);
+        result
+    }
+
+    /// Run the specified send request like [`timed`](#method.timed), and
+    /// count it if it succeeds.
+    ///
+    /// If the send limit has been reached, the request is not run.
+    fn counted<T, F>(&self, endpoint_name: &str, request: F) -> Result<T, ApiError>
+            where F: FnOnce() -> Result<T, ApiError> {
+        self.send_counter.reserve()?;
+        let result = self.timed(endpoint_name, request);
+        if result.is_err() {
+            self.send_counter.release();
+        } else {
+            self.spend_credit();
+        }
+        result
+    }
+
+    /// Update the cached credit count after a successful request that costs
+    /// a credit, if the credit precheck is enabled.
+    fn spend_credit(&self) {
+        if let Some(ref cache) = self.credit_precheck {
+            cache.spend();
+        }
+    }
+
+    /// Run the specified text send, unless the same text was sent to the
+    /// recipient within the deduplication window. In that case, the message
+    /// ID of the previous send is returned instead.
+    fn deduplicated<F>(&self, kind: &str, to: &str, text: &str, send: F) -> Result<String, ApiError>
+            where F: FnOnce() -> Result<String, ApiError> {
+        let cache = match self.dedup_cache {
+            Some(ref cache) => cache,
+            None => return send(),
+        };
+        let key = DedupCache::key(kind, to, text);
+        if let Some(msg_id) = cache.get(&key) {
+            debug!("Not sending duplicate message to {}, previous message ID: {}", to, msg_id);
+            return Ok(msg_id);
         }
+        let msg_id = send()?;
+        cache.insert(key, &msg_id);
+        Ok(msg_id)
     }
 }
 
 /// Struct to talk to the simple API (without end-to-end encryption).
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Clone)]
 pub struct SimpleApi {
     id: String,
     secret: String,
-    endpoint: Cow<'static, str>,
+    conn: Connection,
+    settings: Settings,
 }
 
 impl SimpleApi {
     /// Initialize the simple API with the Gateway ID and the Gateway Secret.
     pub(crate) fn new<I: Into<String>, S: Into<String>>(
-        endpoint: Cow<'static, str>,
+        conn: Connection,
         id: I,
         secret: S,
+        settings: Settings,
     ) -> Self {
         return SimpleApi {
             id: id.into(),
             secret: secret.into(),
-            endpoint: endpoint,
+            conn: conn,
+            settings: settings,
         }
     }
 
     /// Send a message to the specified recipient in basic mode.
     ///
+    /// The text may be at most
+    /// [`MAX_SIMPLE_MESSAGE_LEN`](constant.MAX_SIMPLE_MESSAGE_LEN.html)
+    /// bytes long. Note that this is the UTF-8 encoded length, not the number
+    /// of characters (see [`text_byte_length`](fn.text_byte_length.html)).
+    ///
+    /// If enabled with
+    /// [`ApiBuilder::with_control_char_validation`](struct.ApiBuilder.html#method.with_control_char_validation),
+    /// text containing control characters is rejected before sending.
+    ///
+    /// The text is form encoded, so any Unicode text arrives unchanged. Line
+    /// breaks are not converted either, use
+    /// [`normalize_newlines`](fn.normalize_newlines.html) to replace Windows
+    /// line breaks with `\n`.
+    ///
     /// Note that this mode of sending messages does not provide end-to-end
     /// encryption, only transport encryption between your host and the Threema
     /// Gateway server.
     ///
     /// Cost: 1 credit.
     pub fn send(&self, to: &Recipient, text: &str) -> Result<String, ApiError> {
-        send_simple(self.endpoint.borrow(), &self.id, to, &self.secret, text)
+        self.settings.recipient_filter.check_recipient(to)?;
+        check_simple_text(text)?;
+        if self.settings.reject_control_chars {
+            check_control_chars(text)?;
+        }
+        let (kind, value) = match *to {
+            Recipient::Id(ref id) => ("id", id),
+            Recipient::Phone(ref phone) => ("phone", phone),
+            Recipient::Email(ref email) => ("email", email),
+        };
+        self.settings.deduplicated(kind, value, text, || {
+            self.precheck_credits()?;
+            self.settings.counted("send_simple", || send_simple(&self.conn, &self.id, to, &self.secret, text))
+        })
+    }
+
+    /// Send a message to the specified recipient in basic mode, splitting it
+    /// into multiple messages if it is longer than
+    /// [`MAX_SIMPLE_MESSAGE_LEN`](constant.MAX_SIMPLE_MESSAGE_LEN.html) bytes.
+    ///
+    /// The text is split using [`split_text`](fn.split_text.html) and the
+    /// parts are sent in order. The IDs of all sent messages are returned.
+    ///
+    /// If sending a part fails after previous parts were already sent,
+    /// [`ApiError::PartiallySent`](errors/enum.ApiError.html#variant.PartiallySent)
+    /// is returned, containing the IDs of the messages sent so far.
+    ///
+    /// Cost: 1 credit per message.
+    pub fn send_chunked(&self, to: &Recipient, text: &str) -> Result<Vec<String>, ApiError> {
+        if text.is_empty() {
+            return Err(ApiError::EmptyMessage);
+        }
+        let mut sent = Vec::new();
+        for part in split_text(text, MAX_SIMPLE_MESSAGE_LEN) {
+            match self.send(to, part) {
+                Ok(msg_id) => sent.push(msg_id),
+                Err(e) if sent.is_empty() => return Err(e),
+                Err(e) => return Err(ApiError::PartiallySent(sent, Box::new(e))),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Send a text to each recipient in basic mode, e.g. a notification in
+    /// the language of each recipient.
+    ///
+    /// The messages are sent in order with [`send`](#method.send), and a
+    /// failed message does not stop the others. The result of every message
+    /// is returned, in the same order.
+    ///
+    /// Cost: 1 credit per message.
+    pub fn send_all(&self, messages: &[(Recipient, &str)]) -> Vec<Result<String, ApiError>> {
+        messages.iter().map(|&(ref to, text)| self.send(to, text)).collect()
+    }
+
+    /// Start building a message to the specified recipient.
+    ///
+    /// ```no_run
+    /// # use threema_gateway::{ApiBuilder, Recipient};
+    /// let api = ApiBuilder::new("*YOUR_ID", "your-gateway-secret").into_simple().unwrap();
+    /// let msg_id = api.message(Recipient::new_id("ECHOECHO")).text("Hello").send().unwrap();
+    /// ```
+    pub fn message<'a>(&'a self, to: Recipient<'a>) -> SimpleMessageBuilder<'a> {
+        SimpleMessageBuilder {
+            api: self,
+            to: to,
+            text: None,
+        }
+    }
+
+    /// Clear all caches: the capability cache, the negative lookup cache, the
+    /// deduplication cache and the cached credit count.
+    ///
+    /// See [`clear_capability_cache`](#method.clear_capability_cache),
+    /// [`clear_negative_lookup_cache`](#method.clear_negative_lookup_cache),
+    /// [`clear_dedup_cache`](#method.clear_dedup_cache) and
+    /// [`clear_credit_cache`](#method.clear_credit_cache).
+    pub fn clear_caches(&self) {
+        self.clear_capability_cache();
+        self.clear_negative_lookup_cache();
+        self.clear_dedup_cache();
+        self.clear_credit_cache();
     }
 
     impl_common_functionality!();
 }
 
+impl fmt::Debug for SimpleApi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SimpleApi")
+            .field("id", &self.id)
+            .field("secret", &"***")
+            .field("conn", &self.conn)
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
 /// Struct to talk to the E2E API (with end-to-end encryption).
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Clone)]
 pub struct E2eApi {
     id: String,
     secret: String,
     private_key: SecretKey,
-    endpoint: Cow<'static, str>,
+    previous_private_keys: Vec<SecretKey>,
+    conn: Connection,
+    settings: Settings,
+    pubkey_cache: Arc<Mutex<HashMap<String, RecipientKey>>>,
 }
 
 impl E2eApi {
     /// Initialize the simple API with the Gateway ID, the Gateway Secret and
     /// the Private Key.
     pub(crate) fn new<I: Into<String>, S: Into<String>>(
-        endpoint: Cow<'static, str>,
+        conn: Connection,
         id: I,
         secret: S,
         private_key: SecretKey,
+        previous_private_keys: Vec<SecretKey>,
+        settings: Settings,
     ) -> Self {
         return E2eApi {
             id: id.into(),
             secret: secret.into(),
             private_key: private_key,
-            endpoint: endpoint,
+            previous_private_keys: previous_private_keys,
+            conn: conn,
+            settings: settings,
+            pubkey_cache: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -129,10 +993,39 @@ impl E2eApi {
     }
 
     /// Encrypt a text message for the specified recipient public key.
-    pub fn encrypt_text_msg(&self, text: &str, recipient_key: &RecipientKey) -> EncryptedMessage {
+    ///
+    /// Empty text is rejected with
+    /// [`ApiError::EmptyMessage`](errors/enum.ApiError.html#variant.EmptyMessage),
+    /// text longer than 3728 bytes with
+    /// [`ApiError::MessageTooLong`](errors/enum.ApiError.html#variant.MessageTooLong).
+    pub fn encrypt_text_msg(&self, text: &str, recipient_key: &RecipientKey) -> Result<EncryptedMessage, ApiError> {
+        check_e2e_text(text)?;
         let data = text.as_bytes();
         let msgtype = MessageType::Text;
-        encrypt(data, msgtype, &recipient_key.0, &self.private_key)
+        Ok(encrypt(data, msgtype, &recipient_key.0, &self.private_key))
+    }
+
+    /// Precompute the shared key for the specified recipient public key.
+    ///
+    /// When sending many messages to the same recipient, encrypting them with
+    /// [`encrypt_text_msg_precomputed`](#method.encrypt_text_msg_precomputed)
+    /// avoids recomputing the shared key for every message.
+    pub fn precompute_key(&self, recipient_key: &RecipientKey) -> PrecomputedKey {
+        PrecomputedKey::new(recipient_key, &self.private_key)
+    }
+
+    /// Encrypt raw bytes with a precomputed shared key.
+    pub fn encrypt_raw_precomputed(&self, data: &[u8], key: &PrecomputedKey) -> EncryptedMessage {
+        encrypt_raw_precomputed(data, key)
+    }
+
+    /// Encrypt a text message with a precomputed shared key.
+    ///
+    /// A random nonce is generated for every message. Text is checked like
+    /// in [`encrypt_text_msg`](#method.encrypt_text_msg).
+    pub fn encrypt_text_msg_precomputed(&self, text: &str, key: &PrecomputedKey) -> Result<EncryptedMessage, ApiError> {
+        check_e2e_text(text)?;
+        Ok(encrypt_precomputed(text.as_bytes(), MessageType::Text, key))
     }
 
     /// Encrypt an image message for the specified recipient public key.
@@ -156,10 +1049,11 @@ impl E2eApi {
     /// Encrypt a file message for the specified recipient public key.
     ///
     /// Before calling this function, you need to symetrically encrypt the file
-    /// data (libsodium secretbox, random key) and upload the ciphertext to the
-    /// blob server. If you also want to set a thumbnail, do the same with the
-    /// update data (in JPEG format) and use the same key. Use the nonce
-    /// `000...1` for the file and `000...2` for the thumbnail.
+    /// data with a random [`BlobKey`](struct.BlobKey.html) and upload the
+    /// ciphertext to the blob server (see
+    /// [`upload_file_data`](#method.upload_file_data)). If you also want to
+    /// set a thumbnail, do the same with the thumbnail data (in JPEG format)
+    /// and use the same key. The blob key is embedded in the file message.
     ///
     /// The file size needs to be specified in bytes. Note that the size is
     /// only used for download size displaying purposes and has no security
@@ -167,7 +1061,7 @@ impl E2eApi {
     pub fn encrypt_file_msg(&self,
                             file_blob_id: &BlobId,
                             thumbnail_blob_id: Option<&BlobId>,
-                            blob_encryption_key: &Key,
+                            blob_encryption_key: &BlobKey,
                             mime_type: &Mime,
                             file_name: Option<&str>,
                             file_size_bytes: u32,
@@ -179,42 +1073,883 @@ impl E2eApi {
                          &recipient_key.0, &self.private_key)
     }
 
-    /// Send an encrypted E2E message to the specified Threema ID.
+    /// Encrypt a delivery receipt for the specified recipient public key,
+    /// referring to one or more messages received from them.
+    pub fn encrypt_delivery_receipt(&self,
+                                    receipt_type: ReceiptType,
+                                    message_ids: &[MessageId],
+                                    recipient_key: &RecipientKey)
+                                    -> EncryptedMessage {
+        encrypt_delivery_receipt(receipt_type, message_ids, &recipient_key.0, &self.private_key)
+    }
+
+    /// Encrypt a reaction to a message for the specified recipient public
+    /// key.
     ///
-    /// Cost: 1 credit.
-    pub fn send(&self, to: &str, message: &EncryptedMessage) -> Result<String, ApiError> {
-        send_e2e(self.endpoint.borrow(), &self.id, to, &self.secret, &message.nonce, &message.ciphertext, None)
+    /// Threema has no separate reaction message type. A reaction ("thumbs
+    /// up" if `agree` is set, "thumbs down" otherwise) is a delivery receipt
+    /// of type [`ReceiptType::Acknowledged`](enum.ReceiptType.html) or
+    /// [`ReceiptType::Declined`](enum.ReceiptType.html), and received
+    /// reactions are decoded as such. Other emoji reactions are not
+    /// supported.
+    pub fn encrypt_reaction(&self,
+                            message_id: &MessageId,
+                            agree: bool,
+                            recipient_key: &RecipientKey)
+                            -> EncryptedMessage {
+        let receipt_type = if agree { ReceiptType::Acknowledged } else { ReceiptType::Declined };
+        self.encrypt_delivery_receipt(receipt_type, &[*message_id], recipient_key)
     }
 
-    /// Used for testing purposes. Not intended to be called by end users.
-    #[doc(hidden)]
-    pub fn send_with_params(&self,
-                            to: &str,
-                            message: &EncryptedMessage,
-                            additional_params: HashMap<String, String>)
+    /// Encrypt a delete message for the specified recipient public key.
+    ///
+    /// This asks the recipient to delete a message that was previously sent
+    /// to them. Deletion is best-effort: Threema apps that do not support
+    /// this message type ignore it, and a recipient may already have read
+    /// the message.
+    pub fn encrypt_delete_msg(&self,
+                              message_id: &MessageId,
+                              recipient_key: &RecipientKey)
+                              -> EncryptedMessage {
+        encrypt_delete_msg(message_id, &recipient_key.0, &self.private_key)
+    }
+
+    /// Encrypt a VoIP call answer rejecting a call for the specified
+    /// recipient public key.
+    ///
+    /// Use this to decline a
+    /// [`Message::VoipCallOffer`](enum.Message.html#variant.VoipCallOffer)
+    /// instead of letting the caller's app ring until it times out. Pass the
+    /// call ID of the offer, see
+    /// [`Message::voip_call_id`](enum.Message.html#method.voip_call_id).
+    pub fn encrypt_voip_reject(&self,
+                               call_id: Option<u32>,
+                               reason: VoipRejectReason,
+                               recipient_key: &RecipientKey)
+                               -> EncryptedMessage {
+        encrypt_voip_reject(call_id, reason, &recipient_key.0, &self.private_key)
+    }
+
+    /// Encrypt a VoIP call hangup for the specified recipient public key.
+    pub fn encrypt_voip_hangup(&self,
+                               call_id: Option<u32>,
+                               recipient_key: &RecipientKey)
+                               -> EncryptedMessage {
+        encrypt_voip_hangup(call_id, &recipient_key.0, &self.private_key)
+    }
+
+    /// Encrypt a typing indicator for the specified recipient public key.
+    pub fn encrypt_typing_indicator(&self, typing: bool, recipient_key: &RecipientKey) -> EncryptedMessage {
+        encrypt_typing_indicator(typing, &recipient_key.0, &self.private_key)
+    }
+
+    /// Send a typing indicator to the specified Threema ID: `true` when you
+    /// start typing (e.g. preparing a response), `false` when you stop.
+    ///
+    /// The message is sent without a push notification. Use
+    /// [`start_typing`](#method.start_typing) to make sure that the
+    /// indicator is turned off again.
+    ///
+    /// Cost: 1 credit.
+    pub fn send_typing_indicator(&self,
+                                 to: &str,
+                                 recipient_key: &RecipientKey,
+                                 typing: bool)
+                                 -> Result<String, ApiError> {
+        let encrypted = self.encrypt_typing_indicator(typing, recipient_key);
+        self.send_with_flags(to, &encrypted, MessageFlags::NO_PUSH)
+    }
+
+    /// Show the typing indicator to the specified Threema ID until the
+    /// returned [`TypingIndicator`](struct.TypingIndicator.html) is stopped
+    /// or dropped.
+    ///
+    /// Cost: 1 credit now, and 1 credit when the indicator is turned off.
+    pub fn start_typing(&self, to: &str, recipient_key: &RecipientKey) -> Result<TypingIndicator, ApiError> {
+        let mut indicator = TypingIndicator {
+            api: self,
+            to: to.to_string(),
+            recipient_key: recipient_key.clone(),
+            typing: false,
+        };
+        indicator.start()?;
+        Ok(indicator)
+    }
+
+    /// Send an encrypted E2E message to the specified Threema ID.
+    ///
+    /// Cost: 1 credit.
+    pub fn send(&self, to: &str, message: &EncryptedMessage) -> Result<String, ApiError> {
+        self.settings.recipient_filter.check_id(to)?;
+        self.precheck_credits()?;
+        self.settings.counted("send_e2e", || {
+            send_e2e_encrypted(&self.conn, &self.id, to, &self.secret, message, None)
+        })
+    }
+
+    /// Send an encrypted E2E message to the specified Threema ID, like
+    /// [`send`](#method.send), and return the date of the gateway response
+    /// together with the message ID.
+    ///
+    /// The gateway does not support scheduled sending: messages are always
+    /// sent immediately. The response does not contain a message date, so the
+    /// date is taken from the `Date` header of the response.
+    ///
+    /// Cost: 1 credit.
+    pub fn send_dated(&self, to: &str, message: &EncryptedMessage) -> Result<SentMessage, ApiError> {
+        self.settings.recipient_filter.check_id(to)?;
+        self.precheck_credits()?;
+        self.settings.counted("send_e2e", || {
+            send_e2e_dated(&self.conn, &self.id, to, &self.secret, &message.nonce, &message.ciphertext, None)
+        })
+    }
+
+    /// Send an encrypted E2E message to the specified Threema ID, using the
+    /// specified [`MessageFlags`](struct.MessageFlags.html).
+    ///
+    /// Use this to suppress the push notification for a message with
+    /// `MessageFlags::NO_PUSH`. Other push settings are not supported by the
+    /// gateway, see [`MessageFlags`](struct.MessageFlags.html).
+    ///
+    /// Cost: 1 credit.
+    pub fn send_with_flags(&self,
+                           to: &str,
+                           message: &EncryptedMessage,
+                           flags: MessageFlags)
+                           -> Result<String, ApiError> {
+        self.settings.recipient_filter.check_id(to)?;
+        self.precheck_credits()?;
+        self.settings.counted("send_e2e", || {
+            send_e2e_encrypted(&self.conn, &self.id, to, &self.secret, message, Some(flags.to_params()))
+        })
+    }
+
+    /// Start building a text message to the specified Threema ID.
+    ///
+    /// ```no_run
+    /// # use threema_gateway::ApiBuilder;
+    /// let api = ApiBuilder::new("*YOUR_ID", "your-gateway-secret")
+    ///     .with_private_key_str("00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff")
+    ///     .and_then(|builder| builder.into_e2e())
+    ///     .unwrap();
+    /// let msg_id = api.message("ECHOECHO").text("Hello").no_push().send().unwrap();
+    /// ```
+    pub fn message<S: Into<String>>(&self, to: S) -> E2eMessageBuilder {
+        E2eMessageBuilder {
+            api: self,
+            to: to.into(),
+            text: None,
+            recipient_key: None,
+            flags: MessageFlags::default(),
+        }
+    }
+
+    /// Encrypt a text message and send it to the specified Threema ID.
+    ///
+    /// The public key of the recipient is looked up once and then cached,
+    /// like in [`decrypt_msg_from`](#method.decrypt_msg_from). Use
+    /// [`send_e2e_text_to`](#method.send_e2e_text_to) if you already know
+    /// the public key.
+    ///
+    /// Cost: 1 credit.
+    pub fn send_e2e_text(&self, to: &str, text: &str) -> Result<String, ApiError> {
+        self.settings.recipient_filter.check_id(to)?;
+        check_e2e_text(text)?;
+        let recipient_key = self.cached_pubkey(to)?;
+        self.send_e2e_text_to(to, &recipient_key, text)
+    }
+
+    /// Encrypt a text message with the specified public key and send it to
+    /// the Threema ID, without looking up the key.
+    ///
+    /// Make sure that `recipient_key` is the public key of `to`, otherwise
+    /// the recipient cannot decrypt the message.
+    ///
+    /// Cost: 1 credit.
+    pub fn send_e2e_text_to(&self, to: &str, recipient_key: &RecipientKey, text: &str) -> Result<String, ApiError> {
+        self.settings.deduplicated("id", to, text, || {
+            let encrypted = self.encrypt_text_msg(text, recipient_key)?;
+            self.send(to, &encrypted)
+        })
+    }
+
+    /// Create a group with this gateway ID as the creator, and send the
+    /// group setup to every member.
+    ///
+    /// Every member receives a group create message with the member list, a
+    /// group rename message with the name and, if `photo` is set, a group
+    /// set photo message. The photo (e.g. a JPEG image) is uploaded once and
+    /// shared by all members. The public keys of the members are looked up
+    /// and cached like in [`send_e2e_text`](#method.send_e2e_text).
+    ///
+    /// `members` must not contain this gateway ID, the creator is a member
+    /// implicitly. The group ID must be unique among the groups created by
+    /// this gateway ID.
+    ///
+    /// Returns the result for each member, in order. A member whose result
+    /// is an error may have received only part of the setup, sending it
+    /// again is safe. An error is returned directly if a member ID is
+    /// invalid or equal to this gateway ID, or if the photo upload fails, in
+    /// which case nothing was sent.
+    ///
+    /// Cost: 2 credits per member (3 with a photo), plus 1 credit for the
+    /// photo upload and for every public key lookup.
+    pub fn create_group(&self,
+                        group_id: &GroupId,
+                        name: &str,
+                        members: &[&str],
+                        photo: Option<&[u8]>)
+                        -> Result<Vec<(String, Result<(), ApiError>)>, ApiError> {
+        if let Some(member) = members.iter().find(|member| !is_valid_threema_id(member)) {
+            return Err(ApiError::BadRecipient(member.to_string()));
+        }
+        if members.iter().any(|member| *member == self.id) {
+            return Err(ApiError::BadRecipient(self.id.clone()));
+        }
+        let photo = match photo {
+            Some(data) => {
+                let key = BlobKey::generate();
+                let encrypted = encrypt_blob(data, &key);
+                let blob_id = self.blob_upload_raw(&encrypted)?;
+                Some((blob_id, encrypted.len() as u32, key))
+            },
+            None => None,
+        };
+        let send_setup = |member: &str| -> Result<(), ApiError> {
+            let key = self.cached_pubkey(member)?;
+            self.send(member, &encrypt_group_create_msg(group_id, members, &key.0, &self.private_key)?)?;
+            self.send(member, &encrypt_group_rename_msg(group_id, name, &key.0, &self.private_key))?;
+            if let Some((ref blob_id, size, ref blob_key)) = photo {
+                let msg = encrypt_group_set_photo_msg(group_id, blob_id, size, blob_key, &key.0, &self.private_key);
+                self.send(member, &msg)?;
+            }
+            Ok(())
+        };
+        Ok(members.iter().map(|member| (member.to_string(), send_setup(member))).collect())
+    }
+
+    /// Send a [`PreparedMessage`](struct.PreparedMessage.html), e.g. one
+    /// that was encrypted and stored earlier.
+    ///
+    /// Cost: 1 credit.
+    pub fn send_prepared(&self, prepared: &PreparedMessage) -> Result<String, ApiError> {
+        let to = &prepared.to;
+        self.settings.recipient_filter.check_id(to)?;
+        self.precheck_credits()?;
+        self.settings.counted("send_e2e", || {
+            send_e2e(&self.conn, &self.id, to, &self.secret, &prepared.nonce, &prepared.box_data,
+                     Some(prepared.flags.to_params()))
+        })
+    }
+
+    /// Send an encrypted media message (image, video or file) to the
+    /// specified Threema ID.
+    ///
+    /// Depending on the
+    /// [`CapabilityPolicy`](enum.CapabilityPolicy.html), the capabilities of
+    /// the recipient are looked up first, to check if it can receive
+    /// `media_type`. No check is done for other message types.
+    ///
+    /// Cost: 1 credit.
+    pub fn send_media(&self,
+                      to: &str,
+                      message: &EncryptedMessage,
+                      media_type: MessageType)
+                      -> Result<String, ApiError> {
+        self.check_media_capability(to, media_type)?;
+        self.send(to, message)
+    }
+
+    /// Send a file that is too large for a single blob, split into chunks.
+    ///
+    /// The file is split into chunks of at most `chunk_size` bytes, and every
+    /// chunk is uploaded and sent as a separate file message named
+    /// `<file_name>.part<n>`. The chunks contain a header to reassemble the
+    /// file, which is specific to this crate, see the
+    /// [`chunked`](chunked/index.html) module. Use this to exchange large
+    /// files with other users of this crate, Threema apps show the chunks as
+    /// separate files. The IDs of all sent messages are returned.
+    ///
+    /// The recipient capabilities are checked once, before the first upload,
+    /// according to the
+    /// [capability policy](struct.ApiBuilder.html#method.with_capability_policy).
+    /// If sending a chunk fails after previous chunks were already sent,
+    /// [`ApiError::PartiallySent`](errors/enum.ApiError.html#variant.PartiallySent)
+    /// is returned, containing the IDs of the messages sent so far. Invalid
+    /// chunk sizes are rejected like in
+    /// [`FileChunk::split`](chunked/struct.FileChunk.html#method.split).
+    ///
+    /// Cost: 2 credits per chunk, 1 for the blob upload (like
+    /// [`blob_upload`](#method.blob_upload)) and 1 for the file message.
+    pub fn send_file_chunked(&self,
+                             to: &str,
+                             recipient_key: &RecipientKey,
+                             file_name: &str,
+                             data: &[u8],
+                             chunk_size: usize)
+                             -> Result<Vec<String>, ApiError> {
+        let chunks = FileChunk::split(data, chunk_size)?;
+        self.check_media_capability(to, MessageType::File)?;
+        let mut sent = Vec::new();
+        for chunk in chunks {
+            let send_chunk = || -> Result<String, ApiError> {
+                let chunk_data = chunk.to_bytes();
+                let (blob_id, _, key) = self.upload_file_data(&chunk_data, None)?;
+                let name = format!("{}.part{}", file_name, chunk.index + 1);
+                let msg = self.encrypt_file_msg(&blob_id, None, &key, &::mime::APPLICATION_OCTET_STREAM, Some(&name),
+                                                chunk_data.len() as u32, None, recipient_key);
+                self.send(to, &msg)
+            };
+            match send_chunk() {
+                Ok(msg_id) => sent.push(msg_id),
+                Err(e) if sent.is_empty() => return Err(e),
+                Err(e) => return Err(ApiError::PartiallySent(sent, Box::new(e))),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Check whether the recipient can receive the media type, according to
+    /// the capability policy.
+    fn check_media_capability(&self, to: &str, media_type: MessageType) -> Result<(), ApiError> {
+        let capability = match media_type {
+            MessageType::Image => "image",
+            MessageType::Video => "video",
+            MessageType::File => "file",
+            _ => return Ok(()),
+        };
+        match self.settings.capability_policy {
+            CapabilityPolicy::Ignore => Ok(()),
+            CapabilityPolicy::CheckAndFail => {
+                if self.lookup_capabilities(to)?.can(capability) {
+                    Ok(())
+                } else {
+                    Err(ApiError::MissingCapability(to.to_string(), capability.to_string()))
+                }
+            },
+            CapabilityPolicy::CheckAndWarn => {
+                match self.lookup_capabilities(to) {
+                    Ok(ref caps) if !caps.can(capability) => warn!("Recipient {} lacks capability: {}", to, capability),
+                    Err(ref e) => warn!("Could not look up capabilities of {}: {}", to, e),
+                    Ok(_) => {},
+                }
+                Ok(())
+            },
+        }
+    }
+
+    /// Used for testing purposes. Not intended to be called by end users.
+    #[doc(hidden)]
+    pub fn send_with_params(&self,
+                            to: &str,
+                            message: &EncryptedMessage,
+                            additional_params: HashMap<String, String>)
                             -> Result<String, ApiError> {
-        send_e2e(self.endpoint.borrow(), &self.id, to, &self.secret, &message.nonce, &message.ciphertext, Some(additional_params))
+        self.settings.recipient_filter.check_id(to)?;
+        self.precheck_credits()?;
+        self.settings.counted("send_e2e", || {
+            send_e2e_encrypted(&self.conn, &self.id, to, &self.secret, message, Some(additional_params))
+        })
     }
 
     impl_common_functionality!();
 
+    /// Decrypt an incoming message, using the public key of the sender.
+    ///
+    /// Successful decryption proves that the message was sent by the owner of
+    /// `sender_key`. Make sure that it is the public key of `incoming.from`.
+    ///
+    /// If a maximum message age is configured (see
+    /// [`ApiBuilder::with_max_message_age`](struct.ApiBuilder.html#method.with_max_message_age)),
+    /// the message date is checked before decrypting.
+    ///
+    /// If previous private keys are configured (see
+    /// [`ApiBuilder::with_previous_private_key`](struct.ApiBuilder.html#method.with_previous_private_key)),
+    /// they are tried in order if the current private key cannot decrypt
+    /// the message.
+    ///
+    /// Decryption failures are returned as
+    /// [`ApiError::CryptoError`](errors/enum.ApiError.html#variant.CryptoError),
+    /// messages that cannot be decoded as
+    /// [`ApiError::ParseError`](errors/enum.ApiError.html#variant.ParseError).
+    pub fn decrypt_msg(&self, incoming: &IncomingMessage, sender_key: &RecipientKey) -> Result<Message, ApiError> {
+        self.settings.check_message_age(incoming)?;
+        let private_keys = Some(&self.private_key).into_iter().chain(&self.previous_private_keys);
+        let data = decrypt_with_any_key(&incoming.box_data, &incoming.nonce, &sender_key.0, private_keys)?;
+        decode(&data)
+    }
+
+    /// Decrypt an incoming message with a precomputed shared key (see
+    /// [`precompute_key`](#method.precompute_key)).
+    ///
+    /// The shared key for a peer is the same for both directions, so it can
+    /// be reused from sending. This is faster than
+    /// [`decrypt_msg`](#method.decrypt_msg) when decrypting many messages from
+    /// the same sender. Since the key is derived from one private key,
+    /// previous private keys are not tried. Errors are returned like in
+    /// [`decrypt_msg`](#method.decrypt_msg).
+    pub fn decrypt_msg_precomputed(&self, incoming: &IncomingMessage, key: &PrecomputedKey) -> Result<Message, ApiError> {
+        self.settings.check_message_age(incoming)?;
+        let data = decrypt_precomputed(&incoming.box_data, &incoming.nonce, key)?;
+        decode(&data)
+    }
+
+    /// Verify the MAC of an incoming message with the gateway secret of this
+    /// API object, see
+    /// [`IncomingMessage::verify_mac`](struct.IncomingMessage.html#method.verify_mac).
+    pub fn verify_callback_mac(&self, incoming: &IncomingMessage) -> Result<(), ApiError> {
+        incoming.verify_mac(&self.secret)
+    }
+
+    /// Verify the MACs of multiple incoming messages, returning one result
+    /// per message, in order.
+    ///
+    /// This is a convenience for receivers that collect callbacks before
+    /// processing them, each MAC is verified like in
+    /// [`verify_callback_mac`](#method.verify_callback_mac) and costs the
+    /// same. No HMAC state is shared across the batch: the sodiumoxide state
+    /// cannot be copied once initialized, and initializing it from a gateway
+    /// secret (which is shorter than a SHA-256 block) only takes two block
+    /// compressions, so there is nothing worth precomputing.
+    pub fn verify_callback_macs<'a, I>(&self, incoming: I) -> Vec<Result<(), ApiError>>
+            where I: IntoIterator<Item=&'a IncomingMessage> {
+        incoming.into_iter().map(|msg| self.verify_callback_mac(msg)).collect()
+    }
+
+    /// Decrypt an incoming message, looking up the public key of the sender.
+    ///
+    /// Public keys never change, so they are cached for the lifetime of this
+    /// API object (and its clones).
+    ///
+    /// Decryption and decoding errors are returned like in
+    /// [`decrypt_msg`](#method.decrypt_msg). All other errors are caused by
+    /// the public key lookup.
+    pub fn decrypt_msg_from(&self, incoming: &IncomingMessage) -> Result<Message, ApiError> {
+        let sender_key = self.cached_pubkey(&incoming.from)?;
+        self.decrypt_msg(incoming, &sender_key)
+    }
+
+    /// Remove all public keys cached by
+    /// [`decrypt_msg_from`](#method.decrypt_msg_from) and
+    /// [`send_e2e_text`](#method.send_e2e_text), e.g. after a suspected key
+    /// compromise.
+    ///
+    /// The cache is shared between clones of this API object, so they are
+    /// affected as well. It is safe to call this while requests are in
+    /// flight, but a lookup that is in flight may add its result to the
+    /// cache again afterwards.
+    pub fn clear_pubkey_cache(&self) {
+        self.pubkey_cache.lock().unwrap().clear();
+    }
+
+    /// Clear all caches: the public key cache, the capability cache, the
+    /// negative lookup cache, the deduplication cache and the cached credit
+    /// count.
+    ///
+    /// See [`clear_pubkey_cache`](#method.clear_pubkey_cache),
+    /// [`clear_capability_cache`](#method.clear_capability_cache),
+    /// [`clear_negative_lookup_cache`](#method.clear_negative_lookup_cache),
+    /// [`clear_dedup_cache`](#method.clear_dedup_cache) and
+    /// [`clear_credit_cache`](#method.clear_credit_cache).
+    pub fn clear_caches(&self) {
+        self.clear_pubkey_cache();
+        self.clear_capability_cache();
+        self.clear_negative_lookup_cache();
+        self.clear_dedup_cache();
+        self.clear_credit_cache();
+    }
+
+    /// Return the public key for the specified Threema ID from the cache, or
+    /// look it up and store it in the cache.
+    fn cached_pubkey(&self, id: &str) -> Result<RecipientKey, ApiError> {
+        if let Some(key) = self.pubkey_cache.lock().unwrap().get(id) {
+            return Ok(key.clone());
+        }
+        // A malformed key is a lookup error, not a crypto error
+        let key = RecipientKey::from_str(&self.lookup_pubkey(id)?)
+            .map_err(|e| ApiError::ParseError(format!("Invalid public key for {}: {}", id, e)))?;
+        self.pubkey_cache.lock().unwrap().insert(id.to_string(), key.clone());
+        Ok(key)
+    }
+
     /// Upload encrypted data to the blob server.
     ///
+    /// The number of concurrent uploads (across all clones of this API
+    /// object) is limited, see
+    /// [`ApiBuilder::with_max_concurrent_uploads`](struct.ApiBuilder.html#method.with_max_concurrent_uploads).
+    /// If the limit is reached, this blocks until another upload finishes.
+    ///
     /// Cost: 1 credit.
     pub fn blob_upload(&self, data: &EncryptedMessage) -> Result<BlobId, ApiError> {
-        blob_upload(self.endpoint.borrow(), &self.id, &self.secret, &data.ciphertext)
+        self.blob_upload_raw(&data.ciphertext)
     }
 
     /// Upload raw data to the blob server.
     ///
+    /// The number of concurrent uploads is limited like in
+    /// [`blob_upload`](#method.blob_upload).
+    ///
     /// Cost: 1 credit.
     pub fn blob_upload_raw(&self, data: &[u8]) -> Result<BlobId, ApiError> {
-        blob_upload(self.endpoint.borrow(), &self.id, &self.secret, data)
+        self.blob_upload_raw_with_stats(data).map(|(blob_id, _)| blob_id)
+    }
+
+    /// Upload encrypted data to the blob server, and return the blob ID
+    /// together with the size and duration of the upload.
+    ///
+    /// This helps to tell a slow gateway from a slow network connection when
+    /// media messages take a long time to send.
+    ///
+    /// Cost: 1 credit.
+    pub fn blob_upload_with_stats(&self, data: &EncryptedMessage) -> Result<(BlobId, UploadStats), ApiError> {
+        self.blob_upload_raw_with_stats(&data.ciphertext)
+    }
+
+    /// Upload raw data to the blob server, and return the blob ID together
+    /// with the size and duration of the upload.
+    ///
+    /// Cost: 1 credit.
+    pub fn blob_upload_raw_with_stats(&self, data: &[u8]) -> Result<(BlobId, UploadStats), ApiError> {
+        self.precheck_credits()?;
+        let _permit = self.settings.upload_limit.0.acquire();
+        let start = Instant::now();
+        let blob_id = self.settings.timed("upload_blob", || blob_upload(&self.conn, &self.id, &self.secret, data))?;
+        self.settings.spend_credit();
+        let stats = UploadStats { bytes: data.len(), duration: start.elapsed() };
+        Ok((blob_id, stats))
+    }
+
+    /// Upload encrypted data to the blob server, and report the progress of
+    /// the upload.
+    ///
+    /// `on_progress` is called with the number of bytes uploaded so far and
+    /// the total number of bytes whenever more data has been sent, e.g. to
+    /// show a progress bar for large media. It is called from the thread of
+    /// the HTTP client, not from the calling thread. The number of concurrent
+    /// uploads is limited like in [`blob_upload`](#method.blob_upload).
+    ///
+    /// Cost: 1 credit.
+    pub fn blob_upload_with_progress<F>(&self, data: &EncryptedMessage, on_progress: F) -> Result<BlobId, ApiError>
+            where F: FnMut(u64, u64) + Send + 'static {
+        self.blob_upload_raw_with_progress(&data.ciphertext, on_progress)
+    }
+
+    /// Upload raw data to the blob server, and report the progress of the
+    /// upload like in
+    /// [`blob_upload_with_progress`](#method.blob_upload_with_progress).
+    ///
+    /// Cost: 1 credit.
+    pub fn blob_upload_raw_with_progress<F>(&self, data: &[u8], on_progress: F) -> Result<BlobId, ApiError>
+            where F: FnMut(u64, u64) + Send + 'static {
+        self.precheck_credits()?;
+        let _permit = self.settings.upload_limit.0.acquire();
+        let blob_id = self.settings.timed("upload_blob", || {
+            blob_upload_with_progress(&self.conn, &self.id, &self.secret, data, on_progress)
+        })?;
+        self.settings.spend_credit();
+        Ok(blob_id)
+    }
+
+    /// Encrypt file data (and an optional thumbnail) with a fresh random key
+    /// and upload it to the blob server.
+    ///
+    /// Returns the blob ID of the file, the blob ID of the thumbnail and the
+    /// key, which can be passed to
+    /// [`encrypt_file_msg`](#method.encrypt_file_msg). This is also useful to
+    /// resend a file whose blob has expired: Since the file is encrypted with
+    /// fixed nonces, the key of the original message must not be reused.
+    ///
+    /// Cost: 1 credit per uploaded blob.
+    pub fn upload_file_data(&self,
+                            file_data: &[u8],
+                            thumbnail_data: Option<&[u8]>)
+                            -> Result<(BlobId, Option<BlobId>, BlobKey), ApiError> {
+        let (encrypted_file, encrypted_thumbnail, key) = encrypt_file_data(file_data, thumbnail_data);
+        let file_blob_id = self.blob_upload_raw(&encrypted_file)?;
+        let thumbnail_blob_id = match encrypted_thumbnail {
+            Some(ref data) => Some(self.blob_upload_raw(data)?),
+            None => None,
+        };
+        Ok((file_blob_id, thumbnail_blob_id, key))
+    }
+
+    /// Download and decrypt the media of an image or file message.
+    ///
+    /// Images are decrypted with the public key of the sender, files with the
+    /// blob key contained in the message. Thumbnails of file messages are not
+    /// downloaded, see [`download_thumbnail`](#method.download_thumbnail).
+    /// See [`blob_download`](#method.blob_download) for the
+    /// meaning of `mode`.
+    ///
+    /// Messages without media result in an
+    /// [`ApiError::NotMedia`](errors/enum.ApiError.html#variant.NotMedia). If
+    /// the size of the decrypted media does not match the size declared in
+    /// the message, an
+    /// [`ApiError::MediaSizeMismatch`](errors/enum.ApiError.html#variant.MediaSizeMismatch)
+    /// is returned.
+    ///
+    /// The whole blob is held in memory, since the encryption does not allow
+    /// decrypting it in chunks. File data is decrypted in place, so a file
+    /// needs about its own size in memory.
+    pub fn download_media(&self,
+                          msg: &Message,
+                          sender_key: &RecipientKey,
+                          mode: BlobDownloadMode)
+                          -> Result<Vec<u8>, ApiError> {
+        let (data, expected_size) = match *msg {
+            Message::Image(ref img) => {
+                let encrypted = self.blob_download(&img.blob_id, mode)?;
+                (decrypt_raw(&encrypted, &img.nonce, &sender_key.0, &self.private_key)?, img.size_bytes)
+            },
+            Message::File(ref file) => {
+                let encrypted = self.blob_download(&file.file_blob_id, mode)?;
+                (decrypt_blob_in_place(encrypted, &file.blob_encryption_key)?, file.file_size_bytes)
+            },
+            _ => return Err(ApiError::NotMedia),
+        };
+        if data.len() != expected_size as usize {
+            return Err(ApiError::MediaSizeMismatch(expected_size, data.len()));
+        }
+        Ok(data)
+    }
+
+    /// Download and decrypt the thumbnail of a file message, e.g. to show a
+    /// preview before downloading the full file with
+    /// [`download_media`](#method.download_media).
+    ///
+    /// File messages contain the blob IDs of both the file and the thumbnail.
+    /// Both blobs are encrypted with the blob key of the message, using
+    /// different nonces. Returns `None` for image messages and for file
+    /// messages without a thumbnail. Other messages result in an
+    /// [`ApiError::NotMedia`](errors/enum.ApiError.html#variant.NotMedia).
+    pub fn download_thumbnail(&self, msg: &Message, mode: BlobDownloadMode) -> Result<Option<Vec<u8>>, ApiError> {
+        match *msg {
+            Message::File(ref file) => match file.thumbnail_blob_id {
+                Some(ref blob_id) => {
+                    let encrypted = self.blob_download(blob_id, mode)?;
+                    Ok(Some(decrypt_thumbnail_blob(&encrypted, &file.blob_encryption_key)?))
+                },
+                None => Ok(None),
+            },
+            Message::Image(_) => Ok(None),
+            _ => Err(ApiError::NotMedia),
+        }
+    }
+
+    /// Download encrypted data from the blob server.
+    ///
+    /// Use [`BlobDownloadMode::Peek`](enum.BlobDownloadMode.html#variant.Peek)
+    /// for blobs that other recipients still need to download (e.g. in a
+    /// group), and
+    /// [`BlobDownloadMode::Consume`](enum.BlobDownloadMode.html#variant.Consume)
+    /// otherwise, so the server can delete the blob before it expires.
+    pub fn blob_download(&self, blob_id: &BlobId, mode: BlobDownloadMode) -> Result<Vec<u8>, ApiError> {
+        self.settings.timed("download_blob", || blob_download(&self.conn, &self.id, &self.secret, blob_id, mode))
+    }
+
+    /// Check whether a blob exists and return its size, without downloading
+    /// it. Returns `None` if the blob does not exist or has expired.
+    ///
+    /// This sends a `HEAD` request to the blob server, which does not mark
+    /// the blob as done. The gateway documentation does not cover `HEAD`
+    /// requests, so the size is `None` if the server does not report it.
+    /// Note that the size is that of the encrypted blob, which is slightly
+    /// larger than the media.
+    pub fn blob_info(&self, blob_id: &BlobId) -> Result<Option<BlobInfo>, ApiError> {
+        self.settings.timed("blob_info", || blob_info(&self.conn, &self.id, &self.secret, blob_id))
+    }
+}
+
+impl fmt::Debug for E2eApi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("E2eApi")
+            .field("id", &self.id)
+            .field("secret", &"***")
+            .field("conn", &self.conn)
+            .field("settings", &self.settings)
+            .finish()
+    }
+}
+
+/// A text message in basic mode that is being built, see
+/// [`SimpleApi::message`](struct.SimpleApi.html#method.message).
+///
+/// The gateway does not support any send options in basic mode.
+#[derive(Debug)]
+pub struct SimpleMessageBuilder<'a> {
+    api: &'a SimpleApi,
+    to: Recipient<'a>,
+    text: Option<String>,
+}
+
+impl<'a> SimpleMessageBuilder<'a> {
+    /// Set the message text.
+    pub fn text<S: Into<String>>(mut self, text: S) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+
+    /// Send the message, like [`SimpleApi::send`](struct.SimpleApi.html#method.send).
+    ///
+    /// A message without text or with empty text is rejected with
+    /// [`ApiError::EmptyMessage`](errors/enum.ApiError.html#variant.EmptyMessage).
+    ///
+    /// Cost: 1 credit.
+    pub fn send(&self) -> Result<String, ApiError> {
+        match self.text {
+            Some(ref text) => self.api.send(&self.to, text),
+            None => Err(ApiError::EmptyMessage),
+        }
+    }
+}
+
+/// An end-to-end encrypted text message that is being built, see
+/// [`E2eApi::message`](struct.E2eApi.html#method.message).
+///
+/// There is no option to set the nickname: the public nickname of the
+/// gateway ID is configured in the gateway administration and cannot be set
+/// per message.
+#[derive(Debug)]
+pub struct E2eMessageBuilder<'a> {
+    api: &'a E2eApi,
+    to: String,
+    text: Option<String>,
+    recipient_key: Option<RecipientKey>,
+    flags: MessageFlags,
+}
+
+impl<'a> E2eMessageBuilder<'a> {
+    /// Set the message text.
+    pub fn text<S: Into<String>>(mut self, text: S) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+
+    /// Encrypt the message with the specified public key of the recipient,
+    /// instead of looking it up.
+    pub fn recipient_key(mut self, recipient_key: RecipientKey) -> Self {
+        self.recipient_key = Some(recipient_key);
+        self
+    }
+
+    /// Do not send a push notification to the recipient.
+    pub fn no_push(mut self) -> Self {
+        self.flags.remove(MessageFlags::SEND_PUSH);
+        self.flags.insert(MessageFlags::NO_PUSH);
+        self
+    }
+
+    /// Ask the recipient not to send delivery receipts.
+    pub fn no_delivery_receipts(mut self) -> Self {
+        self.flags.insert(MessageFlags::NO_DELIVERY_RECEIPTS);
+        self
+    }
+
+    /// Set the message flags, replacing the flags set so far.
+    ///
+    /// See [`MessageFlags`](struct.MessageFlags.html) for the flags honored
+    /// by the gateway.
+    pub fn flags(mut self, flags: MessageFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Encrypt and send the message.
+    ///
+    /// Unless set with [`recipient_key`](#method.recipient_key), the public
+    /// key of the recipient is looked up and cached like in
+    /// [`E2eApi::send_e2e_text`](struct.E2eApi.html#method.send_e2e_text). A
+    /// message without text, or with text that
+    /// [`E2eApi::encrypt_text_msg`](struct.E2eApi.html#method.encrypt_text_msg)
+    /// rejects, is rejected before looking up the key.
+    ///
+    /// Cost: 1 credit, plus 1 credit for the public key lookup.
+    pub fn send(&self) -> Result<String, ApiError> {
+        let text = match self.text {
+            Some(ref text) => text,
+            None => return Err(ApiError::EmptyMessage),
+        };
+        check_e2e_text(text)?;
+        self.api.settings.recipient_filter.check_id(&self.to)?;
+        self.api.settings.deduplicated("id", &self.to, text, || {
+            let encrypted = match self.recipient_key {
+                Some(ref key) => self.api.encrypt_text_msg(text, key)?,
+                None => self.api.encrypt_text_msg(text, &self.api.cached_pubkey(&self.to)?)?,
+            };
+            self.api.send_with_flags(&self.to, &encrypted, self.flags)
+        })
+    }
+}
+
+/// A typing indicator shown to a recipient, see
+/// [`E2eApi::start_typing`](struct.E2eApi.html#method.start_typing).
+///
+/// The indicator is turned off when it is dropped, so it cannot be left on
+/// by an early return or an error. There is no timer to turn it off after a
+/// certain time, since this crate does not use an async runtime: keep the
+/// guard only while preparing the response. Starting or stopping an
+/// indicator that is already in that state sends nothing, so rapid toggles
+/// do not cost additional credits.
+#[derive(Debug)]
+pub struct TypingIndicator<'a> {
+    api: &'a E2eApi,
+    to: String,
+    recipient_key: RecipientKey,
+    typing: bool,
+}
+
+impl<'a> TypingIndicator<'a> {
+    /// Return whether the indicator is currently turned on.
+    pub fn is_typing(&self) -> bool {
+        self.typing
+    }
+
+    /// Turn the indicator on again, after it was stopped.
+    ///
+    /// Cost: 1 credit, unless it is already turned on.
+    pub fn start(&mut self) -> Result<(), ApiError> {
+        if !self.typing {
+            self.api.send_typing_indicator(&self.to, &self.recipient_key, true)?;
+            self.typing = true;
+        }
+        Ok(())
+    }
+
+    /// Turn the indicator off.
+    ///
+    /// If this fails, the indicator is turned off again when it is dropped.
+    ///
+    /// Cost: 1 credit, unless it is already turned off.
+    pub fn stop(&mut self) -> Result<(), ApiError> {
+        if self.typing {
+            self.api.send_typing_indicator(&self.to, &self.recipient_key, false)?;
+            self.typing = false;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for TypingIndicator<'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop() {
+            warn!("Could not turn off typing indicator for {}: {}", self.to, e);
+        }
     }
 }
 
 /// A convenient way to set up the API object.
 ///
+/// # Connection reuse
+///
+/// Every API object holds a single HTTP client, which is shared by all of its
+/// clones. Connections to the gateway are kept alive and reused for
+/// subsequent requests, so sending many messages through the same (or a
+/// cloned) API object does not open a new TCP and TLS connection per message.
+///
+/// The size of the connection pool and the keep-alive timeout cannot be
+/// configured, because the underlying HTTP client (`reqwest` 0.8) does not
+/// expose these settings.
+///
 /// # Examples
 ///
 /// ## Simple API
@@ -225,7 +1960,7 @@ impl E2eApi {
 /// let gateway_id = "*3MAGWID";
 /// let gateway_secret = "hihghrg98h00ghrg";
 ///
-/// let api: SimpleApi = ApiBuilder::new(gateway_id, gateway_secret).into_simple();
+/// let api: SimpleApi = ApiBuilder::new(gateway_id, gateway_secret).into_simple().unwrap();
 /// ```
 ///
 /// ## E2E API
@@ -242,12 +1977,28 @@ impl E2eApi {
 ///                              .and_then(|builder| builder.into_e2e())
 ///                              .unwrap();
 /// ```
-#[derive(Debug)]
 pub struct ApiBuilder {
     pub id: String,
     pub secret: String,
     pub private_key: Option<SecretKey>,
     pub endpoint: Cow<'static, str>,
+    previous_private_keys: Vec<SecretKey>,
+    user_agent: Cow<'static, str>,
+    client_config: ClientConfig,
+    settings: Settings,
+}
+
+impl fmt::Debug for ApiBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ApiBuilder")
+            .field("id", &self.id)
+            .field("secret", &"***")
+            .field("endpoint", &self.endpoint)
+            .field("user_agent", &self.user_agent)
+            .field("client_config", &self.client_config)
+            .field("settings", &self.settings)
+            .finish()
+    }
 }
 
 impl ApiBuilder {
@@ -258,12 +2009,53 @@ impl ApiBuilder {
             secret: secret.into(),
             private_key: None,
             endpoint: Cow::Borrowed(MSGAPI_URL),
+            previous_private_keys: Vec::new(),
+            user_agent: Cow::Borrowed(DEFAULT_USER_AGENT),
+            client_config: ClientConfig::default(),
+            settings: Settings::default(),
+        }
+    }
+
+    /// Initialize the ApiBuilder from a directory containing the credentials.
+    ///
+    /// The directory must contain a file `from` with the Gateway ID and a
+    /// file `secret` with the Gateway Secret. If it also contains a file
+    /// `private_key` with the hex encoded private key, the key is set as
+    /// well. The key may be prefixed with `private:`, like in the key files
+    /// generated by the Threema Gateway SDKs. Leading and trailing whitespace
+    /// is ignored in all files.
+    ///
+    /// If a file cannot be read, an
+    /// [`ApiBuilderError::CredentialsFile`](errors/enum.ApiBuilderError.html#variant.CredentialsFile)
+    /// naming the file is returned.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> Result<Self, ApiBuilderError> {
+        ApiBuilder::from_credentials(&DirCredentials::new(dir.as_ref()))
+    }
+
+    /// Initialize the ApiBuilder with credentials read from a
+    /// [`CredentialSource`](trait.CredentialSource.html).
+    ///
+    /// If the source provides a private key, it is set as well. The key may
+    /// be prefixed with `private:`.
+    pub fn from_credentials<C: CredentialSource>(source: &C) -> Result<Self, ApiBuilderError> {
+        let credentials = source.credentials()?;
+        let builder = ApiBuilder::new(credentials.id, credentials.secret);
+        match credentials.private_key {
+            Some(ref key) if key.starts_with("private:") => builder.with_private_key_str(&key["private:".len()..]),
+            Some(ref key) => builder.with_private_key_str(key),
+            None => Ok(builder),
         }
     }
 
     /// Set a custom API endpoint.
     ///
-    /// The API endpoint should be a HTTPS URL without trailing slash.
+    /// The API endpoint should be a HTTPS URL without trailing slash. By
+    /// default, `https://msgapi.threema.ch` is used.
+    ///
+    /// Threema Work users are reachable through the regular gateway, there is
+    /// no separate Work endpoint or Work-specific lookup API. A custom
+    /// endpoint is only needed if you route requests through a proxy or use a
+    /// test server.
     pub fn with_custom_endpoint<E: Into<Cow<'static, str>>>(mut self, endpoint: E) -> Self {
         let endpoint = endpoint.into();
         debug!("Using custom endpoint: {}", endpoint);
@@ -276,41 +2068,1419 @@ impl ApiBuilder {
         self
     }
 
-    /// Return a [`SimpleAPI`](struct.SimpleApi.html) instance.
-    pub fn into_simple(self) -> SimpleApi {
-        SimpleApi::new(self.endpoint, self.id, self.secret)
-    }
-
-    /// Set the private key. Only needed for E2e mode.
-    pub fn with_private_key(mut self, private_key: SecretKey) -> Self {
-        self.private_key = Some(private_key);
+    /// Set a custom `User-Agent` header.
+    ///
+    /// By default, `threema-gateway-rs/<version>` is sent.
+    pub fn with_user_agent<U: Into<Cow<'static, str>>>(mut self, user_agent: U) -> Self {
+        self.user_agent = user_agent.into();
         self
     }
 
-    /// Set the private key from a byte slice. Only needed for E2e mode.
-    pub fn with_private_key_bytes(mut self, private_key: &[u8]) -> Result<Self, ApiBuilderError> {
-        let private_key = SecretKey::from_slice(private_key)
-            .ok_or(ApiBuilderError::InvalidKey("Invalid libsodium private key".into()))?;
-        self.private_key = Some(private_key);
+    /// Send all requests through the specified HTTP(S) proxy.
+    ///
+    /// The HTTP client does not support overriding DNS resolution. If the
+    /// gateway host must be pinned to a specific address or resolved via
+    /// internal DNS, route all requests through a proxy instead.
+    pub fn with_proxy(mut self, url: &str) -> Result<Self, ApiBuilderError> {
+        let proxy = Proxy::all(url).map_err(ApiBuilderError::InvalidProxy)?;
+        self.client_config.proxies.push(proxy);
         Ok(self)
     }
 
-    /// Set the private key from a hex-encoded string reference. Only needed
-    /// for E2e mode.
-    pub fn with_private_key_str(self, private_key: &str) -> Result<Self, ApiBuilderError> {
-        let private_key_bytes = HEXLOWER_PERMISSIVE.decode(private_key.as_bytes())
-            .map_err(|e| {
-                let msg = format!("Could not decode private key hex string: {}", e);
-                ApiBuilderError::InvalidKey(msg)
-            })?;
-        self.with_private_key_bytes(&private_key_bytes)
+    /// Trust the specified (DER encoded) root certificate, in addition to the
+    /// system's trusted certificates.
+    ///
+    /// This can be used to test against a local gateway emulation with a
+    /// self-signed certificate, see also
+    /// [`with_danger_accept_self_signed_cert_der`](#method.with_danger_accept_self_signed_cert_der).
+    pub fn with_root_certificate_der(mut self, der: &[u8]) -> Result<Self, ApiBuilderError> {
+        Certificate::from_der(der).map_err(ApiBuilderError::InvalidCertificate)?;
+        self.client_config.root_certificates.push(der.to_vec());
+        Ok(self)
     }
 
-    /// Return a [`E2eAPI`](struct.SimpleApi.html) instance.
-    pub fn into_e2e(self) -> Result<E2eApi, ApiBuilderError> {
-        match self.private_key {
-            Some(key) => Ok(E2eApi::new(self.endpoint, self.id, self.secret, key)),
-            None => Err(ApiBuilderError::MissingKey),
-        }
+    /// Disable TLS hostname verification.
+    ///
+    /// # Warning
+    ///
+    /// **This is dangerous and must only be used for testing.** Without
+    /// hostname verification, any valid certificate for any host is trusted
+    /// for the gateway, which makes man-in-the-middle attacks trivial. Never
+    /// use this in production, the gateway secret and all messages would be
+    /// exposed.
+    ///
+    /// The HTTP client does not support disabling certificate verification
+    /// completely. To test against a server with a self-signed certificate,
+    /// use
+    /// [`with_danger_accept_self_signed_cert_der`](#method.with_danger_accept_self_signed_cert_der).
+    pub fn with_danger_disable_hostname_verification(mut self) -> Self {
+        warn!("TLS hostname verification is disabled, this is insecure!");
+        self.client_config.disable_hostname_verification = true;
+        self
     }
+
+    /// Accept the specified (DER encoded) self-signed certificate for the
+    /// gateway, whatever host it was issued for.
+    ///
+    /// This is meant for testing against a local gateway emulation over
+    /// HTTPS. It trusts the certificate like
+    /// [`with_root_certificate_der`](#method.with_root_certificate_der) and
+    /// disables hostname verification like
+    /// [`with_danger_disable_hostname_verification`](#method.with_danger_disable_hostname_verification).
+    /// The HTTP client (reqwest 0.8) cannot accept invalid certificates
+    /// without checking them at all, so this is the closest equivalent of
+    /// reqwest's `danger_accept_invalid_certs`: the server must present the
+    /// given certificate or one that is otherwise trusted.
+    ///
+    /// # Warning
+    ///
+    /// **This is dangerous and must only be used for testing.** Since
+    /// hostname verification is disabled, any certificate trusted by the
+    /// system is accepted for the gateway as well, which makes
+    /// man-in-the-middle attacks trivial. Never use this in production, the
+    /// gateway secret and all messages would be exposed.
+    pub fn with_danger_accept_self_signed_cert_der(self, der: &[u8]) -> Result<Self, ApiBuilderError> {
+        Ok(self.with_root_certificate_der(der)?.with_danger_disable_hostname_verification())
+    }
+
+    /// Set whether HTTP redirects are followed.
+    ///
+    /// By default, redirects are not followed
+    /// ([`RedirectPolicy::None`](enum.RedirectPolicy.html)) and a redirect
+    /// response results in an error. The gateway does not redirect requests,
+    /// and following a redirect would send the gateway secret (which is part
+    /// of every request) to a possibly unexpected host. Only allow redirects
+    /// if a proxy or test server in front of the gateway requires them.
+    pub fn with_redirect_policy(mut self, policy: RedirectPolicy) -> Self {
+        self.client_config.redirect_policy = policy;
+        self
+    }
+
+    /// Record the raw body of the last successful text response, see
+    /// [`last_raw_response`](struct.E2eApi.html#method.last_raw_response).
+    ///
+    /// This helps with debugging, since the parsed result of a request does
+    /// not always show what the gateway sent. Binary responses (blob
+    /// downloads) are not recorded.
+    pub fn with_raw_response_recording(mut self) -> Self {
+        self.client_config.record_last_response = true;
+        self
+    }
+
+    /// Add a middleware that is invoked around every request.
+    ///
+    /// See [`RequestMiddleware`](trait.RequestMiddleware.html) for details.
+    /// Without middlewares, requests are sent directly.
+    pub fn with_middleware<M: RequestMiddleware + 'static>(mut self, middleware: M) -> Self {
+        self.client_config.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Only allow sending messages to the specified Threema IDs.
+    ///
+    /// Sending to any other ID fails with
+    /// [`ApiError::RecipientNotAllowed`](errors/enum.ApiError.html#variant.RecipientNotAllowed)
+    /// before a request is made. Since the ID of a phone or e-mail recipient
+    /// is not known in advance, sending to those is rejected as well.
+    pub fn with_recipient_allowlist<I, S>(mut self, ids: I) -> Self
+            where I: IntoIterator<Item=S>, S: Into<String> {
+        self.settings.recipient_filter.allowlist = Some(ids.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Never send messages to the specified Threema IDs.
+    ///
+    /// Sending to these IDs fails with
+    /// [`ApiError::RecipientNotAllowed`](errors/enum.ApiError.html#variant.RecipientNotAllowed)
+    /// before a request is made. The denylist takes precedence over the
+    /// allowlist.
+    pub fn with_recipient_denylist<I, S>(mut self, ids: I) -> Self
+            where I: IntoIterator<Item=S>, S: Into<String> {
+        self.settings.recipient_filter.denylist = ids.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set a callback that is invoked after every request to the gateway.
+    ///
+    /// The callback receives the name of the API endpoint (e.g. `send_e2e`
+    /// or `lookup_pubkey`), the time it took to complete the request and
+    /// whether the request succeeded. It is called for failed requests as
+    /// well, which makes it suitable for feeding latency and error metrics
+    /// into a monitoring system.
+    ///
+    /// Requests that are rejected before being sent (e.g. because of the
+    /// recipient allowlist) are not reported.
+    pub fn with_request_hook<F>(mut self, hook: F) -> Self
+            where F: Fn(&str, Duration, Result<(), &ApiError>) + Send + Sync + 'static {
+        self.settings.request_hook = Some(RequestHook(Arc::new(hook)));
+        self
+    }
+
+    /// Reject incoming messages older than `max_age` when decrypting them.
+    ///
+    /// Messages dated in the future are rejected as well, unless they are
+    /// within the clock skew tolerance (one minute by default, see
+    /// [`with_clock_skew_tolerance`](#method.with_clock_skew_tolerance)).
+    pub fn with_max_message_age(mut self, max_age: Duration) -> Self {
+        self.settings.max_message_age = Some(max_age);
+        self
+    }
+
+    /// Set how far in the future the date of an incoming message may be.
+    ///
+    /// This is only used if a maximum message age is set with
+    /// [`with_max_message_age`](#method.with_max_message_age).
+    pub fn with_clock_skew_tolerance(mut self, tolerance: Duration) -> Self {
+        self.settings.clock_skew_tolerance = Some(tolerance);
+        self
+    }
+
+    /// Set the maximum number of concurrent blob uploads.
+    ///
+    /// The limit is shared by all clones of the resulting API object, so
+    /// uploads from multiple threads don't overwhelm the gateway or the
+    /// network. Further uploads block until a running upload finishes. The
+    /// default is 4. A limit of 0 results in an
+    /// [`ApiBuilderError::InvalidUploadLimit`](errors/enum.ApiBuilderError.html#variant.InvalidUploadLimit)
+    /// when the API object is created.
+    pub fn with_max_concurrent_uploads(mut self, max_concurrent: usize) -> Self {
+        self.settings.upload_limit = UploadLimit::new(max_concurrent);
+        self
+    }
+
+    /// Check that credits are left before sending a message or uploading a
+    /// blob.
+    ///
+    /// If enabled, the remaining credits are looked up before the first
+    /// request that costs credits, and
+    /// [`ApiError::NoCredits`](errors/enum.ApiError.html#variant.NoCredits)
+    /// is returned without sending the request if no credits are left. The
+    /// credit count is cached for 60 seconds (across all clones of the API
+    /// object), and decremented after every successful send or upload, so it
+    /// is only looked up once per minute. Disabled by default, since the
+    /// lookup adds a round trip.
+    pub fn with_precheck_credits(mut self, enabled: bool) -> Self {
+        self.settings.credit_precheck = if enabled { Some(CreditCache::default()) } else { None };
+        self
+    }
+
+    /// Limit the number of messages that can be sent, as a guard against
+    /// accidentally spending all credits (e.g. in a loop).
+    ///
+    /// The limit applies to the API object and all its clones. Successful
+    /// sends are counted, and once `limit` messages have been sent, all
+    /// further sends fail with
+    /// [`ApiError::SpendLimitReached`](errors/enum.ApiError.html#variant.SpendLimitReached)
+    /// without making a request. Sends that are in progress count towards
+    /// the limit, so it is never exceeded by concurrent sends. Blob uploads
+    /// and lookups are not counted.
+    ///
+    /// The number of sent messages is available via
+    /// [`sends_performed`](struct.E2eApi.html#method.sends_performed).
+    pub fn with_send_limit(mut self, limit: usize) -> Self {
+        self.settings.send_counter.limit = Some(limit);
+        self
+    }
+
+    /// Do not send a text again if the same text was sent to the same
+    /// recipient within `window`, e.g. by a buggy loop or a retry storm.
+    ///
+    /// Duplicate sends return the message ID of the previous message
+    /// without making a request. Only text messages sent with
+    /// [`SimpleApi::send`](struct.SimpleApi.html#method.send),
+    /// [`E2eApi::send_e2e_text`](struct.E2eApi.html#method.send_e2e_text),
+    /// [`E2eApi::send_e2e_text_to`](struct.E2eApi.html#method.send_e2e_text_to)
+    /// and the message builders are deduplicated. Only a SHA-256 digest of
+    /// the recipient and the text is kept in memory, until it expires, and
+    /// shared by all clones of the API object. Identical texts sent concurrently may both be sent.
+    /// Disabled by default.
+    pub fn with_dedup_window(mut self, window: Duration) -> Self {
+        self.settings.dedup_cache = Some(DedupCache::new(window));
+        self
+    }
+
+    /// Cache the results of capability lookups for `ttl`.
+    ///
+    /// Unlike public keys, capabilities can change (e.g. when a user
+    /// updates the Threema app), so they are only cached for a limited time.
+    /// Expired entries are looked up again when needed. The cache is shared
+    /// by all clones of the API object and is also used for the capability
+    /// checks of [`E2eApi::send_media`](struct.E2eApi.html#method.send_media).
+    /// Disabled by default.
+    pub fn with_capability_cache(mut self, ttl: Duration) -> Self {
+        self.settings.capability_cache = Some(CapabilityCache::new(ttl));
+        self
+    }
+
+    /// Remember lookups that did not find a match for `ttl`.
+    ///
+    /// ID lookups without a match
+    /// ([`lookup_id`](struct.SimpleApi.html#method.lookup_id) returning
+    /// `Ok(None)`) and public key lookups of unknown IDs (returning
+    /// `ApiError::IdNotFound`) are answered from the cache until the entry
+    /// expires, which avoids repeatedly querying the gateway for contacts
+    /// that don't use Threema. Entries expire so that newly registered users
+    /// are found eventually. Only a SHA-256 digest of the looked up phone
+    /// number, e-mail address or ID is kept in memory. The cache is shared
+    /// by all clones of the API object. Disabled by default.
+    pub fn with_negative_cache_ttl(mut self, ttl: Duration) -> Self {
+        self.settings.negative_lookup_cache = Some(NegativeLookupCache::new(ttl));
+        self
+    }
+
+    /// Set whether recipient capabilities are checked before sending media
+    /// with [`E2eApi::send_media`](struct.E2eApi.html#method.send_media).
+    ///
+    /// By default, capabilities are not checked
+    /// ([`CapabilityPolicy::Ignore`](enum.CapabilityPolicy.html#variant.Ignore)).
+    /// Checking costs an additional request per message, but avoids wasting
+    /// credits on media the recipient cannot receive.
+    pub fn with_capability_policy(mut self, policy: CapabilityPolicy) -> Self {
+        self.settings.capability_policy = policy;
+        self
+    }
+
+    /// Reject simple messages containing control characters.
+    ///
+    /// The gateway may reject text with control characters (e.g. `\0` or
+    /// escape sequences) without a helpful error message. If enabled,
+    /// [`SimpleApi::send`](struct.SimpleApi.html#method.send) checks the text
+    /// first and returns
+    /// [`ApiError::ControlCharacter`](errors/enum.ApiError.html#variant.ControlCharacter)
+    /// with the position of the first control character. Newlines, carriage
+    /// returns and tabs are allowed.
+    pub fn with_control_char_validation(mut self) -> Self {
+        self.settings.reject_control_chars = true;
+        self
+    }
+
+    /// Use the specified token to cancel requests.
+    ///
+    /// See [`CancellationToken`](struct.CancellationToken.html) for details.
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.settings.cancellation_token = Some(token);
+        self
+    }
+
+    /// Check that uploads are not disabled by a limit of 0, which would
+    /// block every upload forever.
+    fn check_upload_limit(&self) -> Result<(), ApiBuilderError> {
+        if self.settings.upload_limit.1 == 0 {
+            return Err(ApiBuilderError::InvalidUploadLimit);
+        }
+        Ok(())
+    }
+
+    /// Return a [`SimpleAPI`](struct.SimpleApi.html) instance.
+    ///
+    /// This fails if the gateway ID is malformed (it must be a `*` followed
+    /// by 7 uppercase alphanumeric characters), if the upload limit is 0, or
+    /// if the HTTP client cannot be initialized.
+    pub fn into_simple(self) -> Result<SimpleApi, ApiBuilderError> {
+        check_gateway_id(&self.id)?;
+        self.check_upload_limit()?;
+        let conn = Connection::new(self.endpoint, self.user_agent, self.client_config).map_err(ApiBuilderError::ClientError)?;
+        Ok(SimpleApi::new(conn, self.id, self.secret, self.settings))
+    }
+
+    /// Set the private key. Only needed for E2e mode.
+    pub fn with_private_key(mut self, private_key: SecretKey) -> Self {
+        self.private_key = Some(private_key);
+        self
+    }
+
+    /// Set the private key from a byte slice. Only needed for E2e mode.
+    pub fn with_private_key_bytes(mut self, private_key: &[u8]) -> Result<Self, ApiBuilderError> {
+        let private_key = SecretKey::from_slice(private_key)
+            .ok_or(ApiBuilderError::InvalidKey("Invalid libsodium private key".into()))?;
+        self.private_key = Some(private_key);
+        Ok(self)
+    }
+
+    /// Set the private key from a hex-encoded string reference. Only needed
+    /// for E2e mode.
+    pub fn with_private_key_str(self, private_key: &str) -> Result<Self, ApiBuilderError> {
+        let private_key_bytes = decode_hex_fixed(private_key)
+            .map_err(|e| {
+                let msg = format!("Could not decode private key hex string: {}", e);
+                ApiBuilderError::InvalidKey(msg)
+            })?;
+        Ok(self.with_private_key(SecretKey(private_key_bytes)))
+    }
+
+    /// Add a previous private key, used for decrypting incoming messages
+    /// during a key rotation.
+    ///
+    /// Messages are always encrypted with the private key set by
+    /// [`with_private_key`](#method.with_private_key). Incoming messages that
+    /// cannot be decrypted with it are decrypted with the previous keys, in
+    /// the order they were added. Only needed for E2e mode.
+    pub fn with_previous_private_key(mut self, private_key: SecretKey) -> Self {
+        self.previous_private_keys.push(private_key);
+        self
+    }
+
+    /// Return a [`E2eAPI`](struct.SimpleApi.html) instance.
+    ///
+    /// This fails if no private key was set, if the gateway ID is malformed,
+    /// if the upload limit is 0, or if the HTTP client cannot be initialized.
+    pub fn into_e2e(self) -> Result<E2eApi, ApiBuilderError> {
+        self.check_upload_limit()?;
+        let key = match self.private_key {
+            Some(key) => key,
+            None => return Err(ApiBuilderError::MissingKey),
+        };
+        check_gateway_id(&self.id)?;
+        let conn = Connection::new(self.endpoint, self.user_agent, self.client_config).map_err(ApiBuilderError::ClientError)?;
+        Ok(E2eApi::new(conn, self.id, self.secret, key, self.previous_private_keys, self.settings))
+    }
+}
+
+/// Check that the gateway ID consists of a `*` followed by 7 uppercase
+/// alphanumeric characters.
+///
+/// A common misconfiguration is to use a regular Threema ID instead of the
+/// gateway ID, which would only be noticed when the first request fails.
+fn check_gateway_id(id: &str) -> Result<(), ApiBuilderError> {
+    let valid = is_valid_threema_id(id) && id.starts_with('*');
+    if valid {
+        Ok(())
+    } else {
+        Err(ApiBuilderError::InvalidGatewayId(id.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use sodiumoxide::crypto::box_;
+    use ::crypto::RecipientKey;
+    use ::message::Message;
+    use ::receive::IncomingMessage;
+    use ::crypto::encrypt_blob;
+    use ::message::ImageMessage;
+    use ::test_server::TestServer;
+    use ::types::{FileMessage, MessageId};
+    use super::*;
+
+    fn incoming(from: &str, message: &EncryptedMessage) -> IncomingMessage {
+        IncomingMessage {
+            from: from.into(),
+            to: "*3MAGWID".into(),
+            message_id: MessageId::new([1, 2, 3, 4, 5, 6, 7, 8]),
+            date: 1524658864,
+            nonce: message.nonce.to_vec(),
+            box_data: message.ciphertext.clone(),
+            mac: vec![0; 32],
+            nickname: None,
+        }
+    }
+
+    /// Build a simple API object that sends its requests to the test server.
+    fn simple_api(builder: ApiBuilder, server: &TestServer) -> SimpleApi {
+        let mut api = builder.into_simple().unwrap();
+        api.conn = server.conn();
+        api
+    }
+
+    /// Build an E2E API object that sends its requests to the test server.
+    fn e2e_api(builder: ApiBuilder, server: &TestServer) -> E2eApi {
+        let mut api = builder.into_e2e().unwrap();
+        api.conn = server.conn();
+        api
+    }
+
+    #[test]
+    fn test_recipient_allowlist() {
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_recipient_allowlist(vec!["ECHOECHO"])
+            .into_simple()
+            .unwrap();
+        match api.send(&Recipient::new_id("OTHERID1"), "Hi") {
+            Err(ApiError::RecipientNotAllowed(ref id)) if id == "OTHERID1" => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match api.send(&Recipient::new_email("user@example.com"), "Hi") {
+            Err(ApiError::RecipientNotAllowed(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(api.settings.recipient_filter.check_id("ECHOECHO").is_ok());
+    }
+
+    #[test]
+    fn test_recipient_denylist() {
+        let (_, sec) = box_::gen_keypair();
+        let (other_pub, _) = box_::gen_keypair();
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key(sec)
+            .with_recipient_allowlist(vec!["ECHOECHO", "OTHERID1"])
+            .with_recipient_denylist(vec!["OTHERID1"])
+            .into_e2e()
+            .unwrap();
+        let encrypted = api.encrypt_text_msg("Hi", &RecipientKey(other_pub)).unwrap();
+        match api.send("OTHERID1", &encrypted) {
+            Err(ApiError::RecipientNotAllowed(ref id)) if id == "OTHERID1" => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(api.settings.recipient_filter.check_id("ECHOECHO").is_ok());
+        assert!(RecipientFilter::default().check_recipient(&Recipient::new_phone("41791234567")).is_ok());
+    }
+
+    #[test]
+    fn test_encrypt_text_msg_length() {
+        let (_, sec) = box_::gen_keypair();
+        let (other_pub, _) = box_::gen_keypair();
+        let api = ApiBuilder::new("*3MAGWID", "1234").with_private_key(sec).into_e2e().unwrap();
+        let recipient_key = RecipientKey(other_pub);
+        assert!(api.encrypt_text_msg(&"a".repeat(3728), &recipient_key).is_ok());
+        match api.encrypt_text_msg(&"a".repeat(3729), &recipient_key) {
+            Err(ApiError::MessageTooLong) => (),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+        let key = api.precompute_key(&recipient_key);
+        match api.encrypt_text_msg_precomputed(&"€".repeat(1243), &key) {
+            Err(ApiError::MessageTooLong) => (),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let api = ApiBuilder::new("*3MAGWID", "1234").into_simple().unwrap();
+        assert!(api.conn.user_agent.starts_with("threema-gateway-rs/"));
+        let api = ApiBuilder::new("*3MAGWID", "1234").with_user_agent("mybot/1.0").into_simple().unwrap();
+        assert_eq!(api.conn.user_agent, "mybot/1.0");
+    }
+
+    #[test]
+    fn test_invalid_upload_limit() {
+        assert!(ApiBuilder::new("*3MAGWID", "1234").with_max_concurrent_uploads(1).into_simple().is_ok());
+        match ApiBuilder::new("*3MAGWID", "1234").with_max_concurrent_uploads(0).into_simple() {
+            Err(ApiBuilderError::InvalidUploadLimit) => (),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+        let (_, sec) = box_::gen_keypair();
+        match ApiBuilder::new("*3MAGWID", "1234").with_private_key(sec).with_max_concurrent_uploads(0).into_e2e() {
+            Err(ApiBuilderError::InvalidUploadLimit) => (),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_check_gateway_id() {
+        assert!(ApiBuilder::new("*3MAGWID", "1234").into_simple().is_ok());
+        for id in vec!["ECHOECHO", "*3MAGWI", "*3MAGWIDX", "**3MAGWI", "*3MAG-ID", "*3MAGWÏ", "*3magwid"] {
+            match ApiBuilder::new(id, "1234").into_simple() {
+                Err(ApiBuilderError::InvalidGatewayId(ref invalid)) => assert_eq!(invalid, id),
+                other => panic!("Unexpected result for {}: {:?}", id, other.map(|_| ())),
+            }
+        }
+        let (_, sec) = box_::gen_keypair();
+        match ApiBuilder::new("ECHOECHO", "1234").with_private_key(sec).into_e2e() {
+            Err(ApiBuilderError::InvalidGatewayId(_)) => (),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_from_credentials() {
+        struct StaticCredentials;
+        impl CredentialSource for StaticCredentials {
+            fn credentials(&self) -> Result<::credentials::Credentials, ApiBuilderError> {
+                Ok(::credentials::Credentials {
+                    id: "*3MAGWID".into(),
+                    secret: "1234".into(),
+                    private_key: Some("998730fbcac1c57dbb181139de41d12835b3fae6af6acdf6ce91670262e88453".into()),
+                })
+            }
+        }
+        let builder = ApiBuilder::from_credentials(&StaticCredentials).unwrap();
+        assert_eq!(builder.id, "*3MAGWID");
+        assert_eq!(builder.secret, "1234");
+        assert!(builder.into_e2e().is_ok());
+    }
+
+    #[test]
+    fn test_from_dir() {
+        let mut suffix = [0; 8];
+        ::sodiumoxide::randombytes::randombytes_into(&mut suffix);
+        let dir = ::std::env::temp_dir().join(format!("threema-gateway-test-{}", ::data_encoding::HEXLOWER.encode(&suffix)));
+        ::std::fs::create_dir(&dir).unwrap();
+        let write = |name: &str, contents: &str| {
+            use std::io::Write;
+            File::create(dir.join(name)).unwrap().write_all(contents.as_bytes()).unwrap();
+        };
+
+        write("from", "*3MAGWID\n");
+        match ApiBuilder::from_dir(&dir) {
+            Err(ApiBuilderError::CredentialsFile(ref path, _)) if path.ends_with("secret") => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        write("secret", "  1234\n");
+        let builder = ApiBuilder::from_dir(&dir).unwrap();
+        assert_eq!(builder.id, "*3MAGWID");
+        assert_eq!(builder.secret, "1234");
+        assert!(builder.private_key.is_none());
+
+        write("private_key", "private:998730fbcac1c57dbb181139de41d12835b3fae6af6acdf6ce91670262e88453\n");
+        assert!(ApiBuilder::from_dir(&dir).unwrap().private_key.is_some());
+
+        write("private_key", "invalid");
+        match ApiBuilder::from_dir(&dir) {
+            Err(ApiBuilderError::InvalidKey(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        ::std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_with_proxy() {
+        let builder = ApiBuilder::new("*3MAGWID", "1234").with_proxy("http://127.0.0.1:3128").unwrap();
+        assert_eq!(builder.client_config.proxies.len(), 1);
+        assert!(builder.into_simple().is_ok());
+        match ApiBuilder::new("*3MAGWID", "1234").with_proxy("not a url") {
+            Err(ApiBuilderError::InvalidProxy(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ping() {
+        let server = TestServer::start(vec![(200, "100"), (401, "")]);
+        let api = simple_api(ApiBuilder::new("*3MAGWID", "1234"), &server);
+        assert!(api.ping().is_ok());
+        match api.ping() {
+            Err(ApiError::BadCredentials) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_custom_endpoint("http://127.0.0.1:1")
+            .into_simple()
+            .unwrap();
+        match api.ping() {
+            Err(ApiError::Network(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tls_settings() {
+        match ApiBuilder::new("*3MAGWID", "1234").with_root_certificate_der(b"not a certificate") {
+            Err(ApiBuilderError::InvalidCertificate(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match ApiBuilder::new("*3MAGWID", "1234").with_danger_accept_self_signed_cert_der(b"not a certificate") {
+            Err(ApiBuilderError::InvalidCertificate(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        let builder = ApiBuilder::new("*3MAGWID", "1234").with_redirect_policy(RedirectPolicy::Limited(3));
+        assert_eq!(builder.client_config.redirect_policy, RedirectPolicy::Limited(3));
+        assert_eq!(ApiBuilder::new("*3MAGWID", "1234").client_config.redirect_policy, RedirectPolicy::None);
+        let builder = ApiBuilder::new("*3MAGWID", "1234").with_danger_disable_hostname_verification();
+        assert!(builder.client_config.disable_hostname_verification);
+        assert!(builder.into_simple().is_ok());
+    }
+
+    #[test]
+    fn test_debug_hides_secret() {
+        let (_, sec) = box_::gen_keypair();
+        let builder = ApiBuilder::new("*3MAGWID", "topsecret123");
+        assert!(!format!("{:?}", builder).contains("topsecret123"));
+        let api = ApiBuilder::new("*3MAGWID", "topsecret123").into_simple().unwrap();
+        assert!(!format!("{:?}", api).contains("topsecret123"));
+        let api = ApiBuilder::new("*3MAGWID", "topsecret123").with_private_key(sec).into_e2e().unwrap();
+        assert!(!format!("{:?}", api).contains("topsecret123"));
+    }
+
+    #[test]
+    fn test_request_hook() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let calls_clone = calls.clone();
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_request_hook(move |name, _, outcome| {
+                calls_clone.lock().unwrap().push((name.to_string(), outcome.is_ok()));
+            })
+            .into_simple()
+            .unwrap();
+        let ok: Result<u8, ApiError> = api.settings.timed("lookup_credits", || Ok(1));
+        assert_eq!(ok.unwrap(), 1);
+        let err: Result<u8, ApiError> = api.settings.timed("send_simple", || Err(ApiError::ServerError));
+        assert!(err.is_err());
+        assert_eq!(*calls.lock().unwrap(), vec![
+            ("lookup_credits".to_string(), true),
+            ("send_simple".to_string(), false),
+        ]);
+    }
+
+    #[test]
+    fn test_blob_upload_with_stats() {
+        let (_, sec) = box_::gen_keypair();
+        let server = TestServer::start(vec![(200, "00112233445566778899aabbccddeeff")]);
+        let api = e2e_api(ApiBuilder::new("*3MAGWID", "1234").with_private_key(sec), &server);
+        let (blob_id, stats) = api.blob_upload_raw_with_stats(&[1; 1000]).unwrap();
+        assert_eq!(blob_id, BlobId::from_str("00112233445566778899aabbccddeeff").unwrap());
+        assert_eq!(stats.bytes, 1000);
+        assert!(stats.bytes_per_sec() > 0.0);
+    }
+
+    #[test]
+    fn test_blob_upload_with_progress() {
+        let (_, sec) = box_::gen_keypair();
+        let server = TestServer::start(vec![(200, "00112233445566778899aabbccddeeff")]);
+        let api = e2e_api(ApiBuilder::new("*3MAGWID", "1234").with_private_key(sec), &server);
+        let last = Arc::new(Mutex::new(None));
+        let reported = last.clone();
+        let blob_id = api.blob_upload_raw_with_progress(&[1; 1000], move |sent, total| {
+            *reported.lock().unwrap() = Some((sent, total));
+        }).unwrap();
+        assert_eq!(blob_id, BlobId::from_str("00112233445566778899aabbccddeeff").unwrap());
+        assert_eq!(*last.lock().unwrap(), Some((1000, 1000)));
+    }
+
+    #[test]
+    fn test_upload_stats_bytes_per_sec() {
+        let stats = UploadStats { bytes: 3000, duration: Duration::from_millis(1500) };
+        assert_eq!(stats.bytes_per_sec(), 2000.0);
+        let stats = UploadStats { bytes: 3000, duration: Duration::from_secs(0) };
+        assert_eq!(stats.bytes_per_sec(), 0.0);
+    }
+
+    #[test]
+    fn test_send_prepared() {
+        let (_, sec) = box_::gen_keypair();
+        let (other_pub, _) = box_::gen_keypair();
+        let mut api = ApiBuilder::new("*3MAGWID", "1234").with_private_key(sec).into_e2e().unwrap();
+        let encrypted = api.encrypt_text_msg("Later", &RecipientKey(other_pub)).unwrap();
+        let prepared = PreparedMessage::new("ECHOECHO", encrypted, MessageFlags::NO_PUSH);
+        let stored = ::serde_json::to_string(&prepared).unwrap();
+        let restored: PreparedMessage = ::serde_json::from_str(&stored).unwrap();
+        assert_eq!(restored, prepared);
+
+        let server = TestServer::start(vec![(200, "0102030405060708")]);
+        api.conn = server.conn();
+        assert_eq!(api.send_prepared(&restored).unwrap(), "0102030405060708");
+        let body = server.requests()[0].body_str();
+        assert!(body.contains("to=ECHOECHO"));
+        assert!(body.contains("noPush=1"));
+        assert!(body.contains(&format!("box={}", ::data_encoding::HEXLOWER.encode(&prepared.box_data))));
+    }
+
+    #[test]
+    fn test_send_all() {
+        let server = TestServer::start(vec![(200, "0102030405060708"), (200, "0807060504030201")]);
+        let api = simple_api(ApiBuilder::new("*3MAGWID", "1234").with_recipient_denylist(vec!["BLOCKED1"]), &server);
+        let results = api.send_all(&[
+            (Recipient::new_id("ECHOECHO"), "Hallo"),
+            (Recipient::new_id("BLOCKED1"), "Hello"),
+            (Recipient::new_email("a@example.com"), "Bonjour"),
+        ]);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), "0102030405060708");
+        match results[1] {
+            Err(ApiError::RecipientNotAllowed(ref id)) => assert_eq!(id, "BLOCKED1"),
+            ref other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(results[2].as_ref().unwrap(), "0807060504030201");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].body_str().contains("text=Hallo"));
+        assert!(requests[1].body_str().contains("text=Bonjour"));
+    }
+
+    #[test]
+    fn test_send_limit() {
+        let server = TestServer::start(vec![(200, "0102030405060708"), (400, ""), (200, "0807060504030201")]);
+        let api = simple_api(ApiBuilder::new("*3MAGWID", "1234").with_send_limit(2), &server);
+        let to = Recipient::new_id("ECHOECHO");
+        assert!(api.send(&to, "1").is_ok());
+        assert_eq!(api.sends_performed(), 1);
+
+        // Failed sends are not counted
+        assert!(api.send(&to, "2").is_err());
+        assert_eq!(api.sends_performed(), 1);
+
+        // The limit is shared with clones
+        let clone = api.clone();
+        assert!(clone.send(&to, "3").is_ok());
+        match api.send(&to, "4") {
+            Err(ApiError::SpendLimitReached(2)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(api.sends_performed(), 2);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_dedup_window() {
+        let server = TestServer::start(vec![(200, "0000000000000001"), (200, "0000000000000002")]);
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_dedup_window(Duration::from_secs(60));
+        let api = simple_api(builder, &server);
+        let to = Recipient::new_id("ECHOECHO");
+        assert_eq!(api.send(&to, "Alert").unwrap(), "0000000000000001");
+        assert_eq!(api.send(&to, "Alert").unwrap(), "0000000000000001");
+        assert_eq!(api.clone().send(&to, "Alert").unwrap(), "0000000000000001");
+        // A different recipient (or text) is sent
+        assert_eq!(api.send(&Recipient::new_phone("ECHOECHO"), "Alert").unwrap(), "0000000000000002");
+        assert_eq!(server.requests().len(), 2);
+        let entries = api.settings.dedup_cache.as_ref().unwrap().entries.lock().unwrap().clone();
+        assert!(entries.contains_key(&DedupCache::key("id", "ECHOECHO", "Alert")));
+        assert!(!format!("{:?}", api).contains("Alert"));
+
+        // Expired entries are sent again
+        let server = TestServer::start(vec![(200, "0000000000000001"), (200, "0000000000000002")]);
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_dedup_window(Duration::from_secs(0));
+        let api = simple_api(builder, &server);
+        assert_eq!(api.send(&to, "Alert").unwrap(), "0000000000000001");
+        assert_eq!(api.send(&to, "Alert").unwrap(), "0000000000000002");
+
+        // Clearing the caches sends the text again
+        let server = TestServer::start(vec![(200, "0000000000000001"), (200, "0000000000000002")]);
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_dedup_window(Duration::from_secs(60));
+        let api = simple_api(builder, &server);
+        assert_eq!(api.send(&to, "Alert").unwrap(), "0000000000000001");
+        api.clone().clear_caches();
+        assert_eq!(api.send(&to, "Alert").unwrap(), "0000000000000002");
+    }
+
+    #[test]
+    fn test_capability_cache() {
+        let server = TestServer::start(vec![(200, "text,image"), (200, "text"), (200, "text,file")]);
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_capability_cache(Duration::from_secs(3600));
+        let mut api = simple_api(builder, &server);
+        assert!(api.lookup_capabilities("ECHOECHO").unwrap().image);
+        assert!(api.lookup_capabilities("ECHOECHO").unwrap().image);
+        assert!(!api.lookup_capabilities("OTHERID1").unwrap().image);
+        assert_eq!(server.requests().len(), 2);
+
+        // Expired entries are looked up again
+        api.settings.capability_cache = Some(CapabilityCache::new(Duration::from_secs(0)));
+        assert!(api.lookup_capabilities("ECHOECHO").unwrap().file);
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_negative_lookup_cache() {
+        let server = TestServer::start(vec![(404, ""), (404, ""), (200, "ECHOECHO"), (404, ""), (404, "")]);
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_negative_cache_ttl(Duration::from_secs(3600));
+        let mut api = simple_api(builder, &server);
+        let criterion = LookupCriterion::Email("nobody@example.com".into());
+        assert_eq!(api.lookup_id(&criterion).unwrap(), None);
+        assert_eq!(api.lookup_id(&criterion).unwrap(), None);
+        match api.lookup_pubkey("NOTFOUND") {
+            Err(ApiError::IdNotFound) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(api.lookup_pubkey("NOTFOUND").is_err());
+        assert_eq!(server.requests().len(), 2);
+
+        // Expired entries are looked up again
+        api.settings.negative_lookup_cache = Some(NegativeLookupCache::new(Duration::from_secs(0)));
+        assert_eq!(api.lookup_id(&criterion).unwrap(), Some("ECHOECHO".to_string()));
+        assert_eq!(api.lookup_id(&LookupCriterion::Phone("41791234567".into())).unwrap(), None);
+        assert_eq!(api.lookup_id(&LookupCriterion::Phone("41791234567".into())).unwrap(), None);
+        assert_eq!(server.requests().len(), 5);
+    }
+
+    #[test]
+    fn test_negative_lookup_cache_prune() {
+        let cache = NegativeLookupCache::new(Duration::from_millis(50));
+        cache.insert("id", "email old@example.com");
+        ::std::thread::sleep(Duration::from_millis(60));
+        cache.insert("id", "email new@example.com");
+        assert_eq!(cache.entries.lock().unwrap().len(), 1);
+        assert!(cache.contains("id", "email new@example.com"));
+        assert!(!format!("{:?}", cache).contains("example.com"));
+    }
+
+    #[test]
+    fn test_clear_caches() {
+        let (_, sec) = box_::gen_keypair();
+        let (other_pub, _) = box_::gen_keypair();
+        let server = TestServer::start(vec![(200, "text"), (200, "text,image")]);
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key(sec)
+            .with_capability_cache(Duration::from_secs(3600))
+            .with_precheck_credits(true);
+        let api = e2e_api(builder, &server);
+        assert!(!api.lookup_capabilities("ECHOECHO").unwrap().image);
+        api.pubkey_cache.lock().unwrap().insert("ECHOECHO".to_string(), RecipientKey(other_pub));
+        api.settings.credit_precheck.as_ref().unwrap().set(100);
+
+        let clone = api.clone();
+        clone.clear_caches();
+        assert!(api.pubkey_cache.lock().unwrap().is_empty());
+        assert_eq!(api.settings.credit_precheck.as_ref().unwrap().get(), None);
+        assert!(api.lookup_capabilities("ECHOECHO").unwrap().image);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn test_create_group() {
+        let (_, sec) = box_::gen_keypair();
+        let (other_pub, _) = box_::gen_keypair();
+        let server = TestServer::start(vec![
+            (200, "0102030405060708090a0b0c0d0e0f10"),
+            (200, "0000000000000001"),
+            (200, "0000000000000002"),
+            (200, "0000000000000003"),
+            (404, ""),
+        ]);
+        let api = e2e_api(ApiBuilder::new("*3MAGWID", "1234").with_private_key(sec), &server);
+        api.pubkey_cache.lock().unwrap().insert("ECHOECHO".to_string(), RecipientKey(other_pub));
+        let group_id = GroupId::new([1; 8]);
+
+        match api.create_group(&group_id, "Bots", &["ECHOECHO", "SHORT"], None) {
+            Err(ApiError::BadRecipient(ref id)) => assert_eq!(id, "SHORT"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match api.create_group(&group_id, "Bots", &["echoecho"], None) {
+            Err(ApiError::BadRecipient(ref id)) => assert_eq!(id, "echoecho"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match api.create_group(&group_id, "Bots", &["ECHOECHO", "*3MAGWID"], Some(b"photo")) {
+            Err(ApiError::BadRecipient(ref id)) => assert_eq!(id, "*3MAGWID"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(server.requests().is_empty());
+
+        let results = api.create_group(&group_id, "Bots", &["ECHOECHO", "*OTHERID"], Some(b"photo")).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, "ECHOECHO");
+        assert!(results[0].1.is_ok());
+        assert_eq!(results[1].0, "*OTHERID");
+        match results[1].1 {
+            Err(ApiError::IdNotFound) => (),
+            ref other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths[0], "/upload_blob?from=*3MAGWID&secret=1234");
+        assert_eq!(&paths[1..4], &["/send_e2e", "/send_e2e", "/send_e2e"]);
+        assert!(paths[4].starts_with("/pubkeys/*OTHERID"));
+    }
+
+    #[test]
+    fn test_send_e2e_text() {
+        let (_, sec) = box_::gen_keypair();
+        let (other_pub, _) = box_::gen_keypair();
+        let server = TestServer::start(vec![
+            (200, "0102030405060708"),
+            (200, "0807060504030201"),
+        ]);
+        let api = e2e_api(ApiBuilder::new("*3MAGWID", "1234").with_private_key(sec), &server);
+        assert_eq!(api.send_e2e_text_to("ECHOECHO", &RecipientKey(other_pub.clone()), "Hi").unwrap(), "0102030405060708");
+
+        // The cached key is used instead of a lookup
+        api.pubkey_cache.lock().unwrap().insert("ECHOECHO".to_string(), RecipientKey(other_pub));
+        assert_eq!(api.send_e2e_text("ECHOECHO", "Hi").unwrap(), "0807060504030201");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.path == "/send_e2e"));
+    }
+
+    #[test]
+    fn test_message_builder() {
+        let server = TestServer::start(vec![(200, "0102030405060708")]);
+        let api = simple_api(ApiBuilder::new("*3MAGWID", "1234"), &server);
+        match api.message(Recipient::new_id("ECHOECHO")).send() {
+            Err(ApiError::EmptyMessage) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match api.message(Recipient::new_id("ECHOECHO")).text("").send() {
+            Err(ApiError::EmptyMessage) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        let msg_id = api.message(Recipient::new_id("ECHOECHO")).text("Hi").send().unwrap();
+        assert_eq!(msg_id, "0102030405060708");
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].body_str().contains("text=Hi"));
+    }
+
+    #[test]
+    fn test_e2e_message_builder() {
+        let (_, sec) = box_::gen_keypair();
+        let (other_pub, _) = box_::gen_keypair();
+        let server = TestServer::start(vec![
+            (200, "0102030405060708"),
+            (200, "0807060504030201"),
+        ]);
+        let api = e2e_api(ApiBuilder::new("*3MAGWID", "1234").with_private_key(sec), &server);
+        match api.message("ECHOECHO").text("").send() {
+            Err(ApiError::EmptyMessage) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        let msg_id = api.message("ECHOECHO")
+            .text("Hi")
+            .recipient_key(RecipientKey(other_pub.clone()))
+            .no_push()
+            .no_delivery_receipts()
+            .send()
+            .unwrap();
+        assert_eq!(msg_id, "0102030405060708");
+
+        // The cached key is used instead of a lookup
+        api.pubkey_cache.lock().unwrap().insert("ECHOECHO".to_string(), RecipientKey(other_pub));
+        assert_eq!(api.message("ECHOECHO").text("Hi").send().unwrap(), "0807060504030201");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.path == "/send_e2e"));
+        let first = requests[0].body_str();
+        assert!(first.contains("noPush=1"));
+        assert!(first.contains("noDeliveryReceipts=1"));
+        let second = requests[1].body_str();
+        assert!(!second.contains("noPush"));
+        assert!(!second.contains("noDeliveryReceipts"));
+    }
+
+    #[test]
+    fn test_precheck_credits() {
+        let server = TestServer::start(vec![(200, "0")]);
+        let mut api = simple_api(ApiBuilder::new("*3MAGWID", "1234").with_precheck_credits(true), &server);
+        for _ in 0..2 {
+            match api.send(&Recipient::new_id("ECHOECHO"), "Hi") {
+                Err(ApiError::NoCredits) => (),
+                other => panic!("Unexpected result: {:?}", other),
+            }
+        }
+        // The credit count is only looked up once
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].path.starts_with("/credits?"));
+
+        let server = TestServer::start(vec![(200, "10"), (200, "0102030405060708"), (200, "0807060504030201")]);
+        api.conn = server.conn();
+        api.settings.credit_precheck = Some(CreditCache::default());
+        assert_eq!(api.send(&Recipient::new_id("ECHOECHO"), "Hi").unwrap(), "0102030405060708");
+        assert_eq!(api.send(&Recipient::new_id("ECHOECHO"), "Hi").unwrap(), "0807060504030201");
+        assert_eq!(server.requests().len(), 3);
+        assert_eq!(api.settings.credit_precheck.as_ref().unwrap().get(), Some(8));
+
+        // Sent messages are subtracted from the cached credit count
+        let server = TestServer::start(vec![(200, "1"), (200, "0102030405060708")]);
+        api.conn = server.conn();
+        api.settings.credit_precheck = Some(CreditCache::default());
+        assert_eq!(api.send(&Recipient::new_id("ECHOECHO"), "Hi").unwrap(), "0102030405060708");
+        match api.send(&Recipient::new_id("ECHOECHO"), "Hi again") {
+            Err(ApiError::NoCredits) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(server.requests().len(), 2);
+
+        // Invalid texts are rejected before the credit lookup
+        let server = TestServer::start(vec![]);
+        api.conn = server.conn();
+        api.settings.credit_precheck = Some(CreditCache::default());
+        match api.send(&Recipient::new_id("ECHOECHO"), "") {
+            Err(ApiError::EmptyMessage) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match api.send(&Recipient::new_id("ECHOECHO"), &"a".repeat(MAX_SIMPLE_MESSAGE_LEN + 1)) {
+            Err(ApiError::MessageTooLong) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn test_capability_policy() {
+        let (_, sec) = box_::gen_keypair();
+        let (other_pub, _) = box_::gen_keypair();
+        let server = TestServer::start(vec![(200, "text,image"), (200, "text,image"), (200, "0102030405060708")]);
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key(sec)
+            .with_capability_policy(CapabilityPolicy::CheckAndFail);
+        let mut api = e2e_api(builder, &server);
+        let encrypted = api.encrypt_raw(b"data", &RecipientKey(other_pub));
+        match api.send_media("ECHOECHO", &encrypted, MessageType::File) {
+            Err(ApiError::MissingCapability(ref id, ref capability)) if id == "ECHOECHO" && capability == "file" => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(api.send_media("ECHOECHO", &encrypted, MessageType::Image).unwrap(), "0102030405060708");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].path.starts_with("/capabilities/ECHOECHO?"));
+        assert_eq!(requests[2].path, "/send_e2e");
+
+        // Other policies
+        let server = TestServer::start(vec![(200, "text"), (200, "0102030405060708"), (200, "0807060504030201")]);
+        api.conn = server.conn();
+        api.settings.capability_policy = CapabilityPolicy::CheckAndWarn;
+        assert_eq!(api.send_media("ECHOECHO", &encrypted, MessageType::File).unwrap(), "0102030405060708");
+        api.settings.capability_policy = CapabilityPolicy::Ignore;
+        assert_eq!(api.send_media("ECHOECHO", &encrypted, MessageType::File).unwrap(), "0807060504030201");
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn test_control_char_validation() {
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_control_char_validation()
+            .into_simple()
+            .unwrap();
+        match api.send(&Recipient::new_id("ECHOECHO"), "Beep\u{7}") {
+            Err(ApiError::ControlCharacter('\u{7}', 4)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_cancellation_token(token.clone())
+            .into_simple()
+            .unwrap();
+        let ok: Result<u8, ApiError> = api.settings.timed("lookup_credits", || Ok(1));
+        assert!(ok.is_ok());
+        token.cancel();
+        assert!(token.is_cancelled());
+        match api.send_chunked(&Recipient::new_id("ECHOECHO"), "Hi") {
+            Err(ApiError::Cancelled) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_msg() {
+        let (our_pub, our_sec) = box_::gen_keypair();
+        let (their_pub, their_sec) = box_::gen_keypair();
+        let ours = ApiBuilder::new("*3MAGWID", "1234").with_private_key(our_sec).into_e2e().unwrap();
+        let theirs = ApiBuilder::new("*OTHERID", "1234").with_private_key(their_sec).into_e2e().unwrap();
+
+        let encrypted = theirs.encrypt_text_msg("Hello", &RecipientKey(our_pub)).unwrap();
+        match ours.decrypt_msg(&incoming("*OTHERID", &encrypted), &RecipientKey(their_pub)).unwrap() {
+            Message::Text(text) => assert_eq!(text, "Hello"),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_msg_previous_key() {
+        let (old_pub, old_sec) = box_::gen_keypair();
+        let (_, new_sec) = box_::gen_keypair();
+        let (their_pub, their_sec) = box_::gen_keypair();
+        let ours = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key(new_sec)
+            .with_previous_private_key(old_sec)
+            .into_e2e()
+            .unwrap();
+        let theirs = ApiBuilder::new("*OTHERID", "1234").with_private_key(their_sec).into_e2e().unwrap();
+
+        let encrypted = theirs.encrypt_text_msg("Hello", &RecipientKey(old_pub)).unwrap();
+        match ours.decrypt_msg(&incoming("*OTHERID", &encrypted), &RecipientKey(their_pub)).unwrap() {
+            Message::Text(text) => assert_eq!(text, "Hello"),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_msg_too_old() {
+        let (our_pub, our_sec) = box_::gen_keypair();
+        let (their_pub, their_sec) = box_::gen_keypair();
+        let ours = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key(our_sec)
+            .with_max_message_age(Duration::from_secs(3600))
+            .into_e2e()
+            .unwrap();
+        let theirs = ApiBuilder::new("*OTHERID", "1234").with_private_key(their_sec).into_e2e().unwrap();
+
+        let encrypted = theirs.encrypt_text_msg("Hello", &RecipientKey(our_pub)).unwrap();
+        match ours.decrypt_msg(&incoming("*OTHERID", &encrypted), &RecipientKey(their_pub)) {
+            Err(ApiError::MessageTooOld) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_msg_wrong_key() {
+        let (our_pub, our_sec) = box_::gen_keypair();
+        let (_, their_sec) = box_::gen_keypair();
+        let (wrong_pub, _) = box_::gen_keypair();
+        let ours = ApiBuilder::new("*3MAGWID", "1234").with_private_key(our_sec).into_e2e().unwrap();
+        let theirs = ApiBuilder::new("*OTHERID", "1234").with_private_key(their_sec).into_e2e().unwrap();
+
+        let encrypted = theirs.encrypt_text_msg("Hello", &RecipientKey(our_pub)).unwrap();
+        match ours.decrypt_msg(&incoming("*OTHERID", &encrypted), &RecipientKey(wrong_pub)) {
+            Err(ApiError::CryptoError(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_download_media() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+        let nonce = box_::gen_nonce();
+        let encrypted_image = box_::seal(b"image", &nonce, &own_pub, &other_sec);
+        let blob_key = BlobKey::generate();
+        let encrypted_file = encrypt_blob(b"file", &blob_key);
+
+        let server = TestServer::start_bytes(vec![
+            (200, encrypted_image.clone()),
+            (200, encrypted_file.clone()),
+            (200, encrypted_file),
+        ]);
+        let api = e2e_api(ApiBuilder::new("*3MAGWID", "1234").with_private_key(own_sec), &server);
+        let sender_key = RecipientKey(other_pub);
+
+        let image = Message::Image(ImageMessage { blob_id: BlobId::new([1; 16]), size_bytes: 5, nonce: nonce.0 });
+        assert_eq!(api.download_media(&image, &sender_key, BlobDownloadMode::Consume).unwrap(), b"image");
+
+        let mut file = FileMessage::new(BlobId::new([2; 16]), None, blob_key, "text/plain".parse().unwrap(), None, 4, None);
+        assert_eq!(api.download_media(&Message::File(file.clone()), &sender_key, BlobDownloadMode::Peek).unwrap(), b"file");
+        file.file_size_bytes = 5;
+        match api.download_media(&Message::File(file), &sender_key, BlobDownloadMode::Peek) {
+            Err(ApiError::MediaSizeMismatch(5, 4)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match api.download_media(&Message::Text("Hi".into()), &sender_key, BlobDownloadMode::Peek) {
+            Err(ApiError::NotMedia) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let requests = server.requests();
+        assert!(requests[0].path.starts_with("/blobs/01010101010101010101010101010101?done=1&"));
+        assert!(requests[1].path.starts_with("/blobs/02020202020202020202020202020202?from="));
+    }
+
+    #[test]
+    fn test_send_file_chunked() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+        let server = TestServer::start(vec![
+            (200, "00000000000000000000000000000001"),
+            (200, "0000000000000001"),
+            (200, "00000000000000000000000000000002"),
+            (200, "0000000000000002"),
+            (500, ""),
+        ]);
+        let api = e2e_api(ApiBuilder::new("*3MAGWID", "1234").with_private_key(own_sec), &server);
+        let recipient_key = RecipientKey(other_pub);
+
+        match api.send_file_chunked("ECHOECHO", &recipient_key, "data.bin", b"0123456789", 4) {
+            Err(ApiError::PartiallySent(ref sent, _)) => assert_eq!(sent, &["0000000000000001", "0000000000000002"]),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // The file messages refer to the uploaded chunks
+        let requests = server.requests();
+        assert_eq!(requests.len(), 5);
+        let mut assembler = ::chunked::ChunkAssembler::new();
+        for (i, pair) in requests[..4].chunks(2).enumerate() {
+            let body = pair[1].body_str();
+            let param = |name: &str| body.split('&')
+                .find(|p| p.starts_with(&format!("{}=", name)))
+                .map(|p| p[name.len() + 1..].to_string())
+                .unwrap();
+            let msg = EncryptedMessage::from_hex(&param("nonce"), &param("box")).unwrap();
+            let decrypted = decrypt_with_any_key(&msg.ciphertext, &msg.nonce, &own_pub, Some(&other_sec)).unwrap();
+            let file = match decode(&decrypted).unwrap() {
+                Message::File(file) => file,
+                other => panic!("Unexpected message: {:?}", other),
+            };
+            assert_eq!(file.file_name, Some(format!("data.bin.part{}", i + 1)));
+            assert_eq!(file.file_blob_id, BlobId::new([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, i as u8 + 1]));
+            // Extract the blob from the multipart body
+            let upload = &pair[0].body;
+            let start = upload.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+            let end = upload.len() - "\r\n--3ma-d84f64f5-a138-4b0a-9e25-339257990c81-3ma--\r\n".len();
+            let chunk_data = decrypt_blob_in_place(upload[start..end].to_vec(), &file.blob_encryption_key).unwrap();
+            assert_eq!(chunk_data.len(), file.file_size_bytes as usize);
+            let chunk = FileChunk::from_bytes(&chunk_data).unwrap();
+            assert_eq!(chunk.total, 3);
+            assert_eq!(assembler.add(chunk).unwrap(), None);
+        }
+        assert_eq!(assembler.pending(), 1);
+    }
+
+    #[test]
+    fn test_send_file_chunked_checks() {
+        let (_, sec) = box_::gen_keypair();
+        let (other_pub, _) = box_::gen_keypair();
+        let server = TestServer::start(vec![
+            (200, "text"),
+            (200, "text,file"),
+            (200, "00000000000000000000000000000001"),
+            (200, "0000000000000001"),
+            (200, "00000000000000000000000000000002"),
+            (200, "0000000000000002"),
+        ]);
+        let builder = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key(sec)
+            .with_capability_policy(CapabilityPolicy::CheckAndFail);
+        let api = e2e_api(builder, &server);
+        let recipient_key = RecipientKey(other_pub);
+
+        match api.send_file_chunked("ECHOECHO", &recipient_key, "data.bin", b"0123456789", 0) {
+            Err(ApiError::InvalidChunkSize) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(server.requests().is_empty());
+
+        // Nothing is uploaded if the recipient can't receive files
+        match api.send_file_chunked("ECHOECHO", &recipient_key, "data.bin", b"0123456789", 5) {
+            Err(ApiError::MissingCapability(_, _)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(server.requests().len(), 1);
+
+        // The capabilities are looked up once for all chunks
+        let sent = api.send_file_chunked("OTHERID1", &recipient_key, "data.bin", b"0123456789", 5).unwrap();
+        assert_eq!(sent, vec!["0000000000000001", "0000000000000002"]);
+        let paths: Vec<String> = server.requests().into_iter().map(|r| r.path).collect();
+        assert_eq!(paths.len(), 6);
+        assert!(paths[1].starts_with("/capabilities/OTHERID1?"));
+        assert!(paths[2..].iter().all(|p| !p.starts_with("/capabilities/")));
+    }
+
+    #[test]
+    fn test_typing_indicator() {
+        let (_, sec) = box_::gen_keypair();
+        let (other_pub, _) = box_::gen_keypair();
+        let server = TestServer::start(vec![
+            (200, "0000000000000001"),
+            (200, "0000000000000002"),
+            (200, "0000000000000003"),
+            (500, ""),
+            (200, "0000000000000004"),
+        ]);
+        let api = e2e_api(ApiBuilder::new("*3MAGWID", "1234").with_private_key(sec), &server);
+        let key = RecipientKey(other_pub);
+        {
+            let mut indicator = api.start_typing("ECHOECHO", &key).unwrap();
+            assert!(indicator.is_typing());
+            // Toggles only send a message if the state changes
+            indicator.start().unwrap();
+            indicator.stop().unwrap();
+            indicator.stop().unwrap();
+            assert!(!indicator.is_typing());
+            indicator.start().unwrap();
+            // A failed stop is retried when dropped
+            assert!(indicator.stop().is_err());
+            assert!(indicator.is_typing());
+        }
+        let requests = server.requests();
+        assert_eq!(requests.len(), 5);
+        assert!(requests.iter().all(|r| r.path == "/send_e2e" && r.body_str().contains("noPush=1")));
+    }
+
+    #[test]
+    fn test_download_thumbnail() {
+        let (_, own_sec) = box_::gen_keypair();
+        let (encrypted_file, encrypted_thumbnail, blob_key) = encrypt_file_data(b"file", Some(b"thumb"));
+        let server = TestServer::start_bytes(vec![(200, encrypted_thumbnail.unwrap()), (200, encrypted_file)]);
+        let api = e2e_api(ApiBuilder::new("*3MAGWID", "1234").with_private_key(own_sec), &server);
+
+        let file = FileMessage::new(BlobId::new([2; 16]), Some(BlobId::new([3; 16])), blob_key.clone(),
+                                    "text/plain".parse().unwrap(), None, 4, None);
+        let msg = Message::File(file);
+        assert_eq!(api.download_thumbnail(&msg, BlobDownloadMode::Peek).unwrap(), Some(b"thumb".to_vec()));
+        assert!(server.requests()[0].path.starts_with("/blobs/03030303030303030303030303030303?"));
+
+        let file = FileMessage::new(BlobId::new([2; 16]), None, blob_key, "text/plain".parse().unwrap(), None, 4, None);
+        assert_eq!(api.download_thumbnail(&Message::File(file), BlobDownloadMode::Peek).unwrap(), None);
+        match api.download_thumbnail(&Message::Text("Hi".into()), BlobDownloadMode::Peek) {
+            Err(ApiError::NotMedia) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_encrypt_reaction() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+        let api = ApiBuilder::new("*3MAGWID", "1234").with_private_key(own_sec).into_e2e().unwrap();
+        let other_api = ApiBuilder::new("*OTHERID", "1234").with_private_key(other_sec).into_e2e().unwrap();
+
+        let message_id = MessageId::new([1, 2, 3, 4, 5, 6, 7, 8]);
+        for &(agree, receipt_type) in &[(true, ReceiptType::Acknowledged), (false, ReceiptType::Declined)] {
+            let encrypted = api.encrypt_reaction(&message_id, agree, &RecipientKey(other_pub));
+            match other_api.decrypt_msg(&incoming("*3MAGWID", &encrypted), &RecipientKey(own_pub)).unwrap() {
+                Message::DeliveryReceipt(ref receipt) => {
+                    assert_eq!(receipt.receipt_type, receipt_type);
+                    assert_eq!(receipt.message_ids, vec![message_id]);
+                },
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decrypt_msg_precomputed() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+        let api = ApiBuilder::new("*3MAGWID", "1234").with_private_key(own_sec).into_e2e().unwrap();
+        let other_api = ApiBuilder::new("*OTHERID", "1234").with_private_key(other_sec).into_e2e().unwrap();
+
+        let key = other_api.precompute_key(&RecipientKey(own_pub));
+        let encrypted = api.encrypt_text_msg("Hello", &RecipientKey(other_pub)).unwrap();
+        match other_api.decrypt_msg_precomputed(&incoming("*3MAGWID", &encrypted), &key).unwrap() {
+            Message::Text(ref text) => assert_eq!(text, "Hello"),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_verify_callback_macs() {
+        let api = ApiBuilder::new("*3MAGWID", "1234").with_private_key(box_::gen_keypair().1).into_e2e().unwrap();
+        let body = b"from=ECHOECHO&to=*TESTTST&messageId=0102030405060708&date=1524658864\
+                     &nonce=000102030405060708090a0b0c0d0e0f1011121314151617\
+                     &box=0011ff&mac=e462f26cb82c75849c6fefeb5c415bb99b24fa5fa807ae91f588f0af9199f766";
+        let valid = IncomingMessage::from_urlencoded_bytes(body).unwrap();
+        let mut invalid = valid.clone();
+        invalid.mac[0] ^= 1;
+        let results = api.verify_callback_macs(vec![&valid, &invalid]);
+        assert!(results[0].is_ok());
+        match results[1] {
+            Err(ApiError::InvalidMac) => (),
+            ref other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_msg_from_cached() {
+        let (our_pub, our_sec) = box_::gen_keypair();
+        let (their_pub, their_sec) = box_::gen_keypair();
+        let ours = ApiBuilder::new("*3MAGWID", "1234").with_private_key(our_sec).into_e2e().unwrap();
+        let theirs = ApiBuilder::new("*OTHERID", "1234").with_private_key(their_sec).into_e2e().unwrap();
+        ours.pubkey_cache.lock().unwrap().insert("*OTHERID".into(), RecipientKey(their_pub));
+
+        // No network request is made, since the key is cached
+        let encrypted = theirs.encrypt_text_msg("Hello", &RecipientKey(our_pub)).unwrap();
+        match ours.decrypt_msg_from(&incoming("*OTHERID", &encrypted)).unwrap() {
+            Message::Text(text) => assert_eq!(text, "Hello"),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_msg_from_invalid_pubkey() {
+        let (our_pub, our_sec) = box_::gen_keypair();
+        let (_, their_sec) = box_::gen_keypair();
+        let server = TestServer::start(vec![(200, "not a hex key")]);
+        let mut ours = ApiBuilder::new("*3MAGWID", "1234").with_private_key(our_sec).into_e2e().unwrap();
+        ours.conn = server.conn();
+        let theirs = ApiBuilder::new("*OTHERID", "1234").with_private_key(their_sec).into_e2e().unwrap();
+
+        let encrypted = theirs.encrypt_text_msg("Hello", &RecipientKey(our_pub)).unwrap();
+        match ours.decrypt_msg_from(&incoming("*OTHERID", &encrypted)) {
+            Err(ApiError::ParseError(ref msg)) => assert!(msg.starts_with("Invalid public key for *OTHERID: ")),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(ours.pubkey_cache.lock().unwrap().is_empty());
+    }
+
 }
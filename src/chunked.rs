@@ -0,0 +1,394 @@
+//! Splitting files into chunks that are sent as separate file messages.
+//!
+//! Files that are too large for a single blob can be sent with
+//! [`E2eApi::send_file_chunked`](../struct.E2eApi.html#method.send_file_chunked),
+//! which uploads every chunk as a separate blob and sends a file message for
+//! each of them. The receiver downloads the chunks with
+//! [`E2eApi::download_media`](../struct.E2eApi.html#method.download_media),
+//! parses them with [`FileChunk::from_bytes`](struct.FileChunk.html#method.from_bytes)
+//! and reassembles the file with a
+//! [`ChunkAssembler`](struct.ChunkAssembler.html).
+//!
+//! ## Chunk format
+//!
+//! The chunk format is specific to this crate, Threema apps show every chunk
+//! as a separate file. The data of every chunk starts with a 20 byte header:
+//!
+//! - 4 bytes: the magic bytes `TGWC`
+//! - 8 bytes: the transfer ID, chosen randomly and shared by all chunks of a
+//!   file
+//! - 4 bytes: the index of the chunk, starting at 0 (little endian)
+//! - 4 bytes: the total number of chunks (little endian)
+//!
+//! The header is followed by the chunk data. Concatenating the data of all
+//! chunks in the order of their index results in the original file. Chunks
+//! may arrive in any order.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sodiumoxide::randombytes::randombytes_into;
+
+use ::errors::ApiError;
+
+
+/// The magic bytes at the start of every chunk.
+const CHUNK_MAGIC: &'static [u8; 4] = b"TGWC";
+
+/// The length of the chunk header, in bytes.
+const CHUNK_HEADER_LEN: usize = 20;
+
+/// The default maximum number of incomplete files kept by a
+/// [`ChunkAssembler`](struct.ChunkAssembler.html).
+pub const DEFAULT_MAX_PENDING_TRANSFERS: usize = 16;
+
+/// The default maximum number of chunks of a file accepted by a
+/// [`ChunkAssembler`](struct.ChunkAssembler.html).
+pub const DEFAULT_MAX_CHUNKS: u32 = 1024;
+
+/// A chunk of a file, see the [module documentation](index.html) for the
+/// format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileChunk {
+    /// The ID shared by all chunks of the file.
+    pub transfer_id: [u8; 8],
+    /// The index of the chunk, starting at 0.
+    pub index: u32,
+    /// The total number of chunks.
+    pub total: u32,
+    /// The chunk data.
+    pub data: Vec<u8>,
+}
+
+impl FileChunk {
+    /// Split a file into chunks of at most `chunk_size` bytes (excluding the
+    /// header), with a random transfer ID.
+    ///
+    /// An empty file results in a single empty chunk. A `chunk_size` of 0
+    /// results in an
+    /// [`ApiError::InvalidChunkSize`](../errors/enum.ApiError.html#variant.InvalidChunkSize),
+    /// more than `u32::MAX` chunks in an
+    /// [`ApiError::TooManyChunks`](../errors/enum.ApiError.html#variant.TooManyChunks).
+    pub fn split(data: &[u8], chunk_size: usize) -> Result<Vec<FileChunk>, ApiError> {
+        if chunk_size == 0 {
+            return Err(ApiError::InvalidChunkSize);
+        }
+        let mut transfer_id = [0; 8];
+        randombytes_into(&mut transfer_id);
+        if data.is_empty() {
+            return Ok(vec![FileChunk { transfer_id: transfer_id, index: 0, total: 1, data: Vec::new() }]);
+        }
+        let total = (data.len() - 1) / chunk_size + 1;
+        if total as u64 > ::std::u32::MAX as u64 {
+            return Err(ApiError::TooManyChunks(total as u64, ::std::u32::MAX));
+        }
+        Ok(data.chunks(chunk_size).enumerate().map(|(index, chunk)| FileChunk {
+            transfer_id: transfer_id,
+            index: index as u32,
+            total: total as u32,
+            data: chunk.to_vec(),
+        }).collect())
+    }
+
+    /// Encode the chunk header and data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(CHUNK_HEADER_LEN + self.data.len());
+        bytes.extend_from_slice(CHUNK_MAGIC);
+        bytes.extend_from_slice(&self.transfer_id);
+        // Writing to a vector never fails
+        bytes.write_u32::<LittleEndian>(self.index).expect("Writing to buffer failed");
+        bytes.write_u32::<LittleEndian>(self.total).expect("Writing to buffer failed");
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Decode a chunk, e.g. the data of a downloaded file message.
+    ///
+    /// Data that is not a valid chunk results in an
+    /// [`ApiError::ParseError`](../errors/enum.ApiError.html#variant.ParseError).
+    pub fn from_bytes(bytes: &[u8]) -> Result<FileChunk, ApiError> {
+        if bytes.len() < CHUNK_HEADER_LEN || &bytes[0..4] != CHUNK_MAGIC {
+            return Err(ApiError::ParseError("Data is not a file chunk".into()));
+        }
+        let mut transfer_id = [0; 8];
+        transfer_id.copy_from_slice(&bytes[4..12]);
+        let mut cursor = Cursor::new(&bytes[12..CHUNK_HEADER_LEN]);
+        let index = cursor.read_u32::<LittleEndian>()
+            .map_err(|e| ApiError::ParseError(format!("Could not read chunk index: {}", e)))?;
+        let total = cursor.read_u32::<LittleEndian>()
+            .map_err(|e| ApiError::ParseError(format!("Could not read chunk count: {}", e)))?;
+        if index >= total {
+            return Err(ApiError::ParseError(format!("Invalid chunk index {} of {}", index, total)));
+        }
+        Ok(FileChunk {
+            transfer_id: transfer_id,
+            index: index,
+            total: total,
+            data: bytes[CHUNK_HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+/// The chunks of a file received so far.
+#[derive(Debug)]
+struct PartialFile {
+    total: u32,
+    chunks: BTreeMap<u32, Vec<u8>>,
+    /// When the last chunk was added.
+    updated: Instant,
+}
+
+/// Reassembles files from chunks received in any order.
+///
+/// Chunks of multiple files can be added interleaved, they are told apart by
+/// their transfer ID. Incomplete files are kept in memory until all their
+/// chunks have been added. If a chunk is lost, or a sender never sends the
+/// remaining chunks, the file is never completed. Long-running receivers
+/// should therefore regularly call
+/// [`discard_older_than`](#method.discard_older_than), or
+/// [`remove`](#method.remove) transfers they are no longer interested in.
+///
+/// The number of chunks is chosen by the sender, so the assembler limits the
+/// number of incomplete files and the number of chunks per file, see
+/// [`with_limits`](#method.with_limits).
+#[derive(Debug)]
+pub struct ChunkAssembler {
+    files: HashMap<[u8; 8], PartialFile>,
+    max_pending: usize,
+    max_chunks: u32,
+}
+
+impl Default for ChunkAssembler {
+    fn default() -> Self {
+        ChunkAssembler::with_limits(DEFAULT_MAX_PENDING_TRANSFERS, DEFAULT_MAX_CHUNKS)
+    }
+}
+
+impl ChunkAssembler {
+    /// Create an empty assembler with the default limits
+    /// ([`DEFAULT_MAX_PENDING_TRANSFERS`](constant.DEFAULT_MAX_PENDING_TRANSFERS.html)
+    /// and [`DEFAULT_MAX_CHUNKS`](constant.DEFAULT_MAX_CHUNKS.html)).
+    pub fn new() -> Self {
+        ChunkAssembler::default()
+    }
+
+    /// Create an empty assembler that keeps at most `max_pending` incomplete
+    /// files, and accepts files with at most `max_chunks` chunks.
+    pub fn with_limits(max_pending: usize, max_chunks: u32) -> Self {
+        ChunkAssembler {
+            files: HashMap::new(),
+            max_pending: max_pending,
+            max_chunks: max_chunks,
+        }
+    }
+
+    /// Add a chunk, and return the reassembled file if it was the last
+    /// missing chunk.
+    ///
+    /// Adding a chunk twice is harmless. A chunk whose total number of
+    /// chunks differs from the previous chunks of the same transfer, or
+    /// whose data differs from a previously added chunk with the same
+    /// index, results in an
+    /// [`ApiError::ParseError`](../errors/enum.ApiError.html#variant.ParseError).
+    ///
+    /// A chunk of a file with more chunks than allowed is rejected with an
+    /// [`ApiError::TooManyChunks`](../errors/enum.ApiError.html#variant.TooManyChunks).
+    /// A chunk of a new file with more than one chunk is rejected with an
+    /// [`ApiError::TooManyPendingTransfers`](../errors/enum.ApiError.html#variant.TooManyPendingTransfers)
+    /// if the maximum number of incomplete files is reached.
+    pub fn add(&mut self, chunk: FileChunk) -> Result<Option<Vec<u8>>, ApiError> {
+        if chunk.index >= chunk.total {
+            return Err(ApiError::ParseError(format!("Invalid chunk index {} of {}", chunk.index, chunk.total)));
+        }
+        if chunk.total > self.max_chunks {
+            return Err(ApiError::TooManyChunks(chunk.total as u64, self.max_chunks));
+        }
+        if !self.files.contains_key(&chunk.transfer_id) && self.files.len() >= self.max_pending
+                && chunk.total > 1 {
+            return Err(ApiError::TooManyPendingTransfers(self.max_pending));
+        }
+        let complete = {
+            let file = self.files.entry(chunk.transfer_id)
+                .or_insert_with(|| PartialFile { total: chunk.total, chunks: BTreeMap::new(), updated: Instant::now() });
+            if file.total != chunk.total {
+                return Err(ApiError::ParseError(
+                    format!("Chunk count changed from {} to {}", file.total, chunk.total)
+                ));
+            }
+            if let Some(data) = file.chunks.get(&chunk.index) {
+                if *data != chunk.data {
+                    return Err(ApiError::ParseError(format!("Chunk {} received with different data", chunk.index)));
+                }
+            }
+            file.chunks.insert(chunk.index, chunk.data);
+            file.updated = Instant::now();
+            file.chunks.len() == file.total as usize
+        };
+        if !complete {
+            return Ok(None);
+        }
+        let file = self.files.remove(&chunk.transfer_id).expect("Partial file not found");
+        let mut data = Vec::with_capacity(file.chunks.values().map(Vec::len).sum());
+        for chunk_data in file.chunks.values() {
+            data.extend_from_slice(chunk_data);
+        }
+        Ok(Some(data))
+    }
+
+    /// Return the number of files that are not complete yet.
+    pub fn pending(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Discard the chunks of an incomplete file. Returns whether the
+    /// transfer was pending.
+    pub fn remove(&mut self, transfer_id: &[u8; 8]) -> bool {
+        self.files.remove(transfer_id).is_some()
+    }
+
+    /// Discard all incomplete files that have not received a chunk for
+    /// `max_age`, and return how many were discarded.
+    pub fn discard_older_than(&mut self, max_age: Duration) -> usize {
+        let before = self.files.len();
+        self.files.retain(|_, file| file.updated.elapsed() < max_age);
+        before - self.files.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use ::errors::ApiError;
+    use super::{ChunkAssembler, FileChunk};
+
+    #[test]
+    fn test_split() {
+        let data: Vec<u8> = (0..10).collect();
+        let chunks = FileChunk::split(&data, 4).unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|c| c.transfer_id == chunks[0].transfer_id && c.total == 3));
+        assert_eq!(chunks[0].data, vec![0, 1, 2, 3]);
+        assert_eq!(chunks[2].index, 2);
+        assert_eq!(chunks[2].data, vec![8, 9]);
+
+        let chunks = FileChunk::split(&[], 4).unwrap();
+        assert_eq!(chunks.len(), 1);
+        assert!(chunks[0].data.is_empty());
+
+        assert!(FileChunk::split(&data, 4).unwrap()[0].transfer_id != FileChunk::split(&data, 4).unwrap()[0].transfer_id);
+
+        match FileChunk::split(&data, 0) {
+            Err(ApiError::InvalidChunkSize) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_to_from_bytes() {
+        let chunk = FileChunk { transfer_id: [1, 2, 3, 4, 5, 6, 7, 8], index: 1, total: 2, data: vec![0xff] };
+        let bytes = chunk.to_bytes();
+        assert_eq!(bytes, vec![b'T', b'G', b'W', b'C', 1, 2, 3, 4, 5, 6, 7, 8, 1, 0, 0, 0, 2, 0, 0, 0, 0xff]);
+        assert_eq!(FileChunk::from_bytes(&bytes).unwrap(), chunk);
+
+        for invalid in &[&bytes[..19], b"XXXX\x01\x02\x03\x04\x05\x06\x07\x08\x01\0\0\0\x02\0\0\0",
+                         b"TGWC\x01\x02\x03\x04\x05\x06\x07\x08\x02\0\0\0\x02\0\0\0"] {
+            match FileChunk::from_bytes(invalid) {
+                Err(ApiError::ParseError(_)) => (),
+                other => panic!("Unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let first: Vec<u8> = (0..100).collect();
+        let second = b"second file".to_vec();
+        let mut first_chunks = FileChunk::split(&first, 30).unwrap();
+        let mut second_chunks = FileChunk::split(&second, 4).unwrap();
+        assert_eq!(first_chunks.len(), 4);
+        assert_eq!(second_chunks.len(), 3);
+
+        let mut assembler = ChunkAssembler::new();
+        assert_eq!(assembler.add(first_chunks.remove(3)).unwrap(), None);
+        assert_eq!(assembler.add(second_chunks.remove(1)).unwrap(), None);
+        assert_eq!(assembler.add(first_chunks.remove(0)).unwrap(), None);
+        // Duplicates are ignored
+        assert_eq!(assembler.add(first_chunks[1].clone()).unwrap(), None);
+        assert_eq!(assembler.add(first_chunks[1].clone()).unwrap(), None);
+        assert_eq!(assembler.add(second_chunks.remove(1)).unwrap(), None);
+        assert_eq!(assembler.pending(), 2);
+        assert_eq!(assembler.add(first_chunks.remove(0)).unwrap(), Some(first));
+        assert_eq!(assembler.add(second_chunks.remove(0)).unwrap(), Some(second));
+        assert_eq!(assembler.pending(), 0);
+    }
+
+    #[test]
+    fn test_reassemble_inconsistent() {
+        let mut assembler = ChunkAssembler::new();
+        let chunk = FileChunk { transfer_id: [1; 8], index: 0, total: 2, data: vec![1] };
+        assert_eq!(assembler.add(chunk).unwrap(), None);
+        let chunk = FileChunk { transfer_id: [1; 8], index: 1, total: 3, data: vec![2] };
+        match assembler.add(chunk) {
+            Err(ApiError::ParseError(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        let chunk = FileChunk { transfer_id: [2; 8], index: 2, total: 2, data: vec![] };
+        assert!(assembler.add(chunk).is_err());
+        let chunk = FileChunk { transfer_id: [1; 8], index: 0, total: 2, data: vec![9] };
+        match assembler.add(chunk) {
+            Err(ApiError::ParseError(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(assembler.pending(), 1);
+        // The original data is kept
+        let chunk = FileChunk { transfer_id: [1; 8], index: 1, total: 2, data: vec![2] };
+        assert_eq!(assembler.add(chunk).unwrap(), Some(vec![1, 2]));
+    }
+
+    #[test]
+    fn test_reassemble_limits() {
+        let mut assembler = ChunkAssembler::with_limits(2, 3);
+        match assembler.add(FileChunk { transfer_id: [1; 8], index: 0, total: 4, data: vec![1] }) {
+            Err(ApiError::TooManyChunks(4, 3)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match assembler.add(FileChunk { transfer_id: [1; 8], index: 0, total: ::std::u32::MAX, data: vec![1] }) {
+            Err(ApiError::TooManyChunks(_, 3)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(assembler.pending(), 0);
+
+        assembler.add(FileChunk { transfer_id: [1; 8], index: 0, total: 3, data: vec![1] }).unwrap();
+        assembler.add(FileChunk { transfer_id: [2; 8], index: 0, total: 2, data: vec![1] }).unwrap();
+        match assembler.add(FileChunk { transfer_id: [3; 8], index: 0, total: 2, data: vec![1] }) {
+            Err(ApiError::TooManyPendingTransfers(2)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        // Pending transfers and single chunk files are still accepted
+        assert_eq!(assembler.add(FileChunk { transfer_id: [2; 8], index: 1, total: 2, data: vec![2] }).unwrap(),
+                   Some(vec![1, 2]));
+        assert_eq!(assembler.add(FileChunk { transfer_id: [4; 8], index: 0, total: 1, data: vec![4] }).unwrap(),
+                   Some(vec![4]));
+        assert_eq!(assembler.pending(), 1);
+    }
+
+    #[test]
+    fn test_discard_pending() {
+        let mut assembler = ChunkAssembler::new();
+        assembler.add(FileChunk { transfer_id: [1; 8], index: 0, total: 2, data: vec![1] }).unwrap();
+        assembler.add(FileChunk { transfer_id: [2; 8], index: 0, total: 2, data: vec![1] }).unwrap();
+        assert!(assembler.remove(&[1; 8]));
+        assert!(!assembler.remove(&[1; 8]));
+        assert_eq!(assembler.pending(), 1);
+
+        thread::sleep(Duration::from_millis(60));
+        assembler.add(FileChunk { transfer_id: [3; 8], index: 0, total: 2, data: vec![1] }).unwrap();
+        assert_eq!(assembler.discard_older_than(Duration::from_millis(50)), 1);
+        assert_eq!(assembler.pending(), 1);
+        assert!(assembler.remove(&[3; 8]));
+    }
+}
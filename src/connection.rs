@@ -2,18 +2,177 @@
 
 use std::borrow::Cow;
 use std::collections::HashMap;
-use std::io::Read;
+use std::cmp;
+use std::io::{self, Cursor, ErrorKind, Read};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
-use reqwest::{Client, StatusCode};
-use reqwest::header::{Accept, ContentType};
+use reqwest::{Body, Certificate, Client, Method, Proxy, RequestBuilder, Response, StatusCode};
+use reqwest::Error as ReqwestError;
+use reqwest::RedirectPolicy as ReqwestRedirectPolicy;
+use reqwest::header::{Accept, ContentLength, ContentType, Date, RetryAfter, UserAgent};
 use reqwest::mime::Mime;
-use data_encoding::HEXLOWER;
+use serde_urlencoded;
 
+use ::crypto::EncryptedMessage;
 use ::errors::ApiError;
-use ::types::BlobId;
+use ::hex::encode_hex;
+use ::middleware::MiddlewareChain;
+use ::types::{BlobId, BlobDownloadMode};
 
 
-/// Map HTTP response status code to an ApiError if it isn't "200".
+/// The maximum length of a simple (transport encrypted) text message, in
+/// bytes.
+///
+/// The limit applies to the UTF-8 encoded text, not to the number of
+/// characters. Non-ASCII characters take 2 to 4 bytes each, so a text with
+/// 2000 characters may well exceed the limit. Use
+/// [`text_byte_length`](fn.text_byte_length.html) to check a text against
+/// it, or [`split_text`](fn.split_text.html) to split a long text.
+pub const MAX_SIMPLE_MESSAGE_LEN: usize = 3500;
+
+/// The maximum size of the encrypted message data accepted by the gateway,
+/// in bytes.
+const MAX_E2E_BOX_LEN: usize = 4000;
+
+/// The maximum length of an E2E text message, in bytes.
+///
+/// The encrypted data additionally contains the message type byte, up to 255
+/// bytes of padding and the 16 byte MAC.
+pub(crate) const MAX_E2E_TEXT_LEN: usize = MAX_E2E_BOX_LEN - 1 - 255 - 16;
+
+/// Return the length of the text in bytes, as counted by the gateway.
+///
+/// This is the length of the UTF-8 encoded text, which is what
+/// [`MAX_SIMPLE_MESSAGE_LEN`](constant.MAX_SIMPLE_MESSAGE_LEN.html) limits.
+/// It is larger than the number of characters if the text contains
+/// non-ASCII characters: `"à"` is one character, but two bytes.
+pub fn text_byte_length(text: &str) -> usize {
+    text.len()
+}
+
+/// The default `User-Agent` header sent with every request.
+pub(crate) const DEFAULT_USER_AGENT: &'static str = concat!("threema-gateway-rs/", env!("CARGO_PKG_VERSION"));
+
+/// Whether HTTP redirects are followed, see
+/// [`ApiBuilder::with_redirect_policy`](struct.ApiBuilder.html#method.with_redirect_policy).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectPolicy {
+    /// Do not follow redirects. A redirect response results in an error.
+    None,
+    /// Follow at most the specified number of redirects.
+    Limited(usize),
+}
+
+impl Default for RedirectPolicy {
+    fn default() -> Self {
+        RedirectPolicy::None
+    }
+}
+
+/// Options for the HTTP client.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ClientConfig {
+    pub(crate) proxies: Vec<Proxy>,
+    /// Additional trusted root certificates (DER encoded)
+    pub(crate) root_certificates: Vec<Vec<u8>>,
+    pub(crate) disable_hostname_verification: bool,
+    pub(crate) redirect_policy: RedirectPolicy,
+    pub(crate) middleware: MiddlewareChain,
+    pub(crate) record_last_response: bool,
+}
+
+/// The raw body of a gateway response, see
+/// [`ApiBuilder::with_raw_response_recording`](struct.ApiBuilder.html#method.with_raw_response_recording).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RawResponse {
+    /// The HTTP status code
+    pub status: u16,
+    /// The response body, exactly as sent by the gateway
+    pub body: String,
+}
+
+/// The HTTP client and settings used to make requests to the gateway.
+///
+/// The client is created once, so that initialization errors can be handled
+/// when building the API object, and so that connections can be reused.
+#[derive(Debug, Clone)]
+pub(crate) struct Connection {
+    client: Client,
+    pub(crate) endpoint: Cow<'static, str>,
+    pub(crate) user_agent: Cow<'static, str>,
+    middleware: MiddlewareChain,
+    /// The last text response, if recording is enabled
+    pub(crate) last_response: Option<Arc<Mutex<Option<RawResponse>>>>,
+}
+
+impl Connection {
+    pub(crate) fn new(
+        endpoint: Cow<'static, str>,
+        user_agent: Cow<'static, str>,
+        config: ClientConfig,
+    ) -> Result<Self, ReqwestError> {
+        let mut builder = Client::builder();
+        for proxy in config.proxies {
+            builder.proxy(proxy);
+        }
+        for der in config.root_certificates {
+            builder.add_root_certificate(Certificate::from_der(&der)?);
+        }
+        if config.disable_hostname_verification {
+            builder.danger_disable_hostname_verification();
+        }
+        builder.redirect(match config.redirect_policy {
+            RedirectPolicy::None => ReqwestRedirectPolicy::none(),
+            // reqwest counts the original request as well
+            RedirectPolicy::Limited(max) => ReqwestRedirectPolicy::limited(max.saturating_add(1)),
+        });
+        Ok(Connection {
+            client: builder.build()?,
+            endpoint: endpoint,
+            user_agent: user_agent,
+            middleware: config.middleware,
+            last_response: if config.record_last_response { Some(Arc::new(Mutex::new(None))) } else { None },
+        })
+    }
+
+    /// Start building a request to the specified URL.
+    pub(crate) fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let mut req = self.client.request(method, url);
+        req.header(UserAgent::new(self.user_agent.to_string()));
+        req
+    }
+
+    /// Send a request, invoking the registered middlewares around it.
+    pub(crate) fn send(&self, req: &mut RequestBuilder) -> Result<Response, ReqwestError> {
+        if self.middleware.is_empty() {
+            return req.send();
+        }
+        let mut request = req.build()?;
+        self.middleware.before(&mut request);
+        let res = self.client.execute(request)?;
+        self.middleware.after(&res);
+        Ok(res)
+    }
+}
+
+/// Encode request parameters as `application/x-www-form-urlencoded` string.
+///
+/// The parameters are sorted by key, so that the encoding of a set of
+/// parameters is always the same. This is used both for form request bodies
+/// and for query strings.
+pub(crate) fn build_form_params<I, K, V>(params: I) -> String
+        where I: IntoIterator<Item=(K, V)>, K: AsRef<str>, V: AsRef<str> {
+    let mut params: Vec<(K, V)> = params.into_iter().collect();
+    params.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+    let pairs: Vec<(&str, &str)> = params.iter().map(|p| (p.0.as_ref(), p.1.as_ref())).collect();
+    serde_urlencoded::to_string(pairs).expect("Could not encode form parameters")
+}
+
+/// Map HTTP response status code to an ApiError if it isn't successful.
+///
+/// All 2xx status codes are treated as success. The gateway currently only
+/// returns 200, but e.g. a 204 (No Content) is not an error either.
 ///
 /// Optionally, you can pass in the meaning of a 400 response code.
 pub(crate) fn map_response_code(
@@ -21,8 +180,8 @@ pub(crate) fn map_response_code(
     bad_request_meaning: Option<ApiError>,
 ) -> Result<(), ApiError> {
     match *status {
-        // 200
-        StatusCode::Ok => Ok(()),
+        // 2xx
+        s if s.is_success() => Ok(()),
         // 400
         StatusCode::BadRequest => match bad_request_meaning {
             Some(error) => Err(error),
@@ -38,12 +197,78 @@ pub(crate) fn map_response_code(
         StatusCode::PayloadTooLarge => Err(ApiError::MessageTooLong),
         // 500
         StatusCode::InternalServerError => Err(ApiError::ServerError),
+        // 503
+        StatusCode::ServiceUnavailable => Err(ApiError::ServiceUnavailable(None)),
         e @ _ => Err(ApiError::Other(format!("Bad response status code: {}", e))),
     }
 }
 
+/// Map the status code of a response to an error, like
+/// [`map_response_code`](fn.map_response_code.html).
+///
+/// For `503 Service Unavailable` responses, the delay from the `Retry-After`
+/// header is included in the error.
+pub(crate) fn map_response(res: &Response, bad_request_meaning: Option<ApiError>) -> Result<(), ApiError> {
+    if res.status() == StatusCode::ServiceUnavailable {
+        let retry_after = match res.headers().get::<RetryAfter>() {
+            Some(&RetryAfter::Delay(delay)) => Some(delay),
+            Some(&RetryAfter::DateTime(date)) => {
+                SystemTime::from(date).duration_since(SystemTime::now()).ok()
+            },
+            None => None,
+        };
+        return Err(ApiError::ServiceUnavailable(retry_after));
+    }
+    map_response_code(&res.status(), bad_request_meaning)
+}
+
+/// Read the response body and decode it as UTF-8.
+///
+/// Use [`read_response_bytes`](fn.read_response_bytes.html) for binary
+/// response bodies.
+///
+/// If recording is enabled, the body is stored as the last raw response of
+/// the connection.
+pub(crate) fn read_response_body(conn: &Connection, res: &mut Response) -> Result<String, ApiError> {
+    let body = read_response_bytes(res).and_then(decode_response_body)?;
+    if let Some(ref last_response) = conn.last_response {
+        *last_response.lock().unwrap() = Some(RawResponse {
+            status: res.status().as_u16(),
+            body: body.clone(),
+        });
+    }
+    Ok(body)
+}
+
+/// Read the complete response body.
+///
+/// If the body is shorter than announced in the `Content-Length` header, an
+/// [`ApiError::TruncatedResponse`](../errors/enum.ApiError.html#variant.TruncatedResponse)
+/// is returned instead of the partial body.
+pub(crate) fn read_response_bytes(res: &mut Response) -> Result<Vec<u8>, ApiError> {
+    let mut bytes = Vec::new();
+    match res.read_to_end(&mut bytes) {
+        Ok(_) => (),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Err(ApiError::TruncatedResponse),
+        Err(e) => return Err(e.into()),
+    }
+    match res.headers().get::<ContentLength>() {
+        Some(&ContentLength(len)) if len != bytes.len() as u64 => Err(ApiError::TruncatedResponse),
+        _ => Ok(bytes),
+    }
+}
+
+/// Decode a response body as UTF-8.
+fn decode_response_body(bytes: Vec<u8>) -> Result<String, ApiError> {
+    String::from_utf8(bytes).map_err(|e| {
+        let bytes = e.into_bytes();
+        let end = if bytes.len() > 64 { 64 } else { bytes.len() };
+        ApiError::InvalidResponseEncoding(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    })
+}
+
 /// Different ways to specify a message recipient in basic mode.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Recipient<'a> {
     /// Recipient identity (8 characters)
     Id(Cow<'a, str>),
@@ -53,6 +278,15 @@ pub enum Recipient<'a> {
     Email(Cow<'a, str>),
 }
 
+/// Return whether the string is a valid Threema ID: 8 characters, each an
+/// uppercase ASCII letter or a digit, except for the first character, which
+/// may also be `*` (for gateway IDs).
+pub(crate) fn is_valid_threema_id(id: &str) -> bool {
+    id.len() == 8 && id.chars().enumerate().all(|(i, c)| {
+        (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9') || (i == 0 && c == '*')
+    })
+}
+
 impl<'a> Recipient<'a> {
     pub fn new_id<T: Into<Cow<'a, str>>>(id: T) -> Self {
         Recipient::Id(id.into())
@@ -65,21 +299,136 @@ impl<'a> Recipient<'a> {
     pub fn new_email<T: Into<Cow<'a, str>>>(email: T) -> Self {
         Recipient::Email(email.into())
     }
+
+    /// Detect the recipient type of a string, for example from user input.
+    ///
+    /// The following rules are applied in order:
+    ///
+    /// - 8 uppercase alphanumeric characters (the first one may be `*`) are
+    ///   a Threema ID
+    /// - Strings containing an `@` are an e-mail address
+    /// - Digits with an optional leading `+` are a phone number (the `+` is
+    ///   removed)
+    ///
+    /// This means that a phone number consisting of exactly 8 digits is
+    /// detected as an ID. Use [`new_phone`](#method.new_phone) if that is not
+    /// what you want. All other input results in an
+    /// [`ApiError::BadRecipient`](errors/enum.ApiError.html#variant.BadRecipient).
+    pub fn from_str(val: &'a str) -> Result<Self, ApiError> {
+        let val = val.trim();
+        if is_valid_threema_id(val) {
+            return Ok(Recipient::new_id(val));
+        }
+        if val.contains('@') {
+            return Ok(Recipient::new_email(val));
+        }
+        let phone = if val.starts_with('+') { &val[1..] } else { val };
+        if !phone.is_empty() && phone.chars().all(|c| c.is_digit(10)) {
+            return Ok(Recipient::new_phone(phone));
+        }
+        Err(ApiError::BadRecipient(val.to_string()))
+    }
+}
+
+/// Normalize line breaks to `\n`.
+///
+/// Threema apps display `\n` as a line break. Windows line breaks (`\r\n`)
+/// and lone carriage returns are sent unchanged by
+/// [`SimpleApi::send`](struct.SimpleApi.html#method.send), but the carriage
+/// return may be displayed as an extra blank line or a placeholder
+/// character, depending on the platform. This function replaces both with
+/// `\n`. The text is only copied if it contains a carriage return.
+pub fn normalize_newlines<'a>(text: &'a str) -> Cow<'a, str> {
+    if !text.contains('\r') {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+/// Split a text into parts of at most `max_bytes` bytes.
+///
+/// The text is split at whitespace if possible, otherwise at a character
+/// boundary, so every part is valid UTF-8. No characters are removed,
+/// concatenating the parts results in the original text. If `max_bytes` is
+/// smaller than a single character, that character is returned as a part of
+/// its own.
+pub fn split_text(text: &str, max_bytes: usize) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut remaining = text;
+    while remaining.len() > max_bytes {
+        // Find the last character boundary within the limit
+        let mut boundary = max_bytes;
+        while !remaining.is_char_boundary(boundary) {
+            boundary -= 1;
+        }
+        if boundary == 0 {
+            boundary = remaining.chars().next().map(char::len_utf8).unwrap_or(0);
+        }
+
+        // Prefer splitting after the last whitespace
+        let split_at = match remaining[..boundary].rfind(char::is_whitespace) {
+            Some(pos) if pos > 0 => {
+                let ws_len = remaining[pos..].chars().next().map(char::len_utf8).unwrap_or(0);
+                pos + ws_len
+            },
+            _ => boundary,
+        };
+
+        let (part, rest) = remaining.split_at(split_at);
+        parts.push(part);
+        remaining = rest;
+    }
+    if !remaining.is_empty() {
+        parts.push(remaining);
+    }
+    parts
+}
+
+/// Check that the text does not contain control characters other than
+/// newline, carriage return and tab.
+pub(crate) fn check_control_chars(text: &str) -> Result<(), ApiError> {
+    match text.char_indices().find(|&(_, c)| c.is_control() && c != '\n' && c != '\r' && c != '\t') {
+        Some((index, c)) => Err(ApiError::ControlCharacter(c, index)),
+        None => Ok(()),
+    }
+}
+
+/// Check that an E2E text message is neither empty nor longer than
+/// `MAX_E2E_TEXT_LEN` bytes.
+pub(crate) fn check_e2e_text(text: &str) -> Result<(), ApiError> {
+    if text.is_empty() {
+        return Err(ApiError::EmptyMessage);
+    }
+    if text_byte_length(text) > MAX_E2E_TEXT_LEN {
+        return Err(ApiError::MessageTooLong);
+    }
+    Ok(())
+}
+
+/// Check that a simple text message is neither empty nor longer than
+/// `MAX_SIMPLE_MESSAGE_LEN` bytes.
+pub(crate) fn check_simple_text(text: &str) -> Result<(), ApiError> {
+    // Reject empty messages
+    if text.is_empty() {
+        return Err(ApiError::EmptyMessage);
+    }
+
+    // Check text length (in bytes, not characters)
+    if text_byte_length(text) > MAX_SIMPLE_MESSAGE_LEN {
+        return Err(ApiError::MessageTooLong);
+    }
+    Ok(())
 }
 
 /// Send a message to the specified recipient in basic mode.
 pub(crate) fn send_simple(
-    endpoint: &str,
+    conn: &Connection,
     from: &str,
     to: &Recipient,
     secret: &str,
     text: &str,
 ) -> Result<String, ApiError> {
-    // Check text length (max 3500 bytes)
-    // Note: Strings in Rust are UTF8, so len() returns the byte count.
-    if text.len() > 3500 {
-        return Err(ApiError::MessageTooLong);
-    }
+    check_simple_text(text)?;
 
     // Prepare POST data
     let mut params = HashMap::new();
@@ -93,22 +442,59 @@ pub(crate) fn send_simple(
     };
 
     // Send request
-    let mut res = Client::new().post(&format!("{}/send_simple", endpoint))
-        .form(&params)
-        .header(Accept::json())
-        .send()?;
-    try!(map_response_code(&res.status(), Some(ApiError::BadSenderOrRecipient)));
+    let mut req = conn.request(Method::Post, &format!("{}/send_simple", conn.endpoint));
+    req.body(build_form_params(&params))
+        .header(ContentType::form_url_encoded())
+        .header(Accept::json());
+    let mut res = conn.send(&mut req)?;
+    try!(map_response(&res, Some(ApiError::BadSenderOrRecipient)));
 
     // Read and return response body
-    let mut body = String::new();
-    try!(res.read_to_string(&mut body));
+    let body = read_response_body(conn, &mut res)?;
 
-    Ok(body)
+    check_message_id(body)
+}
+
+/// Check that the response to a send request contains a message ID.
+///
+/// The gateway always returns the ID of a sent message, so an empty body
+/// (e.g. of a `204 No Content` response) results in an
+/// [`ApiError::BadMessageId`](../errors/enum.ApiError.html#variant.BadMessageId).
+fn check_message_id(body: String) -> Result<String, ApiError> {
+    if body.trim().is_empty() {
+        Err(ApiError::BadMessageId)
+    } else {
+        Ok(body)
+    }
+}
+
+/// Request parameters that are set by [`send_e2e`](fn.send_e2e.html) and may
+/// not be passed in as additional parameters.
+const RESERVED_E2E_PARAMS: [&'static str; 5] = ["from", "to", "secret", "nonce", "box"];
+
+/// The result of sending an E2E message, see
+/// [`E2eApi::send_dated`](struct.E2eApi.html#method.send_dated).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    /// The message ID assigned by the gateway, as returned in the response
+    /// body
+    pub message_id: String,
+    /// The date of the gateway response, taken from its `Date` header
+    ///
+    /// This is the time when the gateway accepted the message, according to
+    /// the gateway clock. It can be used to correlate delivery receipts. It
+    /// only has a resolution of one second, and is `None` if the header is
+    /// missing or invalid.
+    pub date: Option<SystemTime>,
 }
 
 /// Send an encrypted E2E message to the specified recipient.
+///
+/// Additional parameters using one of the reserved keys (`from`, `to`,
+/// `secret`, `nonce` and `box`) are rejected with
+/// [`ApiError::ReservedParameter`](../errors/enum.ApiError.html#variant.ReservedParameter).
 pub(crate) fn send_e2e(
-    endpoint: &str,
+    conn: &Connection,
     from: &str,
     to: &str,
     secret: &str,
@@ -116,82 +502,624 @@ pub(crate) fn send_e2e(
     ciphertext: &[u8],
     additional_params: Option<HashMap<String, String>>,
 ) -> Result<String, ApiError> {
+    send_e2e_dated(conn, from, to, secret, nonce, ciphertext, additional_params)
+        .map(|sent| sent.message_id)
+}
+
+/// Send an encrypted E2E message to the specified recipient, and return the
+/// message ID together with the date of the gateway response.
+pub(crate) fn send_e2e_dated(
+    conn: &Connection,
+    from: &str,
+    to: &str,
+    secret: &str,
+    nonce: &[u8],
+    ciphertext: &[u8],
+    additional_params: Option<HashMap<String, String>>,
+) -> Result<SentMessage, ApiError> {
     // Prepare POST data
     let mut params = match additional_params {
         Some(p) => p,
         None => HashMap::new(),
     };
+    if let Some(key) = RESERVED_E2E_PARAMS.iter().find(|key| params.contains_key(**key)) {
+        return Err(ApiError::ReservedParameter(key.to_string()));
+    }
     params.insert("from".into(), from.into());
     params.insert("to".into(), to.into());
     params.insert("secret".into(), secret.into());
-    params.insert("nonce".into(), HEXLOWER.encode(nonce));
-    params.insert("box".into(), HEXLOWER.encode(ciphertext));
+    params.insert("nonce".into(), encode_hex(nonce));
+    params.insert("box".into(), encode_hex(ciphertext));
 
     // Send request
-    let mut res = Client::new().post(&format!("{}/send_e2e", endpoint))
-        .form(&params)
-        .header(Accept::json())
-        .send()?;
-    try!(map_response_code(&res.status(), Some(ApiError::BadSenderOrRecipient)));
+    let mut req = conn.request(Method::Post, &format!("{}/send_e2e", conn.endpoint));
+    req.body(build_form_params(&params))
+        .header(ContentType::form_url_encoded())
+        .header(Accept::json());
+    let mut res = conn.send(&mut req)?;
+    try!(map_response(&res, Some(ApiError::BadSenderOrRecipient)));
+    let date = res.headers().get::<Date>().map(|date| SystemTime::from(date.0));
 
     // Read and return response body
-    let mut body = String::new();
-    try!(res.read_to_string(&mut body));
+    let body = read_response_body(conn, &mut res)?;
 
-    Ok(body)
+    Ok(SentMessage { message_id: check_message_id(body)?, date: date })
 }
 
-/// Upload a blob to the blob server.
-pub(crate) fn blob_upload(
-    endpoint: &str,
+/// Send an [`EncryptedMessage`](../struct.EncryptedMessage.html) to the
+/// specified recipient.
+pub(crate) fn send_e2e_encrypted(
+    conn: &Connection,
     from: &str,
+    to: &str,
     secret: &str,
-    data: &[u8],
-) -> Result<BlobId, ApiError> {
-    // Build URL
-    let url = format!("{}/upload_blob?from={}&secret={}", endpoint, from, secret);
+    message: &EncryptedMessage,
+    additional_params: Option<HashMap<String, String>>,
+) -> Result<String, ApiError> {
+    send_e2e(conn, from, to, secret, &message.nonce, &message.ciphertext, additional_params)
+}
 
-    // Build multipart/form-data request body
-    let boundary = "3ma-d84f64f5-a138-4b0a-9e25-339257990c81-3ma".to_string();
+/// The boundary of the multipart/form-data body of blob uploads.
+const BLOB_UPLOAD_BOUNDARY: &'static str = "3ma-d84f64f5-a138-4b0a-9e25-339257990c81-3ma";
+
+/// Build the multipart/form-data body of a blob upload, and return it
+/// together with the offset of the blob data in the body.
+fn blob_upload_body(data: &[u8]) -> (Vec<u8>, usize) {
+    let boundary = BLOB_UPLOAD_BOUNDARY;
     let mut req_body = Vec::new();
     req_body.extend_from_slice("--".as_bytes());
     req_body.extend_from_slice(&boundary.as_bytes());
     req_body.extend_from_slice("\r\n".as_bytes());
     req_body.extend_from_slice("Content-Disposition: form-data; name=\"blob\"\r\n".as_bytes());
     req_body.extend_from_slice("Content-Type: application/octet-stream\r\n\r\n".as_bytes());
+    let offset = req_body.len();
     req_body.extend_from_slice(data);
     req_body.extend_from_slice("\r\n--".as_bytes());
     req_body.extend_from_slice(&boundary.as_bytes());
     req_body.extend_from_slice("--\r\n".as_bytes());
+    (req_body, offset)
+}
+
+/// A request body that reports how much of the blob data it contains has
+/// been read.
+struct ProgressReader<F> {
+    inner: Cursor<Vec<u8>>,
+    /// The offset of the blob data in the body
+    offset: u64,
+    /// The length of the blob data
+    total: u64,
+    /// The number of blob bytes reported so far
+    reported: u64,
+    on_progress: F,
+}
+
+impl<F: FnMut(u64, u64)> Read for ProgressReader<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        let sent = cmp::min(self.inner.position().saturating_sub(self.offset), self.total);
+        if sent > self.reported {
+            self.reported = sent;
+            (self.on_progress)(sent, self.total);
+        }
+        Ok(read)
+    }
+}
+
+/// Upload a blob to the blob server.
+pub(crate) fn blob_upload(
+    conn: &Connection,
+    from: &str,
+    secret: &str,
+    data: &[u8],
+) -> Result<BlobId, ApiError> {
+    let (req_body, _) = blob_upload_body(data);
+    send_blob_upload(conn, from, secret, req_body.into())
+}
+
+/// Upload a blob to the blob server, and report the number of uploaded
+/// bytes of the blob and its total size to `on_progress` while the request
+/// body is sent.
+///
+/// The callback is called from the thread of the HTTP client.
+pub(crate) fn blob_upload_with_progress<F>(
+    conn: &Connection,
+    from: &str,
+    secret: &str,
+    data: &[u8],
+    on_progress: F,
+) -> Result<BlobId, ApiError> where F: FnMut(u64, u64) + Send + 'static {
+    let (req_body, offset) = blob_upload_body(data);
+    let len = req_body.len() as u64;
+    let reader = ProgressReader {
+        inner: Cursor::new(req_body),
+        offset: offset as u64,
+        total: data.len() as u64,
+        reported: 0,
+        on_progress: on_progress,
+    };
+    send_blob_upload(conn, from, secret, Body::sized(reader, len))
+}
+
+/// Send a blob upload request with the specified multipart/form-data body.
+fn send_blob_upload(conn: &Connection, from: &str, secret: &str, req_body: Body) -> Result<BlobId, ApiError> {
+    // Build URL
+    let url = format!("{}/upload_blob?{}", conn.endpoint, build_form_params(vec![("from", from), ("secret", secret)]));
 
     // Send request
-    let mimetype: Mime = format!("multipart/form-data; boundary={}", boundary)
+    let mimetype: Mime = format!("multipart/form-data; boundary={}", BLOB_UPLOAD_BOUNDARY)
         .parse().expect("Could not parse multipart/form-data mime type");
-    let mut res = Client::new().post(&url)
-        .body(req_body)
+    let mut req = conn.request(Method::Post, &url);
+    req.body(req_body)
         .header(Accept::text())
-        .header(ContentType(mimetype))
-        .send()?;
-    try!(map_response_code(&res.status(), Some(ApiError::BadBlob)));
+        .header(ContentType(mimetype));
+    let mut res = conn.send(&mut req)?;
+    try!(map_response(&res, Some(ApiError::BadBlob)));
 
     // Read response body containing blob ID
-    let mut body = String::new();
-    res.read_to_string(&mut body)?;
+    let body = read_response_body(conn, &mut res)?;
 
     BlobId::from_str(body.trim())
 }
 
+/// Download a blob from the blob server.
+pub(crate) fn blob_download(
+    conn: &Connection,
+    from: &str,
+    secret: &str,
+    blob_id: &BlobId,
+    mode: BlobDownloadMode,
+) -> Result<Vec<u8>, ApiError> {
+    // Build URL
+    let mut params = vec![("from", from), ("secret", secret)];
+    if mode == BlobDownloadMode::Consume {
+        params.push(("done", "1"));
+    }
+    let url = format!("{}/blobs/{}?{}", conn.endpoint, blob_id, build_form_params(params));
+
+    debug!("Downloading blob {}", blob_id);
+
+    // Send request
+    let mut res = conn.send(&mut conn.request(Method::Get, &url))?;
+    if res.status() == StatusCode::NotFound {
+        return Err(ApiError::BadBlob);
+    }
+    try!(map_response(&res, None));
+
+    // Read binary response body
+    read_response_bytes(&mut res)
+}
+
+/// Information about a blob on the blob server, see
+/// [`E2eApi::blob_info`](struct.E2eApi.html#method.blob_info).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobInfo {
+    /// The size of the encrypted blob in bytes, if reported by the server
+    pub size: Option<u64>,
+}
+
+/// Request information about a blob with a `HEAD` request, without
+/// downloading it.
+///
+/// Returns `None` if the blob does not exist (any more).
+pub(crate) fn blob_info(
+    conn: &Connection,
+    from: &str,
+    secret: &str,
+    blob_id: &BlobId,
+) -> Result<Option<BlobInfo>, ApiError> {
+    // Build URL (never marks the blob as done)
+    let url = format!("{}/blobs/{}?{}", conn.endpoint, blob_id, build_form_params(vec![("from", from), ("secret", secret)]));
+
+    debug!("Requesting info for blob {}", blob_id);
+
+    // Send request
+    let res = conn.send(&mut conn.request(Method::Head, &url))?;
+    if res.status() == StatusCode::NotFound {
+        return Ok(None);
+    }
+    try!(map_response(&res, None));
+
+    Ok(Some(BlobInfo {
+        size: res.headers().get::<ContentLength>().map(|len| len.0),
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use std::iter::repeat;
     use ::MSGAPI_URL;
     use ::errors::ApiError;
+    use ::test_server::TestServer;
     use super::*;
 
+    fn conn() -> Connection {
+        Connection::new(Cow::Borrowed(MSGAPI_URL), Cow::Borrowed(DEFAULT_USER_AGENT), ClientConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn test_send_simple_request() {
+        let server = TestServer::start(vec![(200, "0123456789abcdef")]);
+        let result = send_simple(&server.conn(), "*3MAGWID", &Recipient::new_email("a@example.com"), "secret", "Hi there");
+        assert_eq!(result.unwrap(), "0123456789abcdef");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].path, "/send_simple");
+        assert_eq!(requests[0].header("user-agent"), Some(DEFAULT_USER_AGENT));
+        let body = requests[0].body_str();
+        for param in &["from=*3MAGWID", "email=a%40example.com", "secret=secret", "text=Hi+there"] {
+            assert!(body.split('&').any(|p| p == *param), "Missing {} in {}", param, body);
+        }
+    }
+
+    #[test]
+    fn test_blob_info() {
+        let found = b"HTTP/1.1 200 OK\r\nContent-Length: 1234\r\nConnection: close\r\n\r\n".to_vec();
+        let not_found = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec();
+        let server = TestServer::start_raw(vec![found, not_found]);
+        let blob_id = BlobId::new([1; 16]);
+        let info = blob_info(&server.conn(), "*3MAGWID", "secret", &blob_id).unwrap();
+        assert_eq!(info, Some(BlobInfo { size: Some(1234) }));
+        assert_eq!(blob_info(&server.conn(), "*3MAGWID", "secret", &blob_id).unwrap(), None);
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "HEAD");
+        assert_eq!(requests[0].path, "/blobs/01010101010101010101010101010101?from=*3MAGWID&secret=secret");
+    }
+
+    #[test]
+    fn test_send_e2e_connection_closed() {
+        // The server closes the connection without responding
+        let server = TestServer::start_raw(vec![vec![]]);
+        match send_e2e(&server.conn(), "*3MAGWID", "ECHOECHO", "secret", &[0; 24], &[0; 32], None) {
+            Err(ref e @ ApiError::Network(_)) => assert!(e.is_retryable()),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_send_e2e_dated() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let response = b"HTTP/1.1 200 OK\r\nDate: Wed, 25 Apr 2018 12:21:04 GMT\r\n\
+                         Content-Length: 16\r\nConnection: close\r\n\r\n0123456789abcdef".to_vec();
+        let server = TestServer::start_raw(vec![response]);
+        let sent = send_e2e_dated(&server.conn(), "*3MAGWID", "ECHOECHO", "secret", &[0; 24], &[0; 32], None).unwrap();
+        assert_eq!(sent.message_id, "0123456789abcdef");
+        assert_eq!(sent.date, Some(UNIX_EPOCH + Duration::from_secs(1524658864)));
+
+        let server = TestServer::start(vec![(200, "0123456789abcdef")]);
+        let sent = send_e2e_dated(&server.conn(), "*3MAGWID", "ECHOECHO", "secret", &[0; 24], &[0; 32], None).unwrap();
+        assert_eq!(sent.message_id, "0123456789abcdef");
+        assert_eq!(sent.date, None);
+    }
+
+    #[test]
+    fn test_send_simple_special_characters() {
+        let texts = vec![
+            "first line\nsecond line\r\nthird line\n",
+            "a+b=c & d%20e?f#g;h/i",
+            "quotes \" ' and <html> \\ backslash",
+            "emoji 😀, umlauts äöü, tab\t, nbsp\u{a0}",
+        ];
+        for text in texts {
+            let server = TestServer::start(vec![(200, "0123456789abcdef")]);
+            send_simple(&server.conn(), "*3MAGWID", &Recipient::new_id("ECHOECHO"), "secret", text).unwrap();
+            let body = server.requests()[0].body.clone();
+            let params: HashMap<String, String> = serde_urlencoded::from_bytes(&body).unwrap();
+            assert_eq!(params["text"], text);
+        }
+    }
+
+    #[test]
+    fn test_normalize_newlines() {
+        assert_eq!(normalize_newlines("a\r\nb\rc\nd\r\n\r"), "a\nb\nc\nd\n\n");
+        assert_eq!(normalize_newlines("\r\r\n"), "\n\n");
+        match normalize_newlines("no carriage\nreturn") {
+            Cow::Borrowed(text) => assert_eq!(text, "no carriage\nreturn"),
+            Cow::Owned(_) => panic!("Text was copied"),
+        }
+    }
+
+    #[test]
+    fn test_send_e2e_request() {
+        let server = TestServer::start(vec![(200, "0123456789abcdef")]);
+        let result = send_e2e(&server.conn(), "*3MAGWID", "ECHOECHO", "secret", &[0xff; 24], &[0x01, 0xab], None);
+        assert_eq!(result.unwrap(), "0123456789abcdef");
+
+        let body = server.requests()[0].body_str();
+        for param in &["to=ECHOECHO", "nonce=ffffffffffffffffffffffffffffffffffffffffffffffff", "box=01ab"] {
+            assert!(body.split('&').any(|p| p == *param), "Missing {} in {}", param, body);
+        }
+    }
+
+    #[test]
+    fn test_send_e2e_status_codes() {
+        let server = TestServer::start(vec![(400, ""), (401, ""), (402, ""), (500, "")]);
+        let conn = server.conn();
+        let send = || send_e2e(&conn, "*3MAGWID", "ECHOECHO", "secret", &[0; 24], &[0; 32], None);
+        match send() { Err(ApiError::BadSenderOrRecipient) => (), other => panic!("Unexpected result: {:?}", other) }
+        match send() { Err(ApiError::BadCredentials) => (), other => panic!("Unexpected result: {:?}", other) }
+        match send() { Err(ApiError::NoCredits) => (), other => panic!("Unexpected result: {:?}", other) }
+        match send() { Err(ApiError::ServerError) => (), other => panic!("Unexpected result: {:?}", other) }
+    }
+
+    #[test]
+    fn test_blob_upload_request() {
+        let server = TestServer::start(vec![(200, "0123456789abcdef0123456789abcdef\n")]);
+        let blob_id = blob_upload(&server.conn(), "*3MAGWID", "secret", b"data").unwrap();
+        assert_eq!(blob_id, BlobId::from_str("0123456789abcdef0123456789abcdef").unwrap());
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/upload_blob?from=*3MAGWID&secret=secret");
+        assert!(requests[0].header("content-type").unwrap().starts_with("multipart/form-data; boundary="));
+        assert!(requests[0].body_str().contains("\r\n\r\ndata\r\n"));
+    }
+
+    #[test]
+    fn test_blob_upload_with_progress() {
+        let server = TestServer::start(vec![(200, "0123456789abcdef0123456789abcdef\n")]);
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let reported = progress.clone();
+        let data = vec![7; 100000];
+        let blob_id = blob_upload_with_progress(&server.conn(), "*3MAGWID", "secret", &data, move |sent, total| {
+            reported.lock().unwrap().push((sent, total));
+        }).unwrap();
+        assert_eq!(blob_id, BlobId::from_str("0123456789abcdef0123456789abcdef").unwrap());
+
+        let progress = progress.lock().unwrap();
+        assert!(!progress.is_empty());
+        assert!(progress.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(progress.iter().all(|&(_, total)| total == 100000));
+        assert_eq!(progress.last(), Some(&(100000, 100000)));
+
+        let requests = server.requests();
+        assert_eq!(requests[0].header("content-length"), Some(blob_upload_body(&data).0.len().to_string().as_str()));
+        assert_eq!(requests[0].body, blob_upload_body(&data).0);
+    }
+
+    #[test]
+    fn test_blob_download_request() {
+        let server = TestServer::start(vec![(200, "data"), (200, "data"), (404, "")]);
+        let blob_id = BlobId::new([0xab; 16]);
+        let conn = server.conn();
+        assert_eq!(blob_download(&conn, "*3MAGWID", "secret", &blob_id, BlobDownloadMode::Peek).unwrap(), b"data");
+        assert_eq!(blob_download(&conn, "*3MAGWID", "secret", &blob_id, BlobDownloadMode::Consume).unwrap(), b"data");
+        match blob_download(&conn, "*3MAGWID", "secret", &blob_id, BlobDownloadMode::Peek) {
+            Err(ApiError::BadBlob) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(requests[0].path, "/blobs/abababababababababababababababab?from=*3MAGWID&secret=secret");
+        assert_eq!(requests[1].path, "/blobs/abababababababababababababababab?done=1&from=*3MAGWID&secret=secret");
+    }
+
+    #[test]
+    fn test_build_form_params() {
+        assert_eq!(build_form_params(Vec::<(&str, &str)>::new()), "");
+        assert_eq!(
+            build_form_params(vec![("to", "ECHOECHO"), ("from", "*3MAGWID"), ("secret", "a&b=c")]),
+            "from=*3MAGWID&secret=a%26b%3Dc&to=ECHOECHO"
+        );
+        assert_eq!(build_form_params(vec![("text", "Grüezi mitenand! 1+1")]), "text=Gr%C3%BCezi+mitenand%21+1%2B1");
+
+        let mut params = HashMap::new();
+        params.insert("nonce".to_string(), "00ff".to_string());
+        params.insert("box".to_string(), "abcd".to_string());
+        params.insert("noPush".to_string(), "1".to_string());
+        assert_eq!(build_form_params(&params), "box=abcd&noPush=1&nonce=00ff");
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        let server = TestServer::start_raw(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 32\r\nConnection: close\r\n\r\n0123456789abcdef".to_vec(),
+        ]);
+        match blob_upload(&server.conn(), "*3MAGWID", "secret", b"data") {
+            Err(ApiError::TruncatedResponse) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let server = TestServer::start_raw(vec![
+            b"HTTP/1.1 200 OK\r\nContent-Length: 1024\r\nConnection: close\r\n\r\nencrypted".to_vec(),
+        ]);
+        match blob_download(&server.conn(), "*3MAGWID", "secret", &BlobId::new([0; 16]), BlobDownloadMode::Peek) {
+            Err(ApiError::TruncatedResponse) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_redirect_policy() {
+        let redirect = b"HTTP/1.1 302 Found\r\nLocation: /credits2\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec();
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n42".to_vec();
+
+        // Redirects are not followed by default
+        let server = TestServer::start_raw(vec![redirect.clone(), ok.clone()]);
+        let url = format!("{}/credits", server.conn().endpoint);
+        let res = server.conn().request(Method::Get, &url).send().unwrap();
+        assert_eq!(res.status(), StatusCode::Found);
+        assert_eq!(server.requests().len(), 1);
+
+        let server = TestServer::start_raw(vec![redirect, ok]);
+        let config = ClientConfig { redirect_policy: RedirectPolicy::Limited(1), ..Default::default() };
+        let conn = Connection::new(server.conn().endpoint, Cow::Borrowed(DEFAULT_USER_AGENT), config).unwrap();
+        let url = format!("{}/credits", conn.endpoint);
+        let mut res = conn.send(&mut conn.request(Method::Get, &url)).unwrap();
+        assert_eq!(res.status(), StatusCode::Ok);
+        assert_eq!(read_response_body(&conn, &mut res).unwrap(), "42");
+        assert_eq!(server.requests()[1].path, "/credits2");
+    }
+
+    #[test]
+    fn test_record_last_response() {
+        let server = TestServer::start(vec![(200, "0123456789abcdef"), (200, "fedcba9876543210")]);
+        let config = ClientConfig { record_last_response: true, ..Default::default() };
+        let conn = Connection::new(server.conn().endpoint, Cow::Borrowed(DEFAULT_USER_AGENT), config).unwrap();
+        assert_eq!(*conn.last_response.as_ref().unwrap().lock().unwrap(), None);
+        send_e2e(&conn, "*3MAGWID", "ECHOECHO", "secret", &[0; 24], &[0; 32], None).unwrap();
+        send_e2e(&conn, "*3MAGWID", "ECHOECHO", "secret", &[0; 24], &[0; 32], None).unwrap();
+        assert_eq!(*conn.last_response.as_ref().unwrap().lock().unwrap(), Some(RawResponse {
+            status: 200,
+            body: "fedcba9876543210".into(),
+        }));
+
+        // Disabled by default
+        assert!(server.conn().last_response.is_none());
+    }
+
+    #[test]
+    fn test_middleware() {
+        use reqwest::header::Raw;
+        use ::middleware::{RequestMiddleware, Request, Response};
+
+        struct Recorder(&'static str, Arc<Mutex<Vec<String>>>);
+
+        impl RequestMiddleware for Recorder {
+            fn before(&self, request: &mut Request) {
+                request.headers_mut().set_raw("X-Trace", Raw::from(self.0));
+                self.1.lock().unwrap().push(format!("before {}", self.0));
+            }
+
+            fn after(&self, response: &Response) {
+                self.1.lock().unwrap().push(format!("after {} {}", self.0, response.status().as_u16()));
+            }
+        }
+
+        let server = TestServer::start(vec![(200, "42")]);
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut config = ClientConfig::default();
+        config.middleware.push(Arc::new(Recorder("a", calls.clone())));
+        config.middleware.push(Arc::new(Recorder("b", calls.clone())));
+        let conn = Connection::new(server.conn().endpoint, Cow::Borrowed(DEFAULT_USER_AGENT), config).unwrap();
+        let url = format!("{}/credits", conn.endpoint);
+        let mut res = conn.send(&mut conn.request(Method::Get, &url)).unwrap();
+        assert_eq!(read_response_body(&conn, &mut res).unwrap(), "42");
+        assert_eq!(*calls.lock().unwrap(), vec!["before a", "before b", "after b 200", "after a 200"]);
+        let requests = server.requests();
+        assert_eq!(requests[0].header("x-trace"), Some("b"));
+        assert_eq!(requests[0].header("user-agent"), Some(DEFAULT_USER_AGENT));
+    }
+
+    #[test]
+    fn test_map_response_code() {
+        assert!(map_response_code(&StatusCode::Ok, None).is_ok());
+        assert!(map_response_code(&StatusCode::Created, None).is_ok());
+        assert!(map_response_code(&StatusCode::NoContent, None).is_ok());
+        match map_response_code(&StatusCode::MovedPermanently, None) {
+            Err(ApiError::Other(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match map_response_code(&StatusCode::BadRequest, Some(ApiError::BadBlob)) {
+            Err(ApiError::BadBlob) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_service_unavailable() {
+        let unavailable = |retry_after: &str| format!(
+            "HTTP/1.1 503 Service Unavailable\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n", retry_after
+        ).into_bytes();
+        let server = TestServer::start_raw(vec![unavailable("Retry-After: 120\r\n"), unavailable("")]);
+        match lookup_credits_raw(&server.conn()) {
+            Err(ApiError::ServiceUnavailable(Some(delay))) => assert_eq!(delay, ::std::time::Duration::from_secs(120)),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match lookup_credits_raw(&server.conn()) {
+            Err(ApiError::ServiceUnavailable(None)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match map_response_code(&StatusCode::ServiceUnavailable, None) {
+            Err(ApiError::ServiceUnavailable(None)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    fn lookup_credits_raw(conn: &Connection) -> Result<(), ApiError> {
+        let url = format!("{}/credits", conn.endpoint);
+        let res = conn.send(&mut conn.request(Method::Get, &url))?;
+        map_response(&res, None)
+    }
+
+    #[test]
+    fn test_send_simple_no_content() {
+        let server = TestServer::start(vec![(204, ""), (200, "")]);
+        let recipient = Recipient::new_id("ECHOECHO");
+        for _ in 0..2 {
+            match send_simple(&server.conn(), "*3MAGWID", &recipient, "secret", "Hello") {
+                Err(ApiError::BadMessageId) => (),
+                other => panic!("Unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_send_e2e_no_content() {
+        let server = TestServer::start(vec![(204, ""), (200, "")]);
+        for _ in 0..2 {
+            match send_e2e_dated(&server.conn(), "*3MAGWID", "ECHOECHO", "secret", &[0; 24], &[0; 32], None) {
+                Err(ApiError::BadMessageId) => (),
+                other => panic!("Unexpected result: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_control_chars() {
+        assert!(check_control_chars("Hello\r\n\tWorld äöü 😀").is_ok());
+        match check_control_chars("ä\u{7}") {
+            Err(ApiError::ControlCharacter('\u{7}', 2)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(check_control_chars("\u{0}").is_err());
+        assert!(check_control_chars("\u{9f}").is_err());
+    }
+
+    #[test]
+    fn test_send_e2e_reserved_param() {
+        let mut params = HashMap::new();
+        params.insert("secret".to_string(), "other".to_string());
+        match send_e2e(&conn(), "*3MAGWID", "ECHOECHO", "secret", &[0; 24], &[0; 32], Some(params)) {
+            Err(ApiError::ReservedParameter(ref key)) if key == "secret" => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_response_body() {
+        assert_eq!(decode_response_body(b"0123456789abcdef".to_vec()).unwrap(), "0123456789abcdef");
+        match decode_response_body(vec![b'o', b'k', 0xff, 0xfe]) {
+            Err(ApiError::InvalidResponseEncoding(ref snippet)) => assert_eq!(snippet, "ok\u{fffd}\u{fffd}"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_max_length_constant() {
+        assert_eq!(MAX_SIMPLE_MESSAGE_LEN, 3500);
+    }
+
+    #[test]
+    fn test_text_byte_length() {
+        assert_eq!(text_byte_length(""), 0);
+        assert_eq!(text_byte_length("abc"), 3);
+        assert_eq!(text_byte_length("à"), 2);
+        assert_eq!(text_byte_length("€"), 3);
+        assert_eq!(text_byte_length("👍"), 4);
+
+        let text: String = repeat("à").take(2000).collect();
+        assert_eq!(text.chars().count(), 2000);
+        assert!(text_byte_length(&text) > MAX_SIMPLE_MESSAGE_LEN);
+    }
+
     #[test]
     fn test_max_length_ok() {
-        let text: String = repeat("à").take(3500 / 2).collect();
-        let result = send_simple(MSGAPI_URL, "TESTTEST", &Recipient::new_id("ECHOECHO"), "secret", &text);
+        let text: String = repeat("à").take(MAX_SIMPLE_MESSAGE_LEN / 2).collect();
+        let result = send_simple(&conn(), "TESTTEST", &Recipient::new_id("ECHOECHO"), "secret", &text);
         match result {
             Err(ApiError::MessageTooLong) => panic!(),
             _ => (),
@@ -200,13 +1128,85 @@ mod tests {
 
     #[test]
     fn test_max_length_too_long() {
-        let mut text: String = repeat("à").take(3500 / 2).collect();
+        let mut text: String = repeat("à").take(MAX_SIMPLE_MESSAGE_LEN / 2).collect();
         text.push('x');
-        let result = send_simple(MSGAPI_URL, "TESTTEST", &Recipient::new_id("ECHOECHO"), "secret", &text);
+        let result = send_simple(&conn(), "TESTTEST", &Recipient::new_id("ECHOECHO"), "secret", &text);
         match result {
             Err(ApiError::MessageTooLong) => (),
             _ => panic!(),
         }
     }
 
+    #[test]
+    fn test_recipient_from_str() {
+        assert_eq!(Recipient::from_str("ECHOECHO").unwrap(), Recipient::new_id("ECHOECHO"));
+        assert_eq!(Recipient::from_str("*3MAGWID").unwrap(), Recipient::new_id("*3MAGWID"));
+        assert_eq!(Recipient::from_str(" user@example.com ").unwrap(), Recipient::new_email("user@example.com"));
+        assert_eq!(Recipient::from_str("41791234567").unwrap(), Recipient::new_phone("41791234567"));
+        assert_eq!(Recipient::from_str("+41791234567").unwrap(), Recipient::new_phone("41791234567"));
+        // Ambiguous: 8 digits are an ID
+        assert_eq!(Recipient::from_str("12345678").unwrap(), Recipient::new_id("12345678"));
+    }
+
+    #[test]
+    fn test_recipient_from_str_invalid() {
+        assert!(Recipient::from_str("").is_err());
+        assert!(Recipient::from_str("+").is_err());
+        assert!(Recipient::from_str("ECHO").is_err());
+        assert!(Recipient::from_str("echoecho").is_err());
+        assert!(Recipient::from_str("ECHO*CHO").is_err());
+        assert!(Recipient::from_str("********").is_err());
+        assert_eq!(Recipient::from_str("*3MAGWID").unwrap(), Recipient::new_id("*3MAGWID"));
+        assert!(Recipient::from_str("+41 79 123").is_err());
+        match Recipient::from_str("hello!") {
+            Err(ApiError::BadRecipient(ref input)) if input == "hello!" => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_split_text_short() {
+        assert_eq!(split_text("hello world", 3500), vec!["hello world"]);
+        assert_eq!(split_text("", 3500), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_split_text_whitespace() {
+        assert_eq!(split_text("hello world foo", 12), vec!["hello world ", "foo"]);
+        assert_eq!(split_text("hello world foo", 11), vec!["hello ", "world foo"]);
+        assert_eq!(split_text("hello\nworld", 8), vec!["hello\n", "world"]);
+    }
+
+    #[test]
+    fn test_split_text_no_whitespace() {
+        assert_eq!(split_text("abcdefgh", 3), vec!["abc", "def", "gh"]);
+    }
+
+    #[test]
+    fn test_split_text_multibyte() {
+        // Each "à" is 2 bytes long
+        assert_eq!(split_text("àààà", 3), vec!["à", "à", "à", "à"]);
+        assert_eq!(split_text("àààà", 4), vec!["àà", "àà"]);
+        // A limit smaller than a character still makes progress
+        assert_eq!(split_text("àà", 1), vec!["à", "à"]);
+    }
+
+    #[test]
+    fn test_split_text_lossless() {
+        let text: String = repeat("Grüezi mitenand! ").take(500).collect();
+        let parts = split_text(&text, 3500);
+        assert_eq!(parts.len(), 3);
+        assert!(parts.iter().all(|p| p.len() <= 3500));
+        assert_eq!(parts.concat(), text);
+    }
+
+    #[test]
+    fn test_empty_message() {
+        let result = send_simple(&conn(), "TESTTEST", &Recipient::new_id("ECHOECHO"), "secret", "");
+        match result {
+            Err(ApiError::EmptyMessage) => (),
+            _ => panic!(),
+        }
+    }
+
 }
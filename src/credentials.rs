@@ -0,0 +1,179 @@
+//! Sources for the gateway credentials.
+
+use std::env::{self, VarError};
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+
+use ::errors::ApiBuilderError;
+
+
+/// The credentials of a gateway ID.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    /// The gateway ID
+    pub id: String,
+    /// The gateway secret
+    pub secret: String,
+    /// The hex encoded private key, only needed for E2E mode. It may be
+    /// prefixed with `private:`.
+    pub private_key: Option<String>,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("id", &self.id)
+            .field("secret", &"***")
+            .field("private_key", &self.private_key.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+/// A source of gateway credentials, see
+/// [`ApiBuilder::from_credentials`](struct.ApiBuilder.html#method.from_credentials).
+///
+/// Implement this trait to read the credentials from a secret manager. The
+/// credentials are read whenever an `ApiBuilder` is created from the source,
+/// so rotated credentials are picked up by building a new API object.
+pub trait CredentialSource {
+    /// Read the credentials.
+    fn credentials(&self) -> Result<Credentials, ApiBuilderError>;
+}
+
+/// Read the credentials from environment variables.
+///
+/// By default, the variables `THREEMA_FROM`, `THREEMA_SECRET` and
+/// `THREEMA_PRIVATE_KEY` are used. The private key variable is optional.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvCredentials {
+    id_var: String,
+    secret_var: String,
+    private_key_var: String,
+}
+
+impl EnvCredentials {
+    /// Use the default variable names.
+    pub fn new() -> Self {
+        EnvCredentials::with_names("THREEMA_FROM", "THREEMA_SECRET", "THREEMA_PRIVATE_KEY")
+    }
+
+    /// Use custom variable names.
+    pub fn with_names<I, S, K>(id_var: I, secret_var: S, private_key_var: K) -> Self
+            where I: Into<String>, S: Into<String>, K: Into<String> {
+        EnvCredentials {
+            id_var: id_var.into(),
+            secret_var: secret_var.into(),
+            private_key_var: private_key_var.into(),
+        }
+    }
+}
+
+impl Default for EnvCredentials {
+    fn default() -> Self {
+        EnvCredentials::new()
+    }
+}
+
+impl CredentialSource for EnvCredentials {
+    fn credentials(&self) -> Result<Credentials, ApiBuilderError> {
+        let read = |name: &str| env::var(name)
+            .map(|val| val.trim().to_string())
+            .map_err(|e| ApiBuilderError::CredentialsEnv(name.to_string(), e));
+        let id = read(&self.id_var)?;
+        let secret = read(&self.secret_var)?;
+        let private_key = match read(&self.private_key_var) {
+            Ok(key) => Some(key),
+            Err(ApiBuilderError::CredentialsEnv(_, VarError::NotPresent)) => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Credentials {
+            id: id,
+            secret: secret,
+            private_key: private_key,
+        })
+    }
+}
+
+/// Read the credentials from files in a directory.
+///
+/// The directory must contain a file `from` with the Gateway ID and a file
+/// `secret` with the Gateway Secret. The file `private_key` with the hex
+/// encoded private key is optional. Leading and trailing whitespace is
+/// ignored in all files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirCredentials(PathBuf);
+
+impl DirCredentials {
+    /// Read the credentials from the specified directory.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        DirCredentials(dir.into())
+    }
+}
+
+impl CredentialSource for DirCredentials {
+    fn credentials(&self) -> Result<Credentials, ApiBuilderError> {
+        let id = read_credentials_file(self.0.join("from"))?;
+        let secret = read_credentials_file(self.0.join("secret"))?;
+        let private_key = match read_credentials_file(self.0.join("private_key")) {
+            Ok(key) => Some(key),
+            Err(ApiBuilderError::CredentialsFile(_, ref e)) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Credentials {
+            id: id,
+            secret: secret,
+            private_key: private_key,
+        })
+    }
+}
+
+/// Read a credentials file and trim whitespace.
+fn read_credentials_file(path: PathBuf) -> Result<String, ApiBuilderError> {
+    let mut contents = String::new();
+    match File::open(&path).and_then(|mut file| file.read_to_string(&mut contents)) {
+        Ok(_) => Ok(contents.trim().to_string()),
+        Err(e) => Err(ApiBuilderError::CredentialsFile(path, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::{self, VarError};
+    use ::errors::ApiBuilderError;
+    use super::{CredentialSource, Credentials, EnvCredentials};
+
+    #[test]
+    fn test_env_credentials() {
+        let source = EnvCredentials::with_names("THREEMA_TEST_ENV_FROM", "THREEMA_TEST_ENV_SECRET", "THREEMA_TEST_ENV_KEY");
+        env::set_var("THREEMA_TEST_ENV_FROM", "*3MAGWID");
+        match source.credentials() {
+            Err(ApiBuilderError::CredentialsEnv(ref name, VarError::NotPresent)) => assert_eq!(name, "THREEMA_TEST_ENV_SECRET"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        env::set_var("THREEMA_TEST_ENV_SECRET", " 1234\n");
+        assert_eq!(source.credentials().unwrap(), Credentials {
+            id: "*3MAGWID".into(),
+            secret: "1234".into(),
+            private_key: None,
+        });
+
+        env::set_var("THREEMA_TEST_ENV_KEY", "private:00");
+        assert_eq!(source.credentials().unwrap().private_key, Some("private:00".into()));
+    }
+
+    #[test]
+    fn test_credentials_debug_redacted() {
+        let credentials = Credentials {
+            id: "*3MAGWID".into(),
+            secret: "topsecret123".into(),
+            private_key: Some("998730fbcac1c57d".into()),
+        };
+        let debug = format!("{:?}", credentials);
+        assert!(debug.contains("*3MAGWID"));
+        assert!(!debug.contains("topsecret123"));
+        assert!(!debug.contains("998730fbcac1c57d"));
+    }
+}
@@ -1,23 +1,37 @@
 //! Encrypt and decrypt messages.
 
 use std::convert::Into;
+use std::fmt;
 use std::io::Write;
 use std::iter::repeat;
 use std::string::ToString;
 
 use byteorder::{LittleEndian, WriteBytesExt};
-use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use data_encoding::HEXLOWER_PERMISSIVE;
 use mime::Mime;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::Serializer;
 use serde_json as json;
 use sodiumoxide;
-use sodiumoxide::crypto::box_::{self, PublicKey, SecretKey};
-use sodiumoxide::crypto::secretbox::Key;
+use sodiumoxide::crypto::box_::{self, Nonce, PublicKey, SecretKey};
+use sodiumoxide::crypto::hash::sha256;
+use sodiumoxide::crypto::secretbox;
 use sodiumoxide::randombytes::randombytes_into;
 
-use ::errors::CryptoError;
-use ::types::{MessageType, BlobId, FileMessage};
+use ::errors::{ApiError, CryptoError};
+use ::hex::{decode_hex_fixed, encode_hex, HexError};
+use ::message::{ReceiptType, VoipRejectReason, nonce_to_hex, nonce_from_hex};
+use ::types::{MessageType, MessageFlags, BlobId, BlobKey, FileMessage, GroupId, MessageId};
 
 
+/// The nonce used to symmetrically encrypt file data.
+pub const FILE_NONCE: secretbox::Nonce = secretbox::Nonce([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                                                           0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+
+/// The nonce used to symmetrically encrypt thumbnail data.
+pub const THUMBNAIL_NONCE: secretbox::Nonce = secretbox::Nonce([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                                                                0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2]);
+
 /// Return a random number in the range `[1, 255]`.
 fn random_padding_amount() -> u8 {
     let mut buf: [u8; 1] = [0];
@@ -35,7 +49,76 @@ pub struct EncryptedMessage {
     pub nonce: [u8; 24],
 }
 
+impl EncryptedMessage {
+    /// Create an `EncryptedMessage` from a hex encoded nonce and ciphertext,
+    /// e.g. to resend a stored message.
+    ///
+    /// The nonce must be exactly 24 bytes long, otherwise
+    /// `CryptoError::BadNonce` is returned. Invalid hex strings result in an
+    /// [`ApiError::ParseError`](errors/enum.ApiError.html#variant.ParseError).
+    pub fn from_hex(nonce_hex: &str, ciphertext_hex: &str) -> Result<Self, ApiError> {
+        let nonce = match decode_hex_fixed(nonce_hex) {
+            Ok(nonce) => nonce,
+            Err(HexError::Length { .. }) => return Err(CryptoError::BadNonce.into()),
+            Err(e) => return Err(ApiError::ParseError(format!("Could not decode nonce hex string: {}", e))),
+        };
+        let ciphertext = HEXLOWER_PERMISSIVE.decode(ciphertext_hex.as_bytes())
+            .map_err(|e| ApiError::ParseError(format!("Could not decode ciphertext hex string: {}", e)))?;
+        Ok(EncryptedMessage {
+            ciphertext: ciphertext,
+            nonce: nonce,
+        })
+    }
+}
+
+/// An encrypted message together with its recipient and flags, ready to be
+/// sent with [`E2eApi::send_prepared`](struct.E2eApi.html#method.send_prepared).
+///
+/// Preparing a message does not need network access: All `encrypt_*`
+/// methods accept a [`RecipientKey`](struct.RecipientKey.html), which can be
+/// created from a stored public key. Prepared messages can be serialized, to
+/// persist them in a queue and send them later.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreparedMessage {
+    /// The recipient identity
+    pub to: String,
+    /// The nonce used for encryption
+    #[serde(serialize_with = "nonce_to_hex")]
+    #[serde(deserialize_with = "nonce_from_hex")]
+    pub nonce: [u8; 24],
+    /// The encrypted message data
+    #[serde(rename = "box")]
+    #[serde(serialize_with = "bytes_to_hex")]
+    #[serde(deserialize_with = "bytes_from_hex")]
+    pub box_data: Vec<u8>,
+    /// The message flags
+    #[serde(default)]
+    pub flags: MessageFlags,
+}
+
+impl PreparedMessage {
+    /// Prepare an encrypted message for the specified recipient.
+    pub fn new<S: Into<String>>(to: S, message: EncryptedMessage, flags: MessageFlags) -> Self {
+        PreparedMessage {
+            to: to.into(),
+            nonce: message.nonce,
+            box_data: message.ciphertext,
+            flags: flags,
+        }
+    }
+}
+
+fn bytes_to_hex<S: Serializer>(val: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode_hex(val))
+}
+
+fn bytes_from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    HEXLOWER_PERMISSIVE.decode(s.as_bytes()).map_err(de::Error::custom)
+}
+
 /// The public key of a recipient.
+#[derive(Debug, Clone, PartialEq)]
 pub struct RecipientKey(pub PublicKey);
 
 impl From<PublicKey> for RecipientKey {
@@ -55,7 +138,7 @@ impl From<[u8; 32]> for RecipientKey {
 impl Into<String> for RecipientKey {
     /// Encode the key bytes as lowercase hex string.
     fn into(self) -> String {
-        HEXLOWER.encode(&(self.0).0)
+        encode_hex(&(self.0).0)
     }
 }
 
@@ -70,15 +153,66 @@ impl RecipientKey {
 
     /// Create a `RecipientKey` from a hex encoded string slice.
     pub fn from_str(val: &str) -> Result<Self, CryptoError> {
-        let bytes = HEXLOWER_PERMISSIVE.decode(val.as_bytes())
-            .map_err(|e| CryptoError::BadKey(format!("Could not decode public key hex string: {}", e)))?;
-        RecipientKey::from_bytes(bytes.as_slice())
+        decode_hex_fixed(val)
+            .map(|bytes| RecipientKey(PublicKey(bytes)))
+            .map_err(|e| CryptoError::BadKey(format!("Could not decode public key hex string: {}", e)))
     }
 
     /// Return a reference to the contained key bytes.
     pub fn as_bytes(&self) -> &[u8] {
         &(self.0).0
     }
+
+    /// Return the fingerprint of the key.
+    ///
+    /// The fingerprint consists of the first 16 bytes of the SHA-256 hash of
+    /// the public key, encoded as lowercase hex string. This is the key
+    /// fingerprint displayed by the Threema apps.
+    pub fn fingerprint(&self) -> String {
+        let sha256::Digest(hash) = sha256::hash(self.as_bytes());
+        encode_hex(&hash[..16])
+    }
+
+    /// Return whether the key matches the specified fingerprint.
+    ///
+    /// Case and whitespace in the fingerprint are ignored, so fingerprints
+    /// copied from the Threema apps (displayed in groups) can be used
+    /// directly. Use this to detect if the public key of a contact has
+    /// unexpectedly changed.
+    pub fn matches_fingerprint(&self, fingerprint: &str) -> bool {
+        let normalized: String = fingerprint.chars()
+            .filter(|c| !c.is_whitespace())
+            .flat_map(char::to_lowercase)
+            .collect();
+        normalized == self.fingerprint()
+    }
+}
+
+/// A shared key, precomputed from the recipient's public key and our private
+/// key.
+///
+/// Encrypting with a precomputed key skips the key agreement step, which is
+/// faster when sending many messages to the same recipient. The shared key
+/// is symmetric, so the same key also decrypts messages from that
+/// recipient.
+#[derive(Clone)]
+pub struct PrecomputedKey(box_::PrecomputedKey);
+
+impl PrecomputedKey {
+    /// Precompute the shared key for the specified recipient public key and
+    /// our private key.
+    pub fn new(recipient_key: &RecipientKey, private_key: &SecretKey) -> Self {
+        if !sodiumoxide::init() {
+            panic!("Could not initialize sodiumoxide library.");
+        }
+        PrecomputedKey(box_::precompute(&recipient_key.0, private_key))
+    }
+}
+
+impl fmt::Debug for PrecomputedKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PrecomputedKey(***)")
+    }
 }
 
 
@@ -95,21 +229,73 @@ pub fn encrypt_raw(data: &[u8], public_key: &PublicKey, private_key: &SecretKey)
     }
 }
 
+/// Encrypt data for the recipient, using a precomputed shared key.
+pub fn encrypt_raw_precomputed(data: &[u8], key: &PrecomputedKey) -> EncryptedMessage {
+    if !sodiumoxide::init() {
+        panic!("Could not initialize sodiumoxide library.");
+    }
+    let nonce = box_::gen_nonce();
+    let ciphertext = box_::seal_precomputed(&data, &nonce, &key.0);
+    EncryptedMessage {
+        ciphertext: ciphertext,
+        nonce: nonce.0,
+    }
+}
+
+/// Prepend the message type byte and add a random amount of PKCS#7 style
+/// padding.
+fn pad(data: &[u8], msgtype: MessageType) -> Vec<u8> {
+    pad_with(data, msgtype, random_padding_amount())
+}
+
+/// Prepend the message type byte and add the specified amount of PKCS#7
+/// style padding.
+fn pad_with(data: &[u8], msgtype: MessageType, padding_amount: u8) -> Vec<u8> {
+    let padding = repeat(padding_amount).take(padding_amount as usize);
+    let msgtype_byte = repeat(msgtype.into()).take(1);
+    msgtype_byte.chain(data.iter().cloned()).chain(padding).collect()
+}
+
 /// Encrypt a message for the recipient.
 pub fn encrypt(data: &[u8],
                msgtype: MessageType,
                public_key: &PublicKey,
                private_key: &SecretKey)
                -> EncryptedMessage {
+    encrypt_raw(&pad(data, msgtype), &public_key, &private_key)
+}
 
-    // Add random amount of PKCS#7 style padding
-    let padding_amount = random_padding_amount();
-    let padding = repeat(padding_amount).take(padding_amount as usize);
-    let msgtype_byte = repeat(msgtype.into()).take(1);
-    let padded_plaintext: Vec<u8> = msgtype_byte.chain(data.iter().cloned()).chain(padding).collect();
+/// Encrypt a message for the recipient, with the specified nonce and amount
+/// of padding (1 to 255 bytes) instead of random ones.
+///
+/// This makes the ciphertext deterministic for known-answer tests, so it is
+/// only compiled for tests: encrypting two messages with the same nonce and
+/// key pair breaks their confidentiality. A padding amount of 0 results in
+/// `CryptoError::BadPadding`.
+#[cfg(test)]
+pub(crate) fn encrypt_with_nonce(data: &[u8],
+                          msgtype: MessageType,
+                          nonce: &[u8; 24],
+                          padding_amount: u8,
+                          public_key: &PublicKey,
+                          private_key: &SecretKey)
+                          -> Result<EncryptedMessage, CryptoError> {
+    if padding_amount == 0 {
+        return Err(CryptoError::BadPadding);
+    }
+    if !sodiumoxide::init() {
+        panic!("Could not initialize sodiumoxide library.");
+    }
+    let ciphertext = box_::seal(&pad_with(data, msgtype, padding_amount), &Nonce(*nonce), public_key, private_key);
+    Ok(EncryptedMessage {
+        ciphertext: ciphertext,
+        nonce: *nonce,
+    })
+}
 
-    // Encrypt
-    encrypt_raw(&padded_plaintext, &public_key, &private_key)
+/// Encrypt a message for the recipient, using a precomputed shared key.
+pub fn encrypt_precomputed(data: &[u8], msgtype: MessageType, key: &PrecomputedKey) -> EncryptedMessage {
+    encrypt_raw_precomputed(&pad(data, msgtype), key)
 }
 
 /// Encrypt an image message for the recipient.
@@ -122,7 +308,7 @@ pub fn encrypt_image_msg(blob_id: &BlobId,
     let mut data = [0; 44];
     // Since we're writing to an array and not to a file or socket, these
     // write operations should never fail.
-    (&mut data[0..16]).write_all(&blob_id.0).expect("Writing to buffer failed");
+    (&mut data[0..16]).write_all(blob_id.as_bytes()).expect("Writing to buffer failed");
     (&mut data[16..20]).write_u32::<LittleEndian>(img_size_bytes).expect("Writing to buffer failed");
     (&mut data[20..44]).write_all(image_data_nonce).expect("Writing to buffer failed");
     let msgtype = MessageType::Image;
@@ -132,7 +318,7 @@ pub fn encrypt_image_msg(blob_id: &BlobId,
 /// Encrypt a file message for the recipient.
 pub fn encrypt_file_msg(file_blob_id: &BlobId,
                         thumbnail_blob_id: Option<&BlobId>,
-                        blob_encryption_key: &Key,
+                        blob_encryption_key: &BlobKey,
                         mime_type: &Mime,
                         file_name: Option<&str>,
                         file_size_bytes: u32,
@@ -149,13 +335,305 @@ pub fn encrypt_file_msg(file_blob_id: &BlobId,
     encrypt(&data.as_bytes(), msgtype, &public_key, &private_key)
 }
 
+/// Encrypt file data with the specified blob key.
+///
+/// The file data of a file message is encrypted symmetrically with a
+/// [`BlobKey`](struct.BlobKey.html) and uploaded to the blob server, while
+/// the file message itself (containing the blob key) is encrypted for the
+/// recipient. Since the nonce is fixed, a blob key must never be used to
+/// encrypt different files.
+pub fn encrypt_blob(data: &[u8], key: &BlobKey) -> Vec<u8> {
+    secretbox::seal(data, &FILE_NONCE, &key.to_secretbox_key())
+}
+
+/// Decrypt file data with the specified blob key.
+///
+/// The file data is encrypted as a single NaCl secretbox, which cannot be
+/// decrypted in chunks: the authentication tag covers the whole file and
+/// must be verified before any plaintext can be trusted. This function
+/// therefore needs the whole encrypted blob and allocates a second buffer
+/// of the same size for the plaintext. Use
+/// [`decrypt_blob_in_place`](fn.decrypt_blob_in_place.html) to avoid the
+/// second buffer for large files.
+pub fn decrypt_blob(data: &[u8], key: &BlobKey) -> Result<Vec<u8>, CryptoError> {
+    secretbox::open(data, &FILE_NONCE, &key.to_secretbox_key())
+        .map_err(|_| CryptoError::DecryptionFailed)
+}
+
+/// Decrypt file data with the specified blob key, reusing the buffer of the
+/// encrypted blob for the plaintext.
+///
+/// Unlike [`decrypt_blob`](fn.decrypt_blob.html), no additional memory is
+/// allocated, so decrypting a large video needs roughly the size of the
+/// file once instead of twice. If decryption fails, the buffer is dropped.
+pub fn decrypt_blob_in_place(mut data: Vec<u8>, key: &BlobKey) -> Result<Vec<u8>, CryptoError> {
+    if data.len() < secretbox::MACBYTES {
+        return Err(CryptoError::DecryptionFailed);
+    }
+    let tag = secretbox::Tag::from_slice(&data[..secretbox::MACBYTES]).ok_or(CryptoError::DecryptionFailed)?;
+    secretbox::open_detached(&mut data[secretbox::MACBYTES..], &tag, &FILE_NONCE, &key.to_secretbox_key())
+        .map_err(|_| CryptoError::DecryptionFailed)?;
+    data.drain(..secretbox::MACBYTES);
+    Ok(data)
+}
+
+/// Encrypt thumbnail data with the specified blob key.
+///
+/// Thumbnails are encrypted with the same key as the file data of a file
+/// message, but with a different nonce.
+pub fn encrypt_thumbnail_blob(data: &[u8], key: &BlobKey) -> Vec<u8> {
+    secretbox::seal(data, &THUMBNAIL_NONCE, &key.to_secretbox_key())
+}
+
+/// Decrypt thumbnail data with the specified blob key.
+pub fn decrypt_thumbnail_blob(data: &[u8], key: &BlobKey) -> Result<Vec<u8>, CryptoError> {
+    secretbox::open(data, &THUMBNAIL_NONCE, &key.to_secretbox_key())
+        .map_err(|_| CryptoError::DecryptionFailed)
+}
+
+/// Encrypt file data and an optional thumbnail with a fresh random key.
+///
+/// Returns the encrypted file data, the encrypted thumbnail data and the key.
+/// The key must be sent to the recipient in the file message.
+pub fn encrypt_file_data(file_data: &[u8], thumbnail_data: Option<&[u8]>) -> (Vec<u8>, Option<Vec<u8>>, BlobKey) {
+    let key = BlobKey::generate();
+    let encrypted_file = encrypt_blob(file_data, &key);
+    let encrypted_thumbnail = thumbnail_data.map(|t| encrypt_thumbnail_blob(t, &key));
+    (encrypted_file, encrypted_thumbnail, key)
+}
+
+/// Encrypt a delivery receipt for the recipient.
+///
+/// The payload consists of the receipt type byte, followed by the IDs of the
+/// messages the receipt refers to.
+pub fn encrypt_delivery_receipt(receipt_type: ReceiptType,
+                                message_ids: &[MessageId],
+                                public_key: &PublicKey,
+                                private_key: &SecretKey)
+                                -> EncryptedMessage {
+    let mut data = Vec::with_capacity(1 + 8 * message_ids.len());
+    data.push(receipt_type.into());
+    for message_id in message_ids {
+        data.extend_from_slice(&message_id.0);
+    }
+    let msgtype = MessageType::DeliveryReceipt;
+    encrypt(&data, msgtype, public_key, private_key)
+}
+
+/// Encrypt a delete message for the recipient.
+///
+/// The payload is a protobuf encoded `DeleteMessage`, containing only the
+/// message ID as field 1 (`fixed64`).
+pub fn encrypt_delete_msg(message_id: &MessageId,
+                          public_key: &PublicKey,
+                          private_key: &SecretKey)
+                          -> EncryptedMessage {
+    let mut data = [0; 9];
+    // Field 1, wire type 1 (64 bit)
+    data[0] = 0x09;
+    data[1..9].copy_from_slice(&message_id.0);
+    let msgtype = MessageType::DeleteMessage;
+    encrypt(&data, msgtype, public_key, private_key)
+}
+
+/// Encrypt a typing indicator for the recipient.
+///
+/// The payload is a single byte, 1 if typing started and 0 if it stopped.
+pub fn encrypt_typing_indicator(typing: bool,
+                                public_key: &PublicKey,
+                                private_key: &SecretKey)
+                                -> EncryptedMessage {
+    let data = [typing as u8];
+    let msgtype = MessageType::TypingIndicator;
+    encrypt(&data, msgtype, public_key, private_key)
+}
+
+/// Encrypt a group create message for the recipient.
+///
+/// The message contains the group ID and the Threema IDs of all members,
+/// except for the creator (the sender), who is a member implicitly.
+///
+/// Returns `ApiError::BadRecipient` if a member ID is not exactly 8 bytes
+/// long, since the IDs are encoded without a separator.
+pub fn encrypt_group_create_msg(group_id: &GroupId,
+                                members: &[&str],
+                                public_key: &PublicKey,
+                                private_key: &SecretKey)
+                                -> Result<EncryptedMessage, ApiError> {
+    let mut data = Vec::with_capacity(8 + members.len() * 8);
+    data.extend_from_slice(&group_id.0);
+    for member in members {
+        if member.len() != 8 {
+            return Err(ApiError::BadRecipient(member.to_string()));
+        }
+        data.extend_from_slice(member.as_bytes());
+    }
+    let msgtype = MessageType::GroupCreate;
+    Ok(encrypt(&data, msgtype, public_key, private_key))
+}
+
+/// Encrypt a group rename message for the recipient.
+pub fn encrypt_group_rename_msg(group_id: &GroupId,
+                                name: &str,
+                                public_key: &PublicKey,
+                                private_key: &SecretKey)
+                                -> EncryptedMessage {
+    let mut data = Vec::with_capacity(8 + name.len());
+    data.extend_from_slice(&group_id.0);
+    data.extend_from_slice(name.as_bytes());
+    let msgtype = MessageType::GroupRename;
+    encrypt(&data, msgtype, public_key, private_key)
+}
+
+/// Encrypt a group set photo message for the recipient.
+///
+/// The photo must be uploaded to the blob server first, encrypted with
+/// [`encrypt_blob`](fn.encrypt_blob.html). `size_bytes` is the size of the
+/// encrypted blob.
+pub fn encrypt_group_set_photo_msg(group_id: &GroupId,
+                                   blob_id: &BlobId,
+                                   size_bytes: u32,
+                                   blob_key: &BlobKey,
+                                   public_key: &PublicKey,
+                                   private_key: &SecretKey)
+                                   -> EncryptedMessage {
+    let mut data = [0; 60];
+    (&mut data[0..8]).write_all(&group_id.0).expect("Writing to buffer failed");
+    (&mut data[8..24]).write_all(blob_id.as_bytes()).expect("Writing to buffer failed");
+    (&mut data[24..28]).write_u32::<LittleEndian>(size_bytes).expect("Writing to buffer failed");
+    (&mut data[28..60]).write_all(&blob_key.0).expect("Writing to buffer failed");
+    let msgtype = MessageType::GroupSetPhoto;
+    encrypt(&data, msgtype, public_key, private_key)
+}
+
+/// The JSON body of a VoIP call answer or hangup.
+#[derive(Serialize)]
+struct VoipAnswer {
+    #[serde(rename = "callId", skip_serializing_if = "Option::is_none")]
+    call_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    action: Option<u8>,
+    #[serde(rename = "rejectReason", skip_serializing_if = "Option::is_none")]
+    reject_reason: Option<u8>,
+}
+
+/// Encrypt a VoIP call answer rejecting a call for the recipient.
+///
+/// `call_id` is the call ID of the offer, if it contains one.
+pub fn encrypt_voip_reject(call_id: Option<u32>,
+                           reason: VoipRejectReason,
+                           public_key: &PublicKey,
+                           private_key: &SecretKey)
+                           -> EncryptedMessage {
+    let answer = VoipAnswer {
+        call_id: call_id,
+        // Action 0 rejects the call, 1 accepts it
+        action: Some(0),
+        reject_reason: Some(reason.into()),
+    };
+    let data = json::to_string(&answer).unwrap();
+    let msgtype = MessageType::VoipCallAnswer;
+    encrypt(data.as_bytes(), msgtype, public_key, private_key)
+}
+
+/// Encrypt a VoIP call hangup for the recipient.
+pub fn encrypt_voip_hangup(call_id: Option<u32>,
+                           public_key: &PublicKey,
+                           private_key: &SecretKey)
+                           -> EncryptedMessage {
+    let hangup = VoipAnswer { call_id: call_id, action: None, reject_reason: None };
+    let data = json::to_string(&hangup).unwrap();
+    let msgtype = MessageType::VoipCallHangup;
+    encrypt(data.as_bytes(), msgtype, public_key, private_key)
+}
+
+/// Decrypt data from the sender.
+pub fn decrypt_raw(ciphertext: &[u8],
+                   nonce: &[u8],
+                   public_key: &PublicKey,
+                   private_key: &SecretKey)
+                   -> Result<Vec<u8>, CryptoError> {
+    if !sodiumoxide::init() {
+        panic!("Could not initialize sodiumoxide library.");
+    }
+    let nonce = Nonce::from_slice(nonce).ok_or(CryptoError::BadNonce)?;
+    box_::open(ciphertext, &nonce, public_key, private_key)
+        .map_err(|_| CryptoError::DecryptionFailed)
+}
+
+/// Decrypt data from the sender, using a precomputed shared key.
+pub fn decrypt_raw_precomputed(ciphertext: &[u8],
+                               nonce: &[u8],
+                               key: &PrecomputedKey)
+                               -> Result<Vec<u8>, CryptoError> {
+    let nonce = Nonce::from_slice(nonce).ok_or(CryptoError::BadNonce)?;
+    box_::open_precomputed(ciphertext, &nonce, &key.0)
+        .map_err(|_| CryptoError::DecryptionFailed)
+}
+
+/// Decrypt a message from the sender, using a precomputed shared key, and
+/// remove the padding.
+///
+/// The returned data still starts with the message type byte.
+pub fn decrypt_precomputed(ciphertext: &[u8], nonce: &[u8], key: &PrecomputedKey) -> Result<Vec<u8>, CryptoError> {
+    unpad(decrypt_raw_precomputed(ciphertext, nonce, key)?)
+}
+
+/// Decrypt a message from the sender, trying the private keys in order, and
+/// remove the padding.
+///
+/// The returned data still starts with the message type byte. The first key
+/// that successfully authenticates the ciphertext is used. If none does,
+/// `CryptoError::DecryptionFailed` is returned.
+pub fn decrypt_with_any_key<'a, I>(ciphertext: &[u8],
+                                   nonce: &[u8],
+                                   public_key: &PublicKey,
+                                   private_keys: I)
+                                   -> Result<Vec<u8>, CryptoError>
+        where I: IntoIterator<Item=&'a SecretKey> {
+    for private_key in private_keys {
+        match decrypt_raw(ciphertext, nonce, public_key, private_key) {
+            Ok(data) => return unpad(data),
+            Err(CryptoError::DecryptionFailed) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(CryptoError::DecryptionFailed)
+}
+
+/// Remove PKCS#7 style padding. At least the message type byte must remain.
+fn unpad(mut data: Vec<u8>) -> Result<Vec<u8>, CryptoError> {
+    let padding_amount = match data.last() {
+        Some(&amount) => amount as usize,
+        None => return Err(CryptoError::BadPadding),
+    };
+    if padding_amount == 0 || padding_amount >= data.len() {
+        return Err(CryptoError::BadPadding);
+    }
+    let len = data.len() - padding_amount;
+    data.truncate(len);
+    Ok(data)
+}
+
 #[cfg(test)]
 mod test {
 
     use sodiumoxide::crypto::box_::{self, PublicKey, SecretKey, Nonce};
-    use super::{random_padding_amount, RecipientKey};
+    use super::{random_padding_amount, RecipientKey, PrecomputedKey, encrypt, encrypt_raw, encrypt_delete_msg, decrypt_raw};
+    use super::{encrypt_with_nonce, encrypt_precomputed, decrypt_precomputed, decrypt_with_any_key, encrypt_file_data, FILE_NONCE, THUMBNAIL_NONCE};
+    use super::{encrypt_blob, decrypt_blob, decrypt_blob_in_place, encrypt_file_msg, unpad, EncryptedMessage, encrypt_delivery_receipt};
+    use super::{encrypt_group_create_msg, encrypt_group_rename_msg, encrypt_group_set_photo_msg};
+    use super::{encrypt_voip_reject, encrypt_voip_hangup, encrypt_typing_indicator, PreparedMessage};
+    use ::message::{decode, Message, ReceiptType, VoipRejectReason};
+    use sodiumoxide::crypto::secretbox;
     use ::api::ApiBuilder;
-    use ::types::{BlobId, MessageType};
+    use ::errors::{ApiError, CryptoError};
+    use ::types::{BlobId, BlobKey, FileMessage, GroupId, MessageFlags, MessageId, MessageType};
+    use serde_json as json;
+
+    fn decrypt(ciphertext: &[u8], nonce: &[u8], pk: &PublicKey, sk: &SecretKey) -> Result<Vec<u8>, CryptoError> {
+        decrypt_with_any_key(ciphertext, nonce, pk, Some(sk))
+    }
 
     #[test]
     fn test_randombytes_uniform() {
@@ -213,6 +691,352 @@ mod test {
         assert_eq!(&data[21..45], &blob_nonce.0);
     }
 
+    #[test]
+    fn test_decrypt_roundtrip() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+
+        let encrypted = encrypt(b"hello", MessageType::Text, &other_pub, &own_sec);
+        let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(decrypted, vec![0x01, b'h', b'e', b'l', b'l', b'o']);
+    }
+
+    #[test]
+    fn test_encrypt_with_nonce() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+        let nonce = [7; 24];
+
+        let a = encrypt_with_nonce(b"hello", MessageType::Text, &nonce, 3, &other_pub, &own_sec).unwrap();
+        let b = encrypt_with_nonce(b"hello", MessageType::Text, &nonce, 3, &other_pub, &own_sec).unwrap();
+        assert_eq!(a.nonce, nonce);
+        assert_eq!(a.ciphertext, b.ciphertext);
+        let decrypted = decrypt_raw(&a.ciphertext, &a.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(decrypted, vec![0x01, b'h', b'e', b'l', b'l', b'o', 3, 3, 3]);
+
+        match encrypt_with_nonce(b"hello", MessageType::Text, &nonce, 0, &other_pub, &own_sec) {
+            Err(CryptoError::BadPadding) => (),
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+    }
+
+    /// Key pairs and nonce of the `box` test vectors from the NaCl
+    /// distribution (`tests/box.c` and `tests/box2.c`, also used in
+    /// "Cryptography in NaCl" by D. J. Bernstein).
+    mod nacl_vectors {
+        pub const ALICE_SK: &'static str = "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a";
+        pub const ALICE_PK: &'static str = "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a";
+        pub const BOB_SK: &'static str = "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb";
+        pub const BOB_PK: &'static str = "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f";
+        pub const NONCE: &'static str = "69696ee955b62b73cd62bda875fc73d68219e0036b7a0b37";
+    }
+
+    fn unhex(val: &str) -> Vec<u8> {
+        ::data_encoding::HEXLOWER.decode(val.as_bytes()).unwrap()
+    }
+
+    fn nacl_keys() -> (PublicKey, SecretKey, PublicKey, SecretKey, [u8; 24]) {
+        let mut nonce = [0; 24];
+        nonce.copy_from_slice(&unhex(nacl_vectors::NONCE));
+        (PublicKey::from_slice(&unhex(nacl_vectors::ALICE_PK)).unwrap(),
+         SecretKey::from_slice(&unhex(nacl_vectors::ALICE_SK)).unwrap(),
+         PublicKey::from_slice(&unhex(nacl_vectors::BOB_PK)).unwrap(),
+         SecretKey::from_slice(&unhex(nacl_vectors::BOB_SK)).unwrap(),
+         nonce)
+    }
+
+    #[test]
+    fn test_known_answer_nacl_box() {
+        // The 131 byte message and ciphertext of the NaCl `box` test vector
+        let plaintext = unhex("be075fc53c81f2d5cf141316ebeb0c7b5228c52a4c62cbd44b66849b64244ffce5ecbaaf33bd751a\
+                               1ac728d45e6c61296cdc3c01233561f41db66cce314adb310e3be8250c46f06dceea3a7fa1348057\
+                               e2f6556ad6b1318a024a838f21af1fde048977eb48f59ffd4924ca1c60902e52f0a089bc76897040\
+                               e082f937763848645e0705");
+        let ciphertext = unhex("f3ffc7703f9400e52a7dfb4b3d3305d98e993b9f48681273c29650ba32fc76ce48332ea7164d96a4\
+                                476fb8c531a1186ac0dfc17c98dce87b4da7f011ec48c97271d2c20f9b928fe2270d6fb863d51738\
+                                b48eeee314a7cc8ab932164548e526ae90224368517acfeabd6bb3732bc0e9da99832b61ca01b6de\
+                                56244a9e88d5f9b37973f622a43d14a6599b1f654cb45a74e355a5");
+        let (alice_pk, _, _, bob_sk, nonce) = nacl_keys();
+        assert_eq!(decrypt_raw(&ciphertext, &nonce, &alice_pk, &bob_sk).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn test_known_answer_text_msg() {
+        // Derived from the NaCl key pairs and nonce with an independent
+        // implementation of crypto_box: A text message "Hello Threema",
+        // preceded by the type byte 0x01 and followed by 3 bytes of padding.
+        let expected = unhex("7dc74e465b2599bf9d40d79f90b5ac8f31d601361886c0f265f026c9b47679b619");
+        let (alice_pk, alice_sk, bob_pk, bob_sk, nonce) = nacl_keys();
+
+        let encrypted = encrypt_with_nonce(b"Hello Threema", MessageType::Text, &nonce, 3, &bob_pk, &alice_sk).unwrap();
+        assert_eq!(encrypted.ciphertext, expected);
+
+        let decrypted = decrypt_with_any_key(&expected, &nonce, &alice_pk, vec![&bob_sk]).unwrap();
+        assert_eq!(decrypted, b"\x01Hello Threema".to_vec());
+    }
+
+    #[test]
+    fn test_encrypt_precomputed() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+
+        let key = PrecomputedKey::new(&RecipientKey(other_pub), &own_sec);
+        let encrypted = encrypt_precomputed(b"hello", MessageType::Text, &key);
+        let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(decrypted, vec![0x01, b'h', b'e', b'l', b'l', b'o']);
+    }
+
+    #[test]
+    fn test_decrypt_precomputed() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+
+        let encrypted = encrypt(b"hello", MessageType::Text, &other_pub, &own_sec);
+        let key = PrecomputedKey::new(&RecipientKey(own_pub), &other_sec);
+        let decrypted = decrypt_precomputed(&encrypted.ciphertext, &encrypted.nonce, &key).unwrap();
+        let expected = decrypt_with_any_key(&encrypted.ciphertext, &encrypted.nonce, &own_pub, vec![&other_sec]).unwrap();
+        assert_eq!(decrypted, expected);
+        assert_eq!(decrypted, vec![0x01, b'h', b'e', b'l', b'l', b'o']);
+
+        // The shared key is the same in both directions
+        let own_key = PrecomputedKey::new(&RecipientKey(other_pub), &own_sec);
+        let encrypted = encrypt_precomputed(b"hi", MessageType::Text, &own_key);
+        assert_eq!(decrypt_precomputed(&encrypted.ciphertext, &encrypted.nonce, &key).unwrap(), vec![0x01, b'h', b'i']);
+
+        // Wrong key
+        let (wrong_pub, _) = box_::gen_keypair();
+        let wrong_key = PrecomputedKey::new(&RecipientKey(wrong_pub), &other_sec);
+        match decrypt_precomputed(&encrypted.ciphertext, &encrypted.nonce, &wrong_key) {
+            Err(CryptoError::DecryptionFailed) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_wrong_key() {
+        let (_, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+        let (wrong_pub, _) = box_::gen_keypair();
+
+        let encrypted = encrypt(b"hello", MessageType::Text, &other_pub, &own_sec);
+        match decrypt(&encrypted.ciphertext, &encrypted.nonce, &wrong_pub, &other_sec) {
+            Err(CryptoError::DecryptionFailed) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_with_any_key() {
+        let (sender_pub, sender_sec) = box_::gen_keypair();
+        let (old_pub, old_sec) = box_::gen_keypair();
+        let (_, new_sec) = box_::gen_keypair();
+        let (wrong_pub, _) = box_::gen_keypair();
+
+        let encrypted = encrypt(b"hello", MessageType::Text, &old_pub, &sender_sec);
+        let decrypted = decrypt_with_any_key(&encrypted.ciphertext, &encrypted.nonce, &sender_pub, vec![&new_sec, &old_sec]).unwrap();
+        assert_eq!(decrypted, vec![0x01, b'h', b'e', b'l', b'l', b'o']);
+
+        match decrypt_with_any_key(&encrypted.ciphertext, &encrypted.nonce, &wrong_pub, vec![&new_sec, &old_sec]) {
+            Err(CryptoError::DecryptionFailed) => (),
+            _ => panic!(),
+        }
+        match decrypt_with_any_key(&encrypted.ciphertext, &encrypted.nonce, &sender_pub, vec![]) {
+            Err(CryptoError::DecryptionFailed) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_bad_nonce() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        match decrypt_raw(&[0; 32], &[0; 23], &own_pub, &own_sec) {
+            Err(CryptoError::BadNonce) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_decrypt_bad_padding() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+
+        // Padding amount larger than the data
+        let encrypted = encrypt_raw(&[0x01, 0x02, 0x03], &other_pub, &own_sec);
+        match decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec) {
+            Err(CryptoError::BadPadding) => (),
+            _ => panic!(),
+        }
+
+        // Zero padding amount
+        let encrypted = encrypt_raw(&[0x01, 0x02, 0x00], &other_pub, &own_sec);
+        match decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec) {
+            Err(CryptoError::BadPadding) => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn test_encrypt_delete_msg() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+
+        let message_id = MessageId::new([1, 2, 3, 4, 5, 6, 7, 8]);
+        let encrypted = encrypt_delete_msg(&message_id, &other_pub, &own_sec);
+        let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(decrypted, vec![0x92, 0x09, 1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn test_encrypt_group_control_msgs() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+        let group_id = GroupId::new([1, 2, 3, 4, 5, 6, 7, 8]);
+
+        match encrypt_group_create_msg(&group_id, &["ECHOECHO", "SHORT"], &other_pub, &own_sec) {
+            Err(ApiError::BadRecipient(ref id)) => assert_eq!(id, "SHORT"),
+            Err(other) => panic!("Unexpected error: {:?}", other),
+            Ok(_) => panic!("Short member ID was encoded"),
+        }
+
+        let encrypted = encrypt_group_create_msg(&group_id, &["ECHOECHO", "*OTHERID"], &other_pub, &own_sec).unwrap();
+        let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(decrypted[0], 0x4a);
+        assert_eq!(&decrypted[1..9], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(&decrypted[9..], b"ECHOECHO*OTHERID");
+
+        let encrypted = encrypt_group_rename_msg(&group_id, "Bots", &other_pub, &own_sec);
+        let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(decrypted[0], 0x4b);
+        assert_eq!(&decrypted[9..], b"Bots");
+
+        let blob_key = BlobKey::new([7; 32]);
+        let encrypted = encrypt_group_set_photo_msg(&group_id, &BlobId::new([9; 16]), 0x0102, &blob_key, &other_pub, &own_sec);
+        let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(decrypted.len(), 61);
+        assert_eq!(decrypted[0], 0x50);
+        assert_eq!(&decrypted[1..9], &[1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(&decrypted[9..25], &[9; 16]);
+        assert_eq!(&decrypted[25..29], &[0x02, 0x01, 0, 0]);
+        assert_eq!(&decrypted[29..], &[7; 32]);
+    }
+
+    #[test]
+    fn test_encrypt_file_data() {
+        let (file, thumbnail, key) = encrypt_file_data(b"file", Some(b"thumbnail"));
+        assert_eq!(secretbox::open(&file, &FILE_NONCE, &key.to_secretbox_key()).unwrap(), b"file");
+        assert_eq!(secretbox::open(&thumbnail.unwrap(), &THUMBNAIL_NONCE, &key.to_secretbox_key()).unwrap(), b"thumbnail");
+
+        // Every call uses a new key
+        let (_, thumbnail, other_key) = encrypt_file_data(b"file", None);
+        assert!(thumbnail.is_none());
+        assert!(key != other_key);
+    }
+
+    #[test]
+    fn test_blob_key_separation() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+        let blob_key = BlobKey::generate();
+
+        // The blob round-trips with the blob key only
+        let blob = encrypt_blob(b"file data", &blob_key);
+        assert_eq!(decrypt_blob(&blob, &blob_key).unwrap(), b"file data");
+        assert!(decrypt_blob(&blob, &BlobKey::generate()).is_err());
+        assert_eq!(decrypt_blob_in_place(blob.clone(), &blob_key).unwrap(), b"file data");
+        assert!(decrypt_blob_in_place(blob.clone(), &BlobKey::generate()).is_err());
+        assert!(decrypt_blob_in_place(blob[..15].to_vec(), &blob_key).is_err());
+
+        // The message round-trips with the box keys and contains the blob key
+        let msg = encrypt_file_msg(&BlobId::new([1; 16]), None, &blob_key, &"text/plain".parse().unwrap(),
+                                   None, 9, None, &other_pub, &own_sec);
+        let decrypted = decrypt_raw(&msg.ciphertext, &msg.nonce, &own_pub, &other_sec).unwrap();
+        let data = unpad(decrypted).unwrap();
+        let file: FileMessage = json::from_slice(&data[1..]).unwrap();
+        assert_eq!(file.blob_encryption_key, blob_key);
+    }
+
+    #[test]
+    fn test_encrypt_delivery_receipt() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+
+        let ids = [MessageId::new([1, 2, 3, 4, 5, 6, 7, 8]), MessageId::new([8; 8])];
+        let encrypted = encrypt_delivery_receipt(ReceiptType::Acknowledged, &ids, &other_pub, &own_sec);
+        let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(&decrypted[..10], &[0x80, 0x03, 1, 2, 3, 4, 5, 6, 7, 8]);
+        match decode(&decrypted).unwrap() {
+            Message::DeliveryReceipt(receipt) => {
+                assert_eq!(receipt.receipt_type, ReceiptType::Acknowledged);
+                assert_eq!(receipt.message_ids, ids.to_vec());
+            },
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encrypt_typing_indicator() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+
+        for &typing in &[true, false] {
+            let encrypted = encrypt_typing_indicator(typing, &other_pub, &own_sec);
+            let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+            assert_eq!(decrypted, vec![0x90, typing as u8]);
+            match decode(&decrypted).unwrap() {
+                Message::TypingIndicator(decoded) => assert_eq!(decoded, typing),
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_encrypt_voip_reject() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+
+        let encrypted = encrypt_voip_reject(Some(1234), VoipRejectReason::Busy, &other_pub, &own_sec);
+        let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(&decrypted[..], &b"\x61{\"callId\":1234,\"action\":0,\"rejectReason\":1}"[..]);
+        match decode(&decrypted).unwrap() {
+            msg @ Message::VoipCallAnswer(_) => assert_eq!(msg.voip_call_id(), Some(1234)),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        let encrypted = encrypt_voip_reject(None, VoipRejectReason::Rejected, &other_pub, &own_sec);
+        let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(&decrypted[..], &b"\x61{\"action\":0,\"rejectReason\":3}"[..]);
+    }
+
+    #[test]
+    fn test_encrypt_voip_hangup() {
+        let (own_pub, own_sec) = box_::gen_keypair();
+        let (other_pub, other_sec) = box_::gen_keypair();
+
+        let encrypted = encrypt_voip_hangup(Some(42), &other_pub, &own_sec);
+        let decrypted = decrypt(&encrypted.ciphertext, &encrypted.nonce, &own_pub, &other_sec).unwrap();
+        assert_eq!(&decrypted[..], &b"\x63{\"callId\":42}"[..]);
+        match decode(&decrypted).unwrap() {
+            msg @ Message::VoipCallHangup(_) => assert_eq!(msg.voip_call_id(), Some(42)),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encrypt_text_msg_empty() {
+        let own_sec = SecretKey([113,146,154,1,241,143,18,181,240,174,72,16,247,83,161,29,215,123,130,243,235,222,137,151,107,162,47,119,98,145,68,146]);
+        let other_pub = PublicKey([153,153,204,118,225,119,78,112,88,6,167,2,67,73,254,255,96,134,225,8,36,229,124,219,43,50,241,185,244,236,55,77]);
+        let api = ApiBuilder::new("*3MAGWID", "1234")
+            .with_private_key(own_sec)
+            .into_e2e()
+            .unwrap();
+        let recipient_key = RecipientKey(other_pub);
+        match api.encrypt_text_msg("", &recipient_key) {
+            Err(ApiError::EmptyMessage) => (),
+            _ => panic!(),
+        }
+        assert!(api.encrypt_text_msg("x", &recipient_key).is_ok());
+    }
+
     #[test]
     fn test_recipient_key_from_publickey() {
         let bytes = [0; 32];
@@ -226,6 +1050,27 @@ mod test {
         let _: RecipientKey = bytes.into();
     }
 
+    #[test]
+    fn test_encrypted_message_from_hex() {
+        let msg = EncryptedMessage::from_hex("000102030405060708090a0b0c0d0e0f10111213141516FF", "00ff10").unwrap();
+        assert_eq!(msg.nonce[1], 1);
+        assert_eq!(msg.nonce[23], 0xff);
+        assert_eq!(msg.ciphertext, vec![0x00, 0xff, 0x10]);
+
+        match EncryptedMessage::from_hex("000102", "00ff10") {
+            Err(ApiError::CryptoError(CryptoError::BadNonce)) => (),
+            other => panic!("Unexpected result: {:?}", other.map(|m| m.ciphertext)),
+        }
+        match EncryptedMessage::from_hex("000102030405060708090a0b0c0d0e0f1011121314151617", "00ff1") {
+            Err(ApiError::ParseError(_)) => (),
+            other => panic!("Unexpected result: {:?}", other.map(|m| m.ciphertext)),
+        }
+        match EncryptedMessage::from_hex("zz0102030405060708090a0b0c0d0e0f1011121314151617", "00") {
+            Err(ApiError::ParseError(_)) => (),
+            other => panic!("Unexpected result: {:?}", other.map(|m| m.ciphertext)),
+        }
+    }
+
     #[test]
     fn test_recipient_key_from_bytes() {
         let bytes = [0; 32];
@@ -266,6 +1111,34 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_recipient_key_fingerprint() {
+        let mut bytes = [0; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let recipient = RecipientKey::from(bytes);
+        assert_eq!(recipient.fingerprint(), "630dcd2966c4336691125448bbb25b4f");
+        assert!(recipient.matches_fingerprint("630dcd2966c4336691125448bbb25b4f"));
+        assert!(recipient.matches_fingerprint("630DCD29 66C43366\n91125448 BBB25B4F"));
+        assert!(!recipient.matches_fingerprint("630dcd2966c4336691125448bbb25b4e"));
+        assert!(!recipient.matches_fingerprint("630dcd2966c43366"));
+    }
+
+    #[test]
+    fn test_hex_output_lowercase() {
+        let key_hex = "FF000000000000000000000000000000000000000000000000000000000000EE";
+        let string: String = RecipientKey::from_str(key_hex).unwrap().into();
+        assert_eq!(string, key_hex.to_lowercase());
+
+        let nonce_hex = "000102030405060708090A0B0C0D0E0F1011121314151617";
+        let msg = EncryptedMessage::from_hex(nonce_hex, "00FF10").unwrap();
+        let prepared = PreparedMessage::new("ECHOECHO", msg, MessageFlags::empty());
+        let serialized = json::to_string(&prepared).unwrap();
+        assert!(serialized.contains(&format!("\"{}\"", nonce_hex.to_lowercase())));
+        assert!(serialized.contains(r#""00ff10""#));
+    }
+
     #[test]
     fn test_recipient_key_as_string() {
         let mut bytes = [0; 32];
@@ -1,8 +1,66 @@
 //! Error types used in this library.
 
-use std::io::Error as IoError;
+use std::env::VarError;
+use std::error::Error;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::PathBuf;
+use std::time::Duration;
+use hyper::Error as HyperError;
 use reqwest::Error as ReqwestError;
 
+
+/// Replace the values of `secret` parameters in a string (e.g. an URL) with
+/// `***`.
+pub(crate) fn redact_secret(val: &str) -> String {
+    let mut redacted = String::with_capacity(val.len());
+    let mut rest = val;
+    while let Some(pos) = rest.find("secret=") {
+        let start = pos + "secret=".len();
+        redacted.push_str(&rest[..start]);
+        redacted.push_str("***");
+        rest = &rest[start..];
+        let end = rest.find(|c: char| c == '&' || c == '#' || c == '"' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        rest = &rest[end..];
+    }
+    redacted.push_str(rest);
+    redacted
+}
+
+/// An error returned by the HTTP client.
+///
+/// Request URLs contain the gateway secret, so the `Display` and `Debug`
+/// implementations of this type remove it.
+pub struct RedactedError(ReqwestError);
+
+impl RedactedError {
+    /// Return the underlying error.
+    ///
+    /// Note that the underlying error may contain the gateway secret.
+    pub fn inner(&self) -> &ReqwestError {
+        &self.0
+    }
+}
+
+impl fmt::Display for RedactedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", redact_secret(&self.0.to_string()))
+    }
+}
+
+impl fmt::Debug for RedactedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RedactedError({})", redact_secret(&format!("{:?}", self.0)))
+    }
+}
+
+impl Error for RedactedError {
+    fn description(&self) -> &str {
+        "HTTP client error"
+    }
+}
+
 quick_error! {
     /// Errors when interacting with the API.
     #[derive(Debug)]
@@ -19,12 +77,35 @@ quick_error! {
         /// Target ID not found
         IdNotFound {}
 
-        /// Message is too long
+        /// Message is too long. Length limits are in bytes (of the UTF-8
+        /// encoded text), not in characters.
         MessageTooLong {}
 
+        /// Message is empty
+        EmptyMessage {}
+
+        /// The recipient does not have the capability required to receive
+        /// the message (e.g. `file`)
+        MissingCapability(id: String, capability: String) {
+            display("Recipient {} lacks capability: {}", id, capability)
+        }
+
+        /// Message text contains a control character (other than newline,
+        /// carriage return or tab) at the specified byte index
+        ControlCharacter(c: char, index: usize) {
+            display("Text contains control character U+{:04X} at byte {}", *c as u32, index)
+        }
+
         /// Internal server error
         ServerError {}
 
+        /// The gateway is temporarily unavailable (e.g. during maintenance).
+        /// The request can be retried later, after the delay requested by the
+        /// gateway if one is set.
+        ServiceUnavailable(retry_after: Option<Duration>) {
+            display("Service temporarily unavailable")
+        }
+
         /// Wrong hash length
         BadHashLength {}
 
@@ -34,12 +115,60 @@ quick_error! {
         /// Invalid blob ID
         BadBlobId {}
 
+        /// Invalid message ID
+        BadMessageId {}
+
+        /// Sending to this recipient is not allowed by the configured
+        /// allowlist or denylist
+        RecipientNotAllowed(recipient: String) {
+            display("Sending to {} is not allowed", recipient)
+        }
+
+        /// The recipient could not be parsed
+        BadRecipient(input: String) {
+            display("Invalid recipient: {}", input)
+        }
+
+        /// An additional request parameter uses a key that is set by this
+        /// library
+        ReservedParameter(key: String) {
+            display("Parameter \"{}\" is reserved", key)
+        }
+
+        /// The incoming message is older than the configured maximum age
+        MessageTooOld {}
+
+        /// The date of the incoming message is too far in the future
+        MessageFromFuture {}
+
+        /// The MAC of the incoming message is invalid
+        InvalidMac {}
+
+        /// The send limit configured with
+        /// [`ApiBuilder::with_send_limit`](../struct.ApiBuilder.html#method.with_send_limit)
+        /// has been reached
+        SpendLimitReached(limit: usize) {
+            display("Send limit of {} messages reached", limit)
+        }
+
+        /// The request was cancelled via a
+        /// [`CancellationToken`](../struct.CancellationToken.html)
+        Cancelled {}
+
         /// Error when sending request (via reqwest)
-        RequestError(err: ReqwestError) {
-            from()
+        RequestError(err: RedactedError) {
             display("RequestError: {}", err)
         }
 
+        /// The connection to the gateway failed, was reset or timed out.
+        ///
+        /// Unlike other request errors, this is usually a transient problem
+        /// and the request can be retried, see
+        /// [`is_retryable`](#method.is_retryable).
+        Network(err: RedactedError) {
+            display("Network error: {}", err)
+        }
+
         /// Error when reading response
         IoError(err: IoError) {
             from()
@@ -51,6 +180,52 @@ quick_error! {
             display("ParseError: {}", msg)
         }
 
+        /// Error while encrypting or decrypting a message
+        CryptoError(err: CryptoError) {
+            from()
+            display("CryptoError: {}", err)
+        }
+
+        /// Sending a message in multiple parts failed after some parts were
+        /// already sent. Contains the IDs of the sent messages and the error.
+        PartiallySent(sent: Vec<String>, err: Box<ApiError>) {
+            display("Sending failed after {} part(s) were sent: {}", sent.len(), err)
+        }
+
+        /// The response body is not valid UTF-8. Contains the beginning of
+        /// the body, with invalid sequences replaced.
+        InvalidResponseEncoding(snippet: String) {
+            display("Response body is not valid UTF-8: \"{}\"", snippet)
+        }
+
+        /// The message does not contain media (an image or a file)
+        NotMedia {}
+
+        /// The size of downloaded media does not match the size declared in
+        /// the message
+        MediaSizeMismatch(expected: u32, actual: usize) {
+            display("Media size mismatch: Expected {} bytes, got {} bytes", expected, actual)
+        }
+
+        /// The response body is shorter than announced, e.g. because the
+        /// connection was closed prematurely.
+        TruncatedResponse {}
+
+        /// The chunk size for splitting a file is 0
+        InvalidChunkSize {}
+
+        /// A file has more chunks than allowed (the number of chunks and the
+        /// limit)
+        TooManyChunks(count: u64, max: u32) {
+            display("Too many chunks: {}, at most {} allowed", count, max)
+        }
+
+        /// A chunk belongs to a new transfer, but the maximum number of
+        /// pending transfers has been reached
+        TooManyPendingTransfers(max: usize) {
+            display("Too many pending transfers, at most {} allowed", max)
+        }
+
         /// Other
         Other(msg: String) {
             display("{}", msg)
@@ -58,6 +233,62 @@ quick_error! {
     }
 }
 
+impl From<ReqwestError> for ApiError {
+    fn from(e: ReqwestError) -> Self {
+        if is_network_error(&e) {
+            ApiError::Network(RedactedError(e))
+        } else {
+            ApiError::RequestError(RedactedError(e))
+        }
+    }
+}
+
+impl ApiError {
+    /// Whether the request failed because of a transient problem and may
+    /// succeed if it is retried later.
+    ///
+    /// This is the case for
+    /// [`Network`](#variant.Network) and
+    /// [`ServiceUnavailable`](#variant.ServiceUnavailable) errors. Note that
+    /// a message may have been sent even if the connection was reset before
+    /// the response arrived, so retrying a send can deliver it twice.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            ApiError::Network(_) | ApiError::ServiceUnavailable(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Whether the HTTP client error was caused by a failed, reset or timed out
+/// connection.
+fn is_network_error(err: &ReqwestError) -> bool {
+    let inner = match err.get_ref() {
+        Some(inner) => inner,
+        None => return false,
+    };
+    // I/O errors are either returned directly (timeouts) or wrapped in a
+    // hyper error.
+    let io_error = match inner.downcast_ref::<HyperError>() {
+        Some(&HyperError::Io(ref e)) => Some(e),
+        Some(&HyperError::Incomplete) | Some(&HyperError::Closed) | Some(&HyperError::Timeout) => return true,
+        Some(_) => None,
+        None => inner.downcast_ref::<IoError>(),
+    };
+    match io_error.map(IoError::kind) {
+        Some(ErrorKind::ConnectionRefused) |
+        Some(ErrorKind::ConnectionReset) |
+        Some(ErrorKind::ConnectionAborted) |
+        Some(ErrorKind::NotConnected) |
+        Some(ErrorKind::BrokenPipe) |
+        Some(ErrorKind::UnexpectedEof) |
+        Some(ErrorKind::TimedOut) |
+        // reqwest reports timeouts as `WouldBlock` on unix
+        Some(ErrorKind::WouldBlock) => true,
+        _ => false,
+    }
+}
+
 quick_error! {
     /// Crypto related errors.
     #[derive(Debug)]
@@ -66,6 +297,50 @@ quick_error! {
         BadKey(msg: String) {
             from()
         }
+        /// Bad nonce
+        BadNonce {}
+        /// The ciphertext could not be decrypted or authenticated
+        DecryptionFailed {}
+        /// The decrypted data contains invalid padding
+        BadPadding {}
+    }
+}
+
+quick_error! {
+    /// Problems found by
+    /// [`validate_e2e_send`](../fn.validate_e2e_send.html).
+    ///
+    /// Some variants correspond to variants of
+    /// [`ApiError`](enum.ApiError.html), but `ApiError` can't be cloned or
+    /// compared (it wraps I/O and HTTP errors), and its `MessageTooLong`
+    /// variant doesn't say by how much the limit was exceeded. A separate
+    /// enum keeps validation results easy to compare and report.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum ValidationError {
+        /// The recipient is not a valid Threema ID (8 uppercase alphanumeric
+        /// characters, the first one may be `*`)
+        BadRecipientId(id: String) {
+            display("Invalid recipient ID: {:?}", id)
+        }
+        /// The public key of the recipient is not a 32 byte hex string
+        BadPublicKey(msg: String) {
+            display("Invalid public key: {}", msg)
+        }
+        /// The private key is not a 32 byte hex string
+        BadPrivateKey(msg: String) {
+            display("Invalid private key: {}", msg)
+        }
+        /// The message is empty
+        EmptyMessage {}
+        /// The message is longer than the maximum length (both in bytes)
+        MessageTooLong(len: usize, max: usize) {
+            display("Message is too long: {} bytes, at most {} allowed", len, max)
+        }
+        /// The message contains a control character at the specified byte
+        /// index
+        ControlCharacter(c: char, index: usize) {
+            display("Text contains control character U+{:04X} at byte {}", *c as u32, index)
+        }
     }
 }
 
@@ -73,9 +348,87 @@ quick_error! {
     /// Errors when interacting with the [`ApiBuilder`](struct.ApiBuilder.html).
     #[derive(Debug)]
     pub enum ApiBuilderError {
+        /// The gateway ID is not a `*` followed by 7 uppercase alphanumeric characters.
+        InvalidGatewayId(id: String) {
+            display("Invalid gateway ID (must be * followed by 7 uppercase alphanumeric characters): {:?}", id)
+        }
         /// No private key has been set.
         MissingKey {}
+        /// The maximum number of concurrent uploads is 0.
+        InvalidUploadLimit {
+            display("The maximum number of concurrent uploads must be at least 1")
+        }
         /// Invalid libsodium private key.
         InvalidKey(msg: String) {}
+        /// Invalid proxy URL.
+        InvalidProxy(err: ReqwestError) {
+            display("Invalid proxy URL: {}", err)
+        }
+        /// Invalid root certificate.
+        InvalidCertificate(err: ReqwestError) {
+            display("Invalid certificate: {}", err)
+        }
+        /// The HTTP client could not be initialized.
+        ClientError(err: ReqwestError) {
+            display("Could not initialize HTTP client: {}", err)
+        }
+        /// A credentials file could not be read.
+        CredentialsFile(path: PathBuf, err: IoError) {
+            display("Could not read credentials file {}: {}", path.display(), err)
+        }
+        /// A credentials environment variable could not be read.
+        CredentialsEnv(name: String, err: VarError) {
+            display("Could not read environment variable {}: {}", name, err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::Client;
+    use super::*;
+
+    #[test]
+    fn test_redact_secret() {
+        assert_eq!(redact_secret("https://example.com/x?from=*ID&secret=abc123"), "https://example.com/x?from=*ID&secret=***");
+        assert_eq!(redact_secret("?secret=abc&from=x and secret=def"), "?secret=***&from=x and secret=***");
+        assert_eq!(redact_secret("no secrets here"), "no secrets here");
+    }
+
+    #[test]
+    fn test_request_error_redacted() {
+        // Nothing listens on port 1, so the request fails
+        let err = Client::new()
+            .get("http://127.0.0.1:1/credits?from=*3MAGWID&secret=topsecret123")
+            .send()
+            .unwrap_err();
+        assert!(err.to_string().contains("topsecret123"));
+
+        let err: ApiError = err.into();
+        assert!(!err.to_string().contains("topsecret123"));
+        assert!(!format!("{:?}", err).contains("topsecret123"));
+    }
+
+    #[test]
+    fn test_network_error() {
+        // A refused connection is a network error
+        let err: ApiError = Client::new().get("http://127.0.0.1:1/credits").send().unwrap_err().into();
+        match err {
+            ApiError::Network(_) => (),
+            ref other => panic!("Unexpected error: {:?}", other),
+        }
+        assert!(err.is_retryable());
+
+        // An invalid URL is not
+        let err: ApiError = Client::new().get("http://[invalid/").send().unwrap_err().into();
+        match err {
+            ApiError::RequestError(_) => (),
+            ref other => panic!("Unexpected error: {:?}", other),
+        }
+        assert!(!err.is_retryable());
+
+        assert!(ApiError::ServiceUnavailable(None).is_retryable());
+        assert!(!ApiError::BadCredentials.is_retryable());
     }
+
 }
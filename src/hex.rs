@@ -0,0 +1,94 @@
+//! Encoding of hex strings and decoding of fixed length hex strings.
+//!
+//! All hex values sent to the gateway or returned by this crate are
+//! lowercase, some gateway parameters are case sensitive. Input is accepted
+//! in upper- and lowercase.
+
+use std::fmt;
+
+use data_encoding::{DecodeError, HEXLOWER, HEXLOWER_PERMISSIVE};
+
+
+/// An error when decoding a fixed length hex string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HexError {
+    /// The string is not valid hex.
+    Invalid(DecodeError),
+    /// The decoded data does not have the expected length (in bytes).
+    Length { expected: usize, actual: usize },
+}
+
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HexError::Invalid(ref e) => write!(f, "{}", e),
+            HexError::Length { expected, actual } => {
+                write!(f, "Invalid length: expected {} bytes, got {}", expected, actual)
+            },
+        }
+    }
+}
+
+/// Encode bytes as a lowercase hex string.
+pub(crate) fn encode_hex(bytes: &[u8]) -> String {
+    HEXLOWER.encode(bytes)
+}
+
+/// Decode a hex string (upper- or lowercase) into a byte array.
+///
+/// The length of the decoded data must match the length of the array `T`
+/// exactly, e.g. `[u8; 16]` for a blob ID.
+pub(crate) fn decode_hex_fixed<T: Default + AsMut<[u8]>>(s: &str) -> Result<T, HexError> {
+    let bytes = HEXLOWER_PERMISSIVE.decode(s.as_bytes()).map_err(HexError::Invalid)?;
+    let mut arr = T::default();
+    {
+        let target = arr.as_mut();
+        if bytes.len() != target.len() {
+            return Err(HexError::Length { expected: target.len(), actual: bytes.len() });
+        }
+        target.copy_from_slice(&bytes);
+    }
+    Ok(arr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_hex_fixed, encode_hex, HexError};
+
+    #[test]
+    fn test_decode_hex_fixed() {
+        let id: [u8; 8] = decode_hex_fixed("0102030405060708").unwrap();
+        assert_eq!(id, [1, 2, 3, 4, 5, 6, 7, 8]);
+        let id: [u8; 4] = decode_hex_fixed("00FFabCD").unwrap();
+        assert_eq!(id, [0x00, 0xff, 0xab, 0xcd]);
+        let empty: [u8; 0] = decode_hex_fixed("").unwrap();
+        assert!(empty.is_empty());
+
+        assert_eq!(decode_hex_fixed::<[u8; 8]>("01020304050607"),
+                   Err(HexError::Length { expected: 8, actual: 7 }));
+        assert_eq!(decode_hex_fixed::<[u8; 8]>("010203040506070809"),
+                   Err(HexError::Length { expected: 8, actual: 9 }));
+        match decode_hex_fixed::<[u8; 8]>("010203040506070g") {
+            Err(HexError::Invalid(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match decode_hex_fixed::<[u8; 8]>("010203040506070") {
+            Err(HexError::Invalid(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encode_hex() {
+        assert_eq!(encode_hex(&[0x00, 0xff, 0xab, 0xcd]), "00ffabcd");
+        assert_eq!(encode_hex(&[]), "");
+        let decoded: [u8; 4] = decode_hex_fixed("00FFABCD").unwrap();
+        assert_eq!(encode_hex(&decoded), "00ffabcd");
+    }
+
+    #[test]
+    fn test_hex_error_display() {
+        let err = HexError::Length { expected: 16, actual: 15 };
+        assert_eq!(err.to_string(), "Invalid length: expected 16 bytes, got 15");
+    }
+}
@@ -21,7 +21,7 @@
 //! let text = "Very secret message!";
 //!
 //! // Send
-//! let api = ApiBuilder::new(from, secret).into_simple();
+//! let api = ApiBuilder::new(from, secret).into_simple().unwrap();
 //! match api.send(&to, &text) {
 //!     Ok(msg_id) => println!("Sent. Message id is {}.", msg_id),
 //!     Err(e) => println!("Could not send message: {:?}", e),
@@ -51,7 +51,7 @@
 //!
 //! // Encrypt
 //! let recipient_key = RecipientKey::from_str(&public_key).unwrap();
-//! let encrypted = api.encrypt_text_msg(text, &recipient_key);
+//! let encrypted = api.encrypt_text_msg(text, &recipient_key).unwrap();
 //!
 //! // Send
 //! match api.send(&to, &encrypted) {
@@ -63,29 +63,53 @@
 //! For more examples, see the
 //! [`examples/`](https://github.com/dbrgn/threema-gateway-rs/tree/master/examples) directory.
 
+// The `ApiError` enum is too large for the default recursion limit of
+// `quick_error!`.
+#![recursion_limit = "256"]
+
+#[macro_use] extern crate bitflags;
 extern crate byteorder;
 extern crate data_encoding;
+extern crate hyper;
 #[macro_use] extern crate log;
 extern crate mime;
+#[cfg(feature = "mime_guess")] extern crate mime_guess;
 #[macro_use] extern crate quick_error;
 extern crate reqwest;
 extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
+extern crate serde_urlencoded;
 extern crate sodiumoxide;
 
 mod api;
+pub mod chunked;
 mod connection;
+mod credentials;
 mod crypto;
+mod hex;
 mod lookup;
+mod message;
+pub mod middleware;
+mod receive;
+mod semaphore;
+#[cfg(test)] mod test_server;
 mod types;
+mod validate;
 pub mod errors;
 
-pub use api::{ApiBuilder, E2eApi, SimpleApi};
-pub use connection::{Recipient};
-pub use crypto::{EncryptedMessage, RecipientKey};
-pub use lookup::{LookupCriterion, Capabilities};
-pub use types::{MessageType, BlobId};
+pub use api::{ApiBuilder, CancellationToken, CapabilityPolicy, E2eApi, SimpleApi, UploadStats};
+pub use api::{E2eMessageBuilder, SimpleMessageBuilder, TypingIndicator};
+pub use connection::{BlobInfo, RawResponse, Recipient, RedirectPolicy, SentMessage, normalize_newlines, split_text, text_byte_length, MAX_SIMPLE_MESSAGE_LEN};
+pub use credentials::{Credentials, CredentialSource, DirCredentials, EnvCredentials};
+pub use crypto::{EncryptedMessage, PreparedMessage, RecipientKey, PrecomputedKey, encrypt_blob, decrypt_blob};
+pub use crypto::{encrypt_thumbnail_blob, decrypt_thumbnail_blob, decrypt_blob_in_place};
+pub use lookup::{LookupCriterion, Capabilities, GatewayStatus, hash_phone, hash_email, normalize_email};
+pub use message::{Message, ImageMessage, GroupTextMessage, DeliveryReceipt, ReceiptType, QuotedText, VoipRejectReason};
+pub use middleware::RequestMiddleware;
+pub use receive::IncomingMessage;
+pub use types::{MessageType, MessageFlags, BlobId, BlobKey, BlobDownloadMode, MessageId, GroupId, FileMessage};
+pub use validate::validate_e2e_send;
 
 const MSGAPI_URL: &'static str = "https://msgapi.threema.ch";
 
@@ -1,13 +1,32 @@
 //! ID and public key lookups.
+//!
+//! None of the lookup endpoints of the gateway support pagination. Their
+//! responses are small (a single ID, key or list of capabilities), so they
+//! are read completely and returned as a whole.
+//!
+//! ## Presence
+//!
+//! The gateway does not provide presence or last-seen information: there is
+//! no endpoint for it, and the lookups do not indicate whether an ID is
+//! still in use. The most reliable signal available are delivery receipts of
+//! type `Received`, which the Threema app of the recipient sends when a
+//! message arrives (unless the recipient disabled them). Their latency says
+//! little, since the app may be offline for a while, but an ID that has not
+//! sent any receipt for weeks is likely inactive. An ID that was revoked is
+//! no longer found by
+//! [`lookup_pubkey`](../struct.E2eApi.html#method.lookup_pubkey), and
+//! messages to it are rejected with
+//! [`ApiError::BadSenderOrRecipient`](../errors/enum.ApiError.html#variant.BadSenderOrRecipient).
 
 use std::fmt;
 use std::str;
-use std::io::Read;
 
-use reqwest::Client;
+use reqwest::{Method, StatusCode};
+use sodiumoxide::crypto::auth::hmacsha256;
 
-use ::connection::map_response_code;
+use ::connection::{Connection, build_form_params, map_response, read_response_body};
 use ::errors::ApiError;
+use ::hex::encode_hex;
 
 
 /// Different ways to look up a Threema ID in the directory.
@@ -22,7 +41,8 @@ pub enum LookupCriterion {
     PhoneHash(String),
     /// The email address.
     Email(String),
-    /// The lowercased and whitespace-trimmed email address must be hashed with
+    /// The email address, normalized with
+    /// [`normalize_email`](fn.normalize_email.html), must be hashed with
     /// HMAC-SHA256. The HMAC key is
     /// `30a5500fed9701fa6defdb610841900febb8e430881f7ad816826264ec09bad7`
     /// (in hexadecimal).
@@ -40,8 +60,55 @@ impl fmt::Display for LookupCriterion {
     }
 }
 
+/// The HMAC key used for hashing phone numbers.
+const PHONE_HASH_KEY: [u8; 32] = [
+    0x85, 0xad, 0xf8, 0x22, 0x69, 0x53, 0xf3, 0xd9, 0x6c, 0xfd, 0x5d, 0x09, 0xbf, 0x29, 0x55, 0x5e,
+    0xb9, 0x55, 0xfc, 0xd8, 0xaa, 0x5e, 0xc4, 0xf9, 0xfc, 0xd8, 0x69, 0xe2, 0x58, 0x37, 0x07, 0x23,
+];
+
+/// The HMAC key used for hashing e-mail addresses.
+const EMAIL_HASH_KEY: [u8; 32] = [
+    0x30, 0xa5, 0x50, 0x0f, 0xed, 0x97, 0x01, 0xfa, 0x6d, 0xef, 0xdb, 0x61, 0x08, 0x41, 0x90, 0x0f,
+    0xeb, 0xb8, 0xe4, 0x30, 0x88, 0x1f, 0x7a, 0xd8, 0x16, 0x82, 0x62, 0x64, 0xec, 0x09, 0xba, 0xd7,
+];
+
+fn hmac_hex(key: [u8; 32], data: &str) -> String {
+    let tag = hmacsha256::authenticate(data.as_bytes(), &hmacsha256::Key(key));
+    encode_hex(&tag.0)
+}
+
+/// Hash a phone number for use with
+/// [`LookupCriterion::PhoneHash`](enum.LookupCriterion.html#variant.PhoneHash).
+///
+/// All characters except digits (e.g. a leading `+`, spaces or dashes) are
+/// removed before hashing, so the number should be in E.164 format.
+pub fn hash_phone(phone: &str) -> String {
+    let normalized: String = phone.chars().filter(|c| c.is_digit(10)).collect();
+    hmac_hex(PHONE_HASH_KEY, &normalized)
+}
+
+/// Normalize an e-mail address the way Threema does before hashing it.
+///
+/// Leading and trailing whitespace is removed and the address is lowercased
+/// (including non-ASCII characters). Nothing else is changed: Threema does
+/// not apply provider specific rules, so dots or `+` suffixes in the local
+/// part (e.g. `first.last+tag@gmail.com`) are kept. Removing them would
+/// produce a hash that does not match the one stored for the address.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Hash an e-mail address for use with
+/// [`LookupCriterion::EmailHash`](enum.LookupCriterion.html#variant.EmailHash).
+///
+/// The address is normalized with
+/// [`normalize_email`](fn.normalize_email.html) before hashing.
+pub fn hash_email(email: &str) -> String {
+    hmac_hex(EMAIL_HASH_KEY, &normalize_email(email))
+}
+
 /// A struct containing flags according to the capabilities of a Threema ID.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Capabilities {
     /// Whether the ID can receive text messages.
     pub text: bool,
@@ -114,98 +181,121 @@ impl Capabilities {
             _ => self.other.contains(&capability.to_lowercase()),
         }
     }
+
+    /// Return whether the ID supports group messaging.
+    pub fn supports_groups(&self) -> bool {
+        self.can("group")
+    }
+}
+
+/// Status information about the own gateway ID.
+///
+/// The gateway API has no endpoint returning metadata about the own ID, so
+/// this is assembled from a capability lookup and a credits lookup. A
+/// successful status query also confirms that the credentials are valid.
+#[derive(Debug, PartialEq)]
+pub struct GatewayStatus {
+    /// The own gateway ID.
+    pub id: String,
+    /// The capabilities of the own gateway ID.
+    pub capabilities: Capabilities,
+    /// The remaining gateway credits.
+    pub credits: i64,
 }
 
 /// Fetch the public key for the specified Threema ID.
 pub(crate) fn lookup_pubkey(
-    endpoint: &str,
+    conn: &Connection,
     our_id: &str,
     their_id: &str,
     secret: &str,
 ) -> Result<String, ApiError> {
     // Build URL
-    let url = format!("{}/pubkeys/{}?from={}&secret={}", endpoint, their_id, our_id, secret);
+    let params = build_form_params(vec![("from", our_id), ("secret", secret)]);
+    let url = format!("{}/pubkeys/{}?{}", conn.endpoint, their_id, params);
 
     debug!("Looking up public key for {}", their_id);
 
     // Send request
-    let mut res = Client::new().get(&url).send()?;
-    try!(map_response_code(&res.status(), None));
+    let mut res = conn.send(&mut conn.request(Method::Get, &url))?;
+    try!(map_response(&res, None));
 
     // Read and return response body
-    let mut body = String::new();
-    try!(res.read_to_string(&mut body));
+    let body = read_response_body(conn, &mut res)?;
     Ok(body)
 }
 
 /// Look up an ID in the Threema directory.
+///
+/// Returns `None` if no ID is linked to the phone number or e-mail address.
 pub(crate) fn lookup_id(
-    endpoint: &str,
+    conn: &Connection,
     criterion: &LookupCriterion,
     our_id: &str,
     secret: &str,
-) -> Result<String, ApiError> {
+) -> Result<Option<String>, ApiError> {
     // Build URL
     let url_base = match criterion {
-        &LookupCriterion::Phone(ref val) => format!("{}/lookup/phone/{}", endpoint, val),
-        &LookupCriterion::PhoneHash(ref val) => format!("{}/lookup/phone_hash/{}", endpoint, val),
-        &LookupCriterion::Email(ref val) => format!("{}/lookup/email/{}", endpoint, val),
-        &LookupCriterion::EmailHash(ref val) => format!("{}/lookup/email_hash/{}", endpoint, val),
+        &LookupCriterion::Phone(ref val) => format!("{}/lookup/phone/{}", conn.endpoint, val),
+        &LookupCriterion::PhoneHash(ref val) => format!("{}/lookup/phone_hash/{}", conn.endpoint, val),
+        &LookupCriterion::Email(ref val) => format!("{}/lookup/email/{}", conn.endpoint, val),
+        &LookupCriterion::EmailHash(ref val) => format!("{}/lookup/email_hash/{}", conn.endpoint, val),
     };
-    let url = format!("{}?from={}&secret={}", url_base, our_id, secret);
+    let url = format!("{}?{}", url_base, build_form_params(vec![("from", our_id), ("secret", secret)]));
 
     debug!("Looking up id key for {}", criterion);
 
     // Send request
-    let mut res = Client::new().get(&url).send()?;
-    try!(map_response_code(&res.status(), Some(ApiError::BadHashLength)));
+    let mut res = conn.send(&mut conn.request(Method::Get, &url))?;
+    if res.status() == StatusCode::NotFound {
+        return Ok(None);
+    }
+    try!(map_response(&res, Some(ApiError::BadHashLength)));
 
     // Read and return response body
-    let mut body = String::new();
-    try!(res.read_to_string(&mut body));
-    Ok(body)
+    let body = read_response_body(conn, &mut res)?;
+    Ok(Some(body))
 }
 
 /// Look up remaining gateway credits.
 pub(crate) fn lookup_credits(
-    endpoint: &str,
+    conn: &Connection,
     our_id: &str,
     secret: &str,
 ) -> Result<i64, ApiError> {
-    let url = format!("{}/credits?from={}&secret={}", endpoint, our_id, secret);
+    let url = format!("{}/credits?{}", conn.endpoint, build_form_params(vec![("from", our_id), ("secret", secret)]));
 
     debug!("Looking up remaining credits");
 
     // Send request
-    let mut res = Client::new().get(&url).send()?;
-    try!(map_response_code(&res.status(), None));
+    let mut res = conn.send(&mut conn.request(Method::Get, &url))?;
+    try!(map_response(&res, None));
 
     // Read, parse and return response body
-    let mut body = String::new();
-    try!(res.read_to_string(&mut body));
+    let body = read_response_body(conn, &mut res)?;
     body.trim().parse::<i64>()
         .map_err(|_| ApiError::ParseError(format!("Could not parse response body as i64: \"{}\"", body)))
 }
 
 /// Look up ID capabilities.
 pub(crate) fn lookup_capabilities(
-    endpoint: &str,
+    conn: &Connection,
     our_id: &str,
     their_id: &str,
     secret: &str,
 ) -> Result<Capabilities, ApiError> {
     // Build URL
-    let url = format!("{}/capabilities/{}?from={}&secret={}", endpoint, their_id, our_id, secret);
+    let params = build_form_params(vec![("from", our_id), ("secret", secret)]);
+    let url = format!("{}/capabilities/{}?{}", conn.endpoint, their_id, params);
 
     debug!("Looking up capabilities for {}", their_id);
 
     // Send request
-    let mut res = Client::new().get(&url).send()?;
-    try!(map_response_code(&res.status(), Some(ApiError::BadHashLength)));
+    let mut res = conn.send(&mut conn.request(Method::Get, &url))?;
+    try!(map_response(&res, Some(ApiError::BadHashLength)));
 
     // Read response body
-    let mut body = String::new();
-    try!(res.read_to_string(&mut body));
+    let body = read_response_body(conn, &mut res)?;
 
     // Parse response body
     body.parse()
@@ -213,7 +303,86 @@ pub(crate) fn lookup_capabilities(
 
 #[cfg(test)]
 mod tests {
-    use super::{LookupCriterion, Capabilities};
+    use ::errors::ApiError;
+    use ::test_server::TestServer;
+    use super::{LookupCriterion, Capabilities, hash_phone, hash_email, normalize_email};
+    use super::{lookup_pubkey, lookup_id, lookup_credits, lookup_capabilities};
+
+    #[test]
+    fn test_lookup_pubkey_request() {
+        let server = TestServer::start(vec![(200, "abcd"), (404, "")]);
+        let conn = server.conn();
+        assert_eq!(lookup_pubkey(&conn, "*3MAGWID", "ECHOECHO", "secret").unwrap(), "abcd");
+        assert_eq!(server.requests()[0].method, "GET");
+        assert_eq!(server.requests()[0].path, "/pubkeys/ECHOECHO?from=*3MAGWID&secret=secret");
+        match lookup_pubkey(&conn, "*3MAGWID", "NOTFOUND", "secret") {
+            Err(ApiError::IdNotFound) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lookup_id_request() {
+        let server = TestServer::start(vec![(200, "ECHOECHO"), (404, ""), (400, "")]);
+        let conn = server.conn();
+        let criterion = LookupCriterion::EmailHash("abcd".into());
+        assert_eq!(lookup_id(&conn, &criterion, "*3MAGWID", "secret").unwrap(), Some("ECHOECHO".into()));
+        assert_eq!(server.requests()[0].path, "/lookup/email_hash/abcd?from=*3MAGWID&secret=secret");
+        assert_eq!(lookup_id(&conn, &criterion, "*3MAGWID", "secret").unwrap(), None);
+        match lookup_id(&conn, &criterion, "*3MAGWID", "secret") {
+            Err(ApiError::BadHashLength) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lookup_credits_request() {
+        let server = TestServer::start(vec![(200, "100\n"), (200, "many")]);
+        let conn = server.conn();
+        assert_eq!(lookup_credits(&conn, "*3MAGWID", "secret").unwrap(), 100);
+        assert_eq!(server.requests()[0].path, "/credits?from=*3MAGWID&secret=secret");
+        match lookup_credits(&conn, "*3MAGWID", "secret") {
+            Err(ApiError::ParseError(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lookup_capabilities_request() {
+        let server = TestServer::start(vec![(200, "text,file")]);
+        let caps = lookup_capabilities(&server.conn(), "*3MAGWID", "ECHOECHO", "secret").unwrap();
+        assert!(caps.text && caps.file && !caps.image);
+        assert_eq!(server.requests()[0].path, "/capabilities/ECHOECHO?from=*3MAGWID&secret=secret");
+    }
+
+    #[test]
+    fn test_hash_phone() {
+        let hash = "ad398f4d7ebe63c6550a486cc6e07f9baa09bd9d8b3d8cb9d9be106d35a7fdbc";
+        assert_eq!(hash_phone("41791234567"), hash);
+        assert_eq!(hash_phone("+41 79 123 45 67"), hash);
+    }
+
+    #[test]
+    fn test_hash_email() {
+        let hash = "1ea093239cc5f0e1b6ec81b866265b921f26dc4033025410063309f4d1a8ee2c";
+        assert_eq!(hash_email("test@threema.ch"), hash);
+        assert_eq!(hash_email("  Test@Threema.ch\n"), hash);
+    }
+
+    #[test]
+    fn test_normalize_email() {
+        let cases = vec![
+            ("test@threema.ch", "test@threema.ch"),
+            ("  Test@Threema.CH\n", "test@threema.ch"),
+            ("\tfirst.last+tag@GMail.com ", "first.last+tag@gmail.com"),
+            ("ÄNDERUNG@Example.org", "änderung@example.org"),
+            ("a b@example.org", "a b@example.org"),
+            ("", ""),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(normalize_email(input), expected, "input: {:?}", input);
+        }
+    }
 
     #[test]
     fn test_lookup_criterion_display() {
@@ -303,6 +472,13 @@ mod tests {
         assert!(cap.can("lasersword"));
         assert!(cap.can("."));
         assert!(!cap.can("image"));
+        assert!(!cap.supports_groups());
+    }
+
+    #[test]
+    fn test_parse_capabilities_group() {
+        let cap = "text,image,Group".parse::<Capabilities>().unwrap();
+        assert!(cap.supports_groups());
     }
 
 }
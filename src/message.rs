@@ -0,0 +1,778 @@
+//! Decode decrypted messages.
+
+use std::io::Cursor;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::Serializer;
+use serde_json as json;
+
+use ::errors::ApiError;
+use ::hex::{decode_hex_fixed, encode_hex};
+use ::types::{BlobId, FileMessage, GroupId, MessageId};
+
+
+/// A decrypted end-to-end encrypted message.
+///
+/// Messages can be serialized with `serde`, e.g. for archiving or
+/// forwarding them to other services. The representation is adjacently
+/// tagged: `{"type": "text", "data": "Hello"}`. Raw message bodies are
+/// encoded as base64, images and files are represented by their blob ID
+/// (the blob data itself is not included).
+///
+/// # Blocked contacts
+///
+/// The protocol has no message that indicates that a user blocked or deleted
+/// the gateway ID, so there is no `Blocked` variant. The Threema app of a
+/// user who blocked you silently discards your messages, while the gateway
+/// still accepts them and charges credits. The only hint is the absence of
+/// delivery receipts: If a user stops sending `Received` receipts for a long
+/// time, consider no longer messaging them.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Message {
+    /// A text message.
+    Text(String),
+    /// An image message.
+    Image(ImageMessage),
+    /// A file message.
+    File(FileMessage),
+    /// A text message sent to a group.
+    GroupText(GroupTextMessage),
+    /// A request to re-send the group setup, sent to the group creator.
+    /// Contains the ID of the group.
+    GroupSyncRequest(GroupId),
+    /// A request to join a group via a group link. Contains the raw
+    /// (protobuf encoded) message body.
+    GroupJoinRequest(#[serde(with = "base64")] Vec<u8>),
+    /// A delivery receipt.
+    DeliveryReceipt(DeliveryReceipt),
+    /// A typing indicator: `true` if the sender started typing, `false` if
+    /// they stopped.
+    TypingIndicator(bool),
+    /// A request to delete a previously sent message.
+    DeleteMessage(MessageId),
+    /// A contact photo was set. Contains the raw message body.
+    ContactSetPhoto(#[serde(with = "base64")] Vec<u8>),
+    /// A contact photo was deleted. Contains the raw message body.
+    ContactDeletePhoto(#[serde(with = "base64")] Vec<u8>),
+    /// A contact photo is requested. Contains the raw message body.
+    ContactRequestPhoto(#[serde(with = "base64")] Vec<u8>),
+    /// A VoIP call offer. Contains the raw (JSON encoded) message body.
+    ///
+    /// Calls are not supported by this library, but an offer can be
+    /// declined with
+    /// [`E2eApi::encrypt_voip_reject`](struct.E2eApi.html#method.encrypt_voip_reject),
+    /// using the call ID returned by [`voip_call_id`](#method.voip_call_id).
+    /// Otherwise the caller's app keeps ringing until it times out.
+    VoipCallOffer(#[serde(with = "base64")] Vec<u8>),
+    /// A VoIP call answer. Contains the raw (JSON encoded) message body.
+    VoipCallAnswer(#[serde(with = "base64")] Vec<u8>),
+    /// VoIP ICE candidates. Contains the raw (JSON encoded) message body.
+    VoipIceCandidates(#[serde(with = "base64")] Vec<u8>),
+    /// A VoIP call hangup. Contains the raw (JSON encoded) message body.
+    VoipCallHangup(#[serde(with = "base64")] Vec<u8>),
+    /// A VoIP call is ringing. Contains the raw (JSON encoded) message body.
+    VoipCallRinging(#[serde(with = "base64")] Vec<u8>),
+    /// A forward security envelope. Contains the raw (protobuf encoded)
+    /// message body.
+    ///
+    /// Envelopes carry the messages of the forward secrecy session handshake
+    /// (init, accept, reject and terminate), as well as messages encrypted
+    /// within such a session. Forward secrecy is not supported by this
+    /// library: Envelopes are neither decrypted nor answered, they are only
+    /// classified so that they can be ignored. Without an established
+    /// session, Threema apps keep sending regular end-to-end encrypted
+    /// messages.
+    ForwardSecurityControl(#[serde(with = "base64")] Vec<u8>),
+    /// A message with a type that is not (yet) supported by this library.
+    Unknown {
+        /// The message type byte.
+        type_byte: u8,
+        /// The message body, without the type byte.
+        #[serde(with = "base64")]
+        body: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// Decode a decrypted and unpadded message.
+    ///
+    /// The first byte of `data` must be the message type byte. Usually you
+    /// want to use [`E2eApi::decrypt_msg`](struct.E2eApi.html#method.decrypt_msg)
+    /// instead, which decrypts and decodes a message. Malformed messages
+    /// result in an
+    /// [`ApiError::ParseError`](errors/enum.ApiError.html#variant.ParseError).
+    pub fn decode(data: &[u8]) -> Result<Message, ApiError> {
+        decode(data)
+    }
+
+    /// Return the call ID of a VoIP signaling message.
+    ///
+    /// Returns `None` for other messages, and for VoIP messages without a
+    /// call ID (sent by older Threema apps) or with a malformed body.
+    pub fn voip_call_id(&self) -> Option<u32> {
+        match *self {
+            Message::VoipCallOffer(ref body) |
+            Message::VoipCallAnswer(ref body) |
+            Message::VoipIceCandidates(ref body) |
+            Message::VoipCallHangup(ref body) |
+            Message::VoipCallRinging(ref body) => decode_voip_call_id(body),
+            _ => None,
+        }
+    }
+}
+
+/// An image message.
+///
+/// The image data (JPEG format) is stored on the blob server. In contrast to
+/// file messages, it is not encrypted with a symmetric key, but with the
+/// sender's private key and the recipient's public key, using the nonce
+/// contained in this message.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct ImageMessage {
+    /// The blob ID of the encrypted image data.
+    pub blob_id: BlobId,
+    /// The image size in bytes.
+    pub size_bytes: u32,
+    /// The nonce used to encrypt the image data.
+    #[serde(serialize_with = "nonce_to_hex")]
+    #[serde(deserialize_with = "nonce_from_hex")]
+    pub nonce: [u8; 24],
+}
+
+/// A text message sent to a group.
+///
+/// # Trust model
+///
+/// Group messages are sent to every member individually and are end-to-end
+/// encrypted like any other message. The sender of the message is the
+/// `from` identity of the [`IncomingMessage`](struct.IncomingMessage.html):
+/// Since the message can only be decrypted with the sender's public key,
+/// successful decryption proves that it was sent by that identity.
+///
+/// The `creator_id` and `group_id` on the other hand are chosen by the sender
+/// and are not verified. Any identity can claim that a message belongs to a
+/// certain group. If group membership matters, check that the sender is a
+/// known member of the group identified by `creator_id` and `group_id`.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct GroupTextMessage {
+    /// The identity of the group creator, as claimed by the sender.
+    pub creator_id: String,
+    /// The group ID, as claimed by the sender.
+    pub group_id: GroupId,
+    /// The message text.
+    pub text: String,
+}
+
+/// The type of a delivery receipt.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptType {
+    /// The message has been received by the recipient.
+    Received,
+    /// The message has been read by the recipient.
+    Read,
+    /// The recipient has explicitly acknowledged the message.
+    Acknowledged,
+    /// The recipient has explicitly declined the message.
+    Declined,
+    /// A receipt type that is not (yet) supported by this library.
+    Unknown(u8),
+}
+
+impl From<u8> for ReceiptType {
+    fn from(val: u8) -> Self {
+        match val {
+            0x01 => ReceiptType::Received,
+            0x02 => ReceiptType::Read,
+            0x03 => ReceiptType::Acknowledged,
+            0x04 => ReceiptType::Declined,
+            other => ReceiptType::Unknown(other),
+        }
+    }
+}
+
+impl From<ReceiptType> for u8 {
+    fn from(val: ReceiptType) -> Self {
+        match val {
+            ReceiptType::Received => 0x01,
+            ReceiptType::Read => 0x02,
+            ReceiptType::Acknowledged => 0x03,
+            ReceiptType::Declined => 0x04,
+            ReceiptType::Unknown(other) => other,
+        }
+    }
+}
+
+/// A delivery receipt, referring to one or more messages.
+///
+/// Receipts of type `Acknowledged` and `Declined` are the reactions
+/// ("thumbs up" and "thumbs down") of the Threema apps.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct DeliveryReceipt {
+    /// The type of the receipt.
+    pub receipt_type: ReceiptType,
+    /// The IDs of the messages this receipt refers to.
+    pub message_ids: Vec<MessageId>,
+}
+
+impl DeliveryReceipt {
+    /// Whether this is a read receipt.
+    pub fn is_read(&self) -> bool {
+        self.receipt_type == ReceiptType::Read
+    }
+
+    /// Whether this is an explicit acknowledgement ("thumbs up") by the
+    /// recipient.
+    ///
+    /// Note that receiving or reading a message is not an acknowledgement.
+    pub fn is_ack(&self) -> bool {
+        self.receipt_type == ReceiptType::Acknowledged
+    }
+}
+
+/// The reason for rejecting a VoIP call.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VoipRejectReason {
+    /// No reason given.
+    Unknown,
+    /// The recipient is busy with another call.
+    Busy,
+    /// The call was not answered in time.
+    Timeout,
+    /// The recipient rejected the call.
+    Rejected,
+    /// Calls are disabled by the recipient.
+    Disabled,
+}
+
+impl From<VoipRejectReason> for u8 {
+    fn from(val: VoipRejectReason) -> Self {
+        match val {
+            VoipRejectReason::Unknown => 0,
+            VoipRejectReason::Busy => 1,
+            VoipRejectReason::Timeout => 2,
+            VoipRejectReason::Rejected => 3,
+            VoipRejectReason::Disabled => 4,
+        }
+    }
+}
+
+/// The prefix of a text message quoting another message.
+const QUOTE_PREFIX: &'static str = "> quote #";
+
+/// A text message quoting a previous message.
+///
+/// Quotes are not a separate message type. Like the Threema apps, they are
+/// embedded in the body of a regular text message, which starts with
+/// `> quote #<message id>` followed by an empty line and the text. Use
+/// [`to_text`](#method.to_text) to create the message text, e.g. for
+/// [`E2eApi::encrypt_text_msg`](struct.E2eApi.html#method.encrypt_text_msg),
+/// and [`parse`](#method.parse) to find quotes in received text messages.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct QuotedText {
+    /// The ID of the quoted message.
+    pub quoted_message_id: MessageId,
+    /// The text of the reply.
+    pub text: String,
+}
+
+impl QuotedText {
+    /// Create a text message quoting the message with the specified ID.
+    pub fn quoting<S: Into<String>>(quoted_message_id: MessageId, text: S) -> Self {
+        QuotedText {
+            quoted_message_id: quoted_message_id,
+            text: text.into(),
+        }
+    }
+
+    /// Return the message text including the quote markup.
+    pub fn to_text(&self) -> String {
+        format!("{}{}\n\n{}", QUOTE_PREFIX, self.quoted_message_id, self.text)
+    }
+
+    /// Parse the text of a text message. Returns `None` if the text does not
+    /// quote another message.
+    pub fn parse(text: &str) -> Option<QuotedText> {
+        if !text.starts_with(QUOTE_PREFIX) {
+            return None;
+        }
+        let rest = &text[QUOTE_PREFIX.len()..];
+        let id = match rest.get(..16).map(MessageId::from_str) {
+            Some(Ok(id)) => id,
+            _ => return None,
+        };
+        let rest = &rest[16..];
+        let text = if rest.starts_with("\n\n") {
+            &rest[2..]
+        } else if rest.starts_with("\r\n\r\n") {
+            &rest[4..]
+        } else {
+            return None;
+        };
+        Some(QuotedText::quoting(id, text))
+    }
+}
+
+/// Decode a decrypted and unpadded message.
+///
+/// The first byte of `data` must be the message type byte.
+pub(crate) fn decode(data: &[u8]) -> Result<Message, ApiError> {
+    let (type_byte, body) = match data.split_first() {
+        Some((type_byte, body)) => (*type_byte, body),
+        None => return Err(ApiError::ParseError("Message is empty".into())),
+    };
+    match type_byte {
+        0x01 => decode_text(body).map(Message::Text),
+        0x02 => decode_image(body).map(Message::Image),
+        0x17 => decode_file(body).map(Message::File),
+        0x18 => Ok(Message::ContactSetPhoto(body.to_vec())),
+        0x19 => Ok(Message::ContactDeletePhoto(body.to_vec())),
+        0x1a => Ok(Message::ContactRequestPhoto(body.to_vec())),
+        0x60 => Ok(Message::VoipCallOffer(body.to_vec())),
+        0x61 => Ok(Message::VoipCallAnswer(body.to_vec())),
+        0x62 => Ok(Message::VoipIceCandidates(body.to_vec())),
+        0x63 => Ok(Message::VoipCallHangup(body.to_vec())),
+        0x64 => Ok(Message::VoipCallRinging(body.to_vec())),
+        0x41 => decode_group_text(body).map(Message::GroupText),
+        0x4d => Ok(Message::GroupJoinRequest(body.to_vec())),
+        0x51 => decode_group_sync_request(body).map(Message::GroupSyncRequest),
+        0x80 => decode_delivery_receipt(body).map(Message::DeliveryReceipt),
+        0x90 => decode_typing_indicator(body).map(Message::TypingIndicator),
+        0x92 => decode_delete_message(body).map(Message::DeleteMessage),
+        0xa0 => Ok(Message::ForwardSecurityControl(body.to_vec())),
+        _ => Ok(Message::Unknown { type_byte: type_byte, body: body.to_vec() }),
+    }
+}
+
+fn decode_text(body: &[u8]) -> Result<String, ApiError> {
+    String::from_utf8(body.to_vec())
+        .map_err(|e| ApiError::ParseError(format!("Text message is not valid UTF-8: {}", e)))
+}
+
+fn decode_image(body: &[u8]) -> Result<ImageMessage, ApiError> {
+    if body.len() != 44 {
+        return Err(ApiError::ParseError(format!("Image message has invalid length: {}", body.len())));
+    }
+    let mut blob_id = [0; 16];
+    blob_id.copy_from_slice(&body[0..16]);
+    let size_bytes = Cursor::new(&body[16..20]).read_u32::<LittleEndian>()
+        .map_err(|e| ApiError::ParseError(format!("Could not read image size: {}", e)))?;
+    let mut nonce = [0; 24];
+    nonce.copy_from_slice(&body[20..44]);
+    Ok(ImageMessage {
+        blob_id: BlobId::new(blob_id),
+        size_bytes: size_bytes,
+        nonce: nonce,
+    })
+}
+
+fn decode_file(body: &[u8]) -> Result<FileMessage, ApiError> {
+    json::from_slice(body)
+        .map_err(|e| ApiError::ParseError(format!("Could not parse file message: {}", e)))
+}
+
+/// Decode the creator ID and group ID at the start of a group message body.
+fn decode_group_header(body: &[u8]) -> Result<(String, GroupId, &[u8]), ApiError> {
+    if body.len() < 16 {
+        return Err(ApiError::ParseError(format!("Group message has invalid length: {}", body.len())));
+    }
+    let creator_id = String::from_utf8(body[0..8].to_vec())
+        .map_err(|_| ApiError::ParseError("Group creator ID is not valid UTF-8".into()))?;
+    let mut group_id = [0; 8];
+    group_id.copy_from_slice(&body[8..16]);
+    Ok((creator_id, GroupId::new(group_id), &body[16..]))
+}
+
+fn decode_group_text(body: &[u8]) -> Result<GroupTextMessage, ApiError> {
+    let (creator_id, group_id, text) = decode_group_header(body)?;
+    Ok(GroupTextMessage {
+        creator_id: creator_id,
+        group_id: group_id,
+        text: decode_text(text)?,
+    })
+}
+
+fn decode_group_sync_request(body: &[u8]) -> Result<GroupId, ApiError> {
+    if body.len() != 8 {
+        return Err(ApiError::ParseError(format!("Group sync request has invalid length: {}", body.len())));
+    }
+    let mut group_id = [0; 8];
+    group_id.copy_from_slice(body);
+    Ok(GroupId::new(group_id))
+}
+
+fn decode_delivery_receipt(body: &[u8]) -> Result<DeliveryReceipt, ApiError> {
+    let (receipt_type, ids) = match body.split_first() {
+        Some((receipt_type, ids)) => (*receipt_type, ids),
+        None => return Err(ApiError::ParseError("Delivery receipt is empty".into())),
+    };
+    if ids.len() % 8 != 0 {
+        return Err(ApiError::ParseError(format!("Delivery receipt has invalid length: {}", body.len())));
+    }
+    let message_ids = ids.chunks(8).map(|chunk| {
+        let mut id = [0; 8];
+        id.copy_from_slice(chunk);
+        MessageId::new(id)
+    }).collect();
+    Ok(DeliveryReceipt {
+        receipt_type: receipt_type.into(),
+        message_ids: message_ids,
+    })
+}
+
+fn decode_typing_indicator(body: &[u8]) -> Result<bool, ApiError> {
+    if body.len() != 1 || body[0] > 1 {
+        return Err(ApiError::ParseError(format!("Invalid typing indicator: {:?}", body)));
+    }
+    Ok(body[0] == 1)
+}
+
+/// The fields shared by all VoIP signaling messages.
+#[derive(Deserialize)]
+struct VoipHeader {
+    #[serde(rename = "callId")]
+    call_id: Option<u32>,
+}
+
+fn decode_voip_call_id(body: &[u8]) -> Option<u32> {
+    json::from_slice::<VoipHeader>(body).ok().and_then(|header| header.call_id)
+}
+
+fn decode_delete_message(body: &[u8]) -> Result<MessageId, ApiError> {
+    // Protobuf encoded, the message ID is field 1 (wire type 1, 64 bit)
+    if body.len() < 9 || body[0] != 0x09 {
+        return Err(ApiError::ParseError("Could not parse delete message".into()));
+    }
+    let mut id = [0; 8];
+    id.copy_from_slice(&body[1..9]);
+    Ok(MessageId::new(id))
+}
+
+pub(crate) fn nonce_to_hex<S: Serializer>(val: &[u8; 24], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&encode_hex(val))
+}
+
+pub(crate) fn nonce_from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 24], D::Error> {
+    let s = String::deserialize(deserializer)?;
+    decode_hex_fixed(&s).map_err(|e| de::Error::custom(format!("Invalid nonce: {}", e)))
+}
+
+/// Serialize raw message bodies as base64 strings.
+mod base64 {
+    use data_encoding::BASE64;
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde::ser::Serializer;
+
+    pub fn serialize<S: Serializer>(val: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&BASE64.encode(val))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        BASE64.decode(s.as_bytes()).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json as json;
+    use ::errors::ApiError;
+    use ::types::{BlobId, GroupId, MessageId};
+    use super::{decode, Message, ReceiptType};
+
+    #[test]
+    fn test_decode_empty() {
+        match decode(&[]) {
+            Err(ApiError::ParseError(_)) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_text() {
+        match decode(&[0x01, 0x68, 0xc3, 0xa4]).unwrap() {
+            Message::Text(text) => assert_eq!(text, "hä"),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_text_invalid_utf8() {
+        assert!(decode(&[0x01, 0xc3]).is_err());
+    }
+
+    #[test]
+    fn test_decode_image() {
+        let mut data = vec![0x02];
+        data.extend_from_slice(&[0xab; 16]);
+        data.extend_from_slice(&[2, 1, 0, 0]);
+        data.extend_from_slice(&[0x42; 24]);
+        match decode(&data).unwrap() {
+            Message::Image(img) => {
+                assert_eq!(img.blob_id, BlobId::new([0xab; 16]));
+                assert_eq!(img.size_bytes, 258);
+                assert_eq!(img.nonce, [0x42; 24]);
+            },
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_image_too_short() {
+        let mut data = vec![0x02];
+        data.extend_from_slice(&[0xab; 40]);
+        assert!(decode(&data).is_err());
+    }
+
+    #[test]
+    fn test_decode_file() {
+        let mut data = vec![0x17];
+        data.extend_from_slice(br#"{"b":"0123456789abcdef0123456789abcdef","k":"0102030401020304010203040102030401020304010203040102030401020304","m":"image/png","s":1234,"i":0}"#);
+        match decode(&data).unwrap() {
+            Message::File(file) => {
+                assert_eq!(file.file_blob_id, BlobId::from_str("0123456789abcdef0123456789abcdef").unwrap());
+                assert_eq!(file.mime_type.to_string(), "image/png");
+                assert_eq!(file.file_size_bytes, 1234);
+            },
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_file_invalid_json() {
+        assert!(decode(&[0x17, b'{']).is_err());
+    }
+
+    #[test]
+    fn test_decode_group_text() {
+        let mut data = vec![0x41];
+        data.extend_from_slice(b"CREATOR1");
+        data.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        data.extend_from_slice(b"Hi all");
+        match decode(&data).unwrap() {
+            Message::GroupText(msg) => {
+                assert_eq!(msg.creator_id, "CREATOR1");
+                assert_eq!(msg.group_id, GroupId::new([1, 2, 3, 4, 5, 6, 7, 8]));
+                assert_eq!(msg.text, "Hi all");
+            },
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        assert!(decode(&[0x41, b'C', b'R']).is_err());
+    }
+
+    #[test]
+    fn test_decode_group_sync_request() {
+        match decode(&[0x51, 1, 2, 3, 4, 5, 6, 7, 8]).unwrap() {
+            Message::GroupSyncRequest(id) => assert_eq!(id, GroupId::new([1, 2, 3, 4, 5, 6, 7, 8])),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        assert!(decode(&[0x51, 1, 2, 3]).is_err());
+        match decode(&[0x4d, 0x0a, 0x00]).unwrap() {
+            Message::GroupJoinRequest(body) => assert_eq!(body, vec![0x0a, 0x00]),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_delivery_receipt() {
+        let data = [0x80, 0x02, 1, 2, 3, 4, 5, 6, 7, 8, 8, 7, 6, 5, 4, 3, 2, 1];
+        match decode(&data).unwrap() {
+            Message::DeliveryReceipt(receipt) => {
+                assert_eq!(receipt.receipt_type, ReceiptType::Read);
+                assert_eq!(receipt.message_ids, vec![
+                    MessageId::new([1, 2, 3, 4, 5, 6, 7, 8]),
+                    MessageId::new([8, 7, 6, 5, 4, 3, 2, 1]),
+                ]);
+            },
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_delivery_receipt_types() {
+        let types = [
+            (0x01, ReceiptType::Received, false, false),
+            (0x02, ReceiptType::Read, true, false),
+            (0x03, ReceiptType::Acknowledged, false, true),
+            (0x04, ReceiptType::Declined, false, false),
+            (0x05, ReceiptType::Unknown(0x05), false, false),
+        ];
+        for &(byte, receipt_type, is_read, is_ack) in types.iter() {
+            match decode(&[0x80, byte, 1, 2, 3, 4, 5, 6, 7, 8]).unwrap() {
+                Message::DeliveryReceipt(receipt) => {
+                    assert_eq!(receipt.receipt_type, receipt_type);
+                    assert_eq!(receipt.is_read(), is_read);
+                    assert_eq!(receipt.is_ack(), is_ack);
+                    assert_eq!(u8::from(receipt.receipt_type), byte);
+                },
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_delivery_receipt_invalid_length() {
+        assert!(decode(&[0x80]).is_err());
+        assert!(decode(&[0x80, 0x01, 1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_decode_typing_indicator() {
+        match decode(&[0x90, 0x01]).unwrap() {
+            Message::TypingIndicator(typing) => assert!(typing),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        match decode(&[0x90, 0x00]).unwrap() {
+            Message::TypingIndicator(typing) => assert!(!typing),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        assert!(decode(&[0x90]).is_err());
+        assert!(decode(&[0x90, 0x02]).is_err());
+        assert!(decode(&[0x90, 0x01, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_decode_delete_message() {
+        match decode(&[0x92, 0x09, 1, 2, 3, 4, 5, 6, 7, 8]).unwrap() {
+            Message::DeleteMessage(id) => assert_eq!(id, MessageId::new([1, 2, 3, 4, 5, 6, 7, 8])),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        assert!(decode(&[0x92, 0x09, 1, 2, 3]).is_err());
+        assert!(decode(&[0x92, 0x0a, 1, 2, 3, 4, 5, 6, 7, 8]).is_err());
+    }
+
+    #[test]
+    fn test_decode_control_messages() {
+        match decode(&[0x18, 1, 2]).unwrap() {
+            Message::ContactSetPhoto(body) => assert_eq!(body, vec![1, 2]),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        match decode(&[0x60, b'{', b'}']).unwrap() {
+            Message::VoipCallOffer(body) => assert_eq!(body, b"{}".to_vec()),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        match decode(&[0x63]).unwrap() {
+            Message::VoipCallHangup(body) => assert!(body.is_empty()),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_voip_call_id() {
+        let offer = br#"{"callId":1234,"offer":{"sdpType":"offer","sdp":"v=0"}}"#;
+        let mut data = vec![0x60];
+        data.extend_from_slice(offer);
+        assert_eq!(decode(&data).unwrap().voip_call_id(), Some(1234));
+        assert_eq!(decode(b"\x64{\"callId\":7}").unwrap().voip_call_id(), Some(7));
+        assert_eq!(decode(b"\x63{}").unwrap().voip_call_id(), None);
+        assert_eq!(decode(b"\x63").unwrap().voip_call_id(), None);
+        assert_eq!(decode(b"\x01{\"callId\":7}").unwrap().voip_call_id(), None);
+    }
+
+    #[test]
+    fn test_decode_forward_security() {
+        match decode(&[0xa0, 0x0a, 0x02, 0x01, 0x02]).unwrap() {
+            Message::ForwardSecurityControl(body) => assert_eq!(body, vec![0x0a, 0x02, 0x01, 0x02]),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        // Malformed input must never panic
+        let data: Vec<u8> = (0..64).collect();
+        for type_byte in 0..256 {
+            for len in 0..data.len() {
+                let mut msg = vec![type_byte as u8];
+                msg.extend_from_slice(&data[..len]);
+                let _ = decode(&msg);
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_unknown() {
+        match decode(&[0xfe, 1, 2, 3]).unwrap() {
+            Message::Unknown { type_byte, body } => {
+                assert_eq!(type_byte, 0xfe);
+                assert_eq!(body, vec![1, 2, 3]);
+            },
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_quoted_text() {
+        let id = MessageId::new([0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0xab]);
+        let quote = super::QuotedText::quoting(id, "Yes!\n\nReally.");
+        assert_eq!(quote.to_text(), "> quote #01020304050607ab\n\nYes!\n\nReally.");
+        assert_eq!(super::QuotedText::parse(&quote.to_text()), Some(quote));
+
+        let quote = super::QuotedText::parse("> quote #01020304050607AB\r\n\r\nOK").unwrap();
+        assert_eq!(quote.quoted_message_id, id);
+        assert_eq!(quote.text, "OK");
+    }
+
+    #[test]
+    fn test_quoted_text_invalid() {
+        assert_eq!(super::QuotedText::parse("Hello"), None);
+        assert_eq!(super::QuotedText::parse("> quote #01020304050607ab"), None);
+        assert_eq!(super::QuotedText::parse("> quote #01020304050607ab\nOK"), None);
+        assert_eq!(super::QuotedText::parse("> quote #0102030405060\n\nOK"), None);
+        assert_eq!(super::QuotedText::parse("> quote #010203040506070ä\n\nOK"), None);
+        assert_eq!(super::QuotedText::parse("> quote #0102030405060xyz\n\nOK"), None);
+    }
+
+    /// Serialize, deserialize and serialize again, return both
+    /// serializations.
+    fn roundtrip(msg: &Message) -> (String, String) {
+        let serialized = json::to_string(msg).unwrap();
+        let deserialized: Message = json::from_str(&serialized).unwrap();
+        (serialized, json::to_string(&deserialized).unwrap())
+    }
+
+    #[test]
+    fn test_serde_text() {
+        let (serialized, roundtripped) = roundtrip(&Message::Text("Hello".into()));
+        assert_eq!(serialized, r#"{"type":"text","data":"Hello"}"#);
+        assert_eq!(serialized, roundtripped);
+    }
+
+    #[test]
+    fn test_serde_raw_body() {
+        let msg = Message::ContactSetPhoto(vec![0xff, 0x00, 0x01]);
+        let (serialized, roundtripped) = roundtrip(&msg);
+        assert_eq!(serialized, r#"{"type":"contact_set_photo","data":"/wAB"}"#);
+        assert_eq!(serialized, roundtripped);
+
+        let msg = Message::Unknown { type_byte: 0x99, body: vec![0x01] };
+        let (serialized, roundtripped) = roundtrip(&msg);
+        assert_eq!(serialized, r#"{"type":"unknown","data":{"type_byte":153,"body":"AQ=="}}"#);
+        assert_eq!(serialized, roundtripped);
+    }
+
+    #[test]
+    fn test_serde_structured() {
+        let msgs = vec![
+            decode(&[0x02, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16,
+                     0x10, 0, 0, 0,
+                     0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23]).unwrap(),
+            Message::DeliveryReceipt(super::DeliveryReceipt {
+                receipt_type: ReceiptType::Unknown(7),
+                message_ids: vec![MessageId::new([1, 2, 3, 4, 5, 6, 7, 8])],
+            }),
+            Message::GroupSyncRequest(GroupId::new([8; 8])),
+            Message::DeleteMessage(MessageId::new([0; 8])),
+            Message::TypingIndicator(true),
+        ];
+        for msg in &msgs {
+            let (serialized, roundtripped) = roundtrip(msg);
+            assert_eq!(serialized, roundtripped);
+        }
+        let (serialized, _) = roundtrip(&msgs[0]);
+        assert!(serialized.contains(r#""blob_id":"0102030405060708090a0b0c0d0e0f10""#));
+        assert!(serialized.contains(r#""nonce":"000102030405060708090a0b0c0d0e0f1011121314151617""#));
+    }
+}
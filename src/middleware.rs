@@ -0,0 +1,94 @@
+//! Hooks that are invoked around every request to the gateway.
+//!
+//! Register a middleware with
+//! [`ApiBuilder::with_middleware`](../struct.ApiBuilder.html#method.with_middleware),
+//! for example to add tracing headers, to log requests or to collect metrics.
+//!
+//! ## Gateway version
+//!
+//! The gateway API is not versioned: there is neither a version endpoint nor
+//! a version header, and the API has only been extended in backwards
+//! compatible ways so far. To have something to go on when the behavior of
+//! the gateway changes, a middleware can log the `Server` header and the
+//! status of every response:
+//!
+//! ```no_run
+//! use threema_gateway::ApiBuilder;
+//! use threema_gateway::middleware::{RequestMiddleware, Response};
+//!
+//! struct LogServer;
+//!
+//! impl RequestMiddleware for LogServer {
+//!     fn after(&self, response: &Response) {
+//!         let server = response.headers().get_raw("Server")
+//!             .and_then(|raw| raw.one())
+//!             .map(String::from_utf8_lossy);
+//!         println!("{} from {:?}", response.status(), server);
+//!     }
+//! }
+//!
+//! let api = ApiBuilder::new("*YOUR_ID", "your-gateway-secret")
+//!     .with_middleware(LogServer)
+//!     .into_simple()
+//!     .unwrap();
+//! ```
+
+use std::fmt;
+use std::sync::Arc;
+
+pub use reqwest::{Request, Response};
+
+
+/// A hook that is invoked around every request to the gateway.
+///
+/// Both methods do nothing by default. If multiple middlewares are
+/// registered, `before` is called in registration order and `after` in
+/// reverse order.
+///
+/// Note that the request URL or body contains the gateway secret, so be
+/// careful when logging requests.
+pub trait RequestMiddleware: Send + Sync {
+    /// Called before the request is sent. The request may be modified, e.g.
+    /// to add headers.
+    fn before(&self, request: &mut Request) {
+        let _ = request;
+    }
+
+    /// Called after the response headers have been received, before the
+    /// response body is read. Not called if the request fails.
+    fn after(&self, response: &Response) {
+        let _ = response;
+    }
+}
+
+/// The registered middlewares.
+#[derive(Clone, Default)]
+pub(crate) struct MiddlewareChain(Vec<Arc<RequestMiddleware>>);
+
+impl MiddlewareChain {
+    pub(crate) fn push(&mut self, middleware: Arc<RequestMiddleware>) {
+        self.0.push(middleware);
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn before(&self, request: &mut Request) {
+        for middleware in self.0.iter() {
+            middleware.before(request);
+        }
+    }
+
+    pub(crate) fn after(&self, response: &Response) {
+        for middleware in self.0.iter().rev() {
+            middleware.after(response);
+        }
+    }
+}
+
+impl fmt::Debug for MiddlewareChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MiddlewareChain({} middlewares)", self.0.len())
+    }
+}
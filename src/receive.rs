@@ -0,0 +1,233 @@
+//! Handle incoming messages.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use data_encoding::HEXLOWER_PERMISSIVE;
+use serde::de::{self, Deserialize, Deserializer};
+use serde_urlencoded;
+use sodiumoxide::crypto::auth::hmacsha256;
+
+use ::errors::ApiError;
+use ::hex::encode_hex;
+use ::types::MessageId;
+
+
+/// An incoming end-to-end encrypted message.
+///
+/// The gateway delivers incoming messages to your callback URL as an
+/// `application/x-www-form-urlencoded` POST request. Use
+/// [`from_urlencoded_bytes`](#method.from_urlencoded_bytes) to parse the
+/// request body, verify it with [`verify_mac`](#method.verify_mac) and
+/// decrypt the message with
+/// [`E2eApi::decrypt_msg`](struct.E2eApi.html#method.decrypt_msg).
+///
+/// There is no challenge-response handshake when setting up the callback
+/// URL. The gateway only requires that the URL uses HTTPS with a valid
+/// certificate, and that your server responds to callback requests with
+/// status code 200. Any other response is treated as a failed delivery and
+/// retried later, so respond quickly and process messages asynchronously if
+/// needed.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct IncomingMessage {
+    /// The sender identity (8 characters)
+    pub from: String,
+    /// Your own gateway identity (8 characters, starting with `*`)
+    pub to: String,
+    /// The message ID assigned by the sender
+    #[serde(rename="messageId")]
+    pub message_id: MessageId,
+    /// The message date set by the sender (UNIX timestamp in seconds)
+    ///
+    /// Use [`timestamp`](#method.timestamp) to get it as a `SystemTime`.
+    #[serde(deserialize_with = "deserialize_date")]
+    pub date: u64,
+    /// The nonce used for encryption (24 bytes)
+    #[serde(deserialize_with = "deserialize_hex")]
+    pub nonce: Vec<u8>,
+    /// The encrypted message data
+    #[serde(rename="box")]
+    #[serde(deserialize_with = "deserialize_hex")]
+    pub box_data: Vec<u8>,
+    /// The HMAC-SHA256 of the request parameters (32 bytes)
+    #[serde(deserialize_with = "deserialize_hex")]
+    pub mac: Vec<u8>,
+    /// The public nickname of the sender, if set
+    pub nickname: Option<String>,
+}
+
+impl IncomingMessage {
+    /// Parse an incoming message from the url-encoded request body sent by
+    /// the gateway.
+    pub fn from_urlencoded_bytes(bytes: &[u8]) -> Result<Self, ApiError> {
+        serde_urlencoded::from_bytes(bytes)
+            .map_err(|e| ApiError::ParseError(format!("Could not parse incoming message: {}", e)))
+    }
+
+    /// Return the message date set by the sender.
+    pub fn timestamp(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.date)
+    }
+
+    /// Check that the message date is not older than `max_age`, and not
+    /// more than `skew_tolerance` in the future.
+    ///
+    /// Rejecting old messages makes replaying intercepted callbacks harder.
+    /// Messages that are too old result in
+    /// [`ApiError::MessageTooOld`](errors/enum.ApiError.html#variant.MessageTooOld),
+    /// messages that are too far in the future in
+    /// [`ApiError::MessageFromFuture`](errors/enum.ApiError.html#variant.MessageFromFuture).
+    pub fn check_age(&self, max_age: Duration, skew_tolerance: Duration) -> Result<(), ApiError> {
+        self.check_age_at(SystemTime::now(), max_age, skew_tolerance)
+    }
+
+    /// Verify the MAC of the message, which the gateway computes with your
+    /// gateway secret.
+    ///
+    /// An invalid MAC results in
+    /// [`ApiError::InvalidMac`](errors/enum.ApiError.html#variant.InvalidMac).
+    /// The MAC is compared in constant time. Since the MAC covers the
+    /// request parameters as sent by the gateway, it should be verified before
+    /// trusting any part of the message.
+    pub fn verify_mac(&self, secret: &str) -> Result<(), ApiError> {
+        let mac = match hmacsha256::Tag::from_slice(&self.mac) {
+            Some(mac) => mac,
+            None => return Err(ApiError::InvalidMac),
+        };
+        if self.compute_mac(secret.as_bytes()) == mac {
+            Ok(())
+        } else {
+            Err(ApiError::InvalidMac)
+        }
+    }
+
+    /// Compute the MAC over the concatenated request parameters.
+    fn compute_mac(&self, secret: &[u8]) -> hmacsha256::Tag {
+        let mut state = hmacsha256::State::init(secret);
+        state.update(self.from.as_bytes());
+        state.update(self.to.as_bytes());
+        state.update(self.message_id.to_string().as_bytes());
+        state.update(self.date.to_string().as_bytes());
+        state.update(encode_hex(&self.nonce).as_bytes());
+        state.update(encode_hex(&self.box_data).as_bytes());
+        state.finalize()
+    }
+
+    fn check_age_at(&self, now: SystemTime, max_age: Duration, skew_tolerance: Duration) -> Result<(), ApiError> {
+        match now.duration_since(self.timestamp()) {
+            Ok(age) if age > max_age => Err(ApiError::MessageTooOld),
+            Ok(_) => Ok(()),
+            Err(ref e) if e.duration() > skew_tolerance => Err(ApiError::MessageFromFuture),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+/// The largest accepted message date. Larger values cannot be represented as
+/// `SystemTime`.
+const MAX_DATE: u64 = ::std::i64::MAX as u64;
+
+fn deserialize_date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let date = u64::deserialize(deserializer)?;
+    if date > MAX_DATE {
+        return Err(de::Error::custom(format!("message date out of range: {}", date)));
+    }
+    Ok(date)
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    HEXLOWER_PERMISSIVE.decode(s.as_bytes()).map_err(de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use ::errors::ApiError;
+    use ::types::MessageId;
+    use super::IncomingMessage;
+
+    #[test]
+    fn test_from_urlencoded_bytes() {
+        let body = b"from=ECHOECHO&to=*TESTTST&messageId=0102030405060708&date=1524658864\
+                     &nonce=000102030405060708090a0b0c0d0e0f1011121314151617\
+                     &box=0011ff&mac=00112233445566778899aabbccddeeff00112233445566778899aabbccddeeff\
+                     &nickname=Test+User";
+        let msg = IncomingMessage::from_urlencoded_bytes(body).unwrap();
+        assert_eq!(msg.from, "ECHOECHO");
+        assert_eq!(msg.to, "*TESTTST");
+        assert_eq!(msg.message_id, MessageId::new([1, 2, 3, 4, 5, 6, 7, 8]));
+        assert_eq!(msg.date, 1524658864);
+        assert_eq!(msg.timestamp(), UNIX_EPOCH + Duration::from_secs(1524658864));
+        assert_eq!(msg.nonce.len(), 24);
+        assert_eq!(msg.nonce[23], 0x17);
+        assert_eq!(msg.box_data, vec![0x00, 0x11, 0xff]);
+        assert_eq!(msg.mac.len(), 32);
+        assert_eq!(msg.nickname, Some("Test User".into()));
+    }
+
+    #[test]
+    fn test_from_urlencoded_bytes_without_nickname() {
+        let body = b"from=ECHOECHO&to=*TESTTST&messageId=0102030405060708&date=1524658864\
+                     &nonce=00&box=00&mac=00";
+        let msg = IncomingMessage::from_urlencoded_bytes(body).unwrap();
+        assert_eq!(msg.nickname, None);
+    }
+
+    #[test]
+    fn test_check_age() {
+        let body = b"from=ECHOECHO&to=*TESTTST&messageId=0102030405060708&date=1000\
+                     &nonce=00&box=00&mac=00";
+        let msg = IncomingMessage::from_urlencoded_bytes(body).unwrap();
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        let minute = Duration::from_secs(60);
+        assert!(msg.check_age_at(at(1000), minute, minute).is_ok());
+        assert!(msg.check_age_at(at(1060), minute, minute).is_ok());
+        assert!(msg.check_age_at(at(940), minute, minute).is_ok());
+        match msg.check_age_at(at(1061), minute, minute) {
+            Err(ApiError::MessageTooOld) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match msg.check_age_at(at(939), minute, minute) {
+            Err(ApiError::MessageFromFuture) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_verify_mac() {
+        let body = b"from=ECHOECHO&to=*TESTTST&messageId=0102030405060708&date=1524658864\
+                     &nonce=000102030405060708090a0b0c0d0e0f1011121314151617\
+                     &box=0011ff&mac=e462f26cb82c75849c6fefeb5c415bb99b24fa5fa807ae91f588f0af9199f766";
+        let mut msg = IncomingMessage::from_urlencoded_bytes(body).unwrap();
+        assert!(msg.verify_mac("1234").is_ok());
+        match msg.verify_mac("4321") {
+            Err(ApiError::InvalidMac) => (),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        msg.box_data.push(0);
+        assert!(msg.verify_mac("1234").is_err());
+
+        msg.mac.truncate(31);
+        assert!(msg.verify_mac("1234").is_err());
+    }
+
+    #[test]
+    fn test_from_urlencoded_bytes_invalid() {
+        // Missing box
+        let body = b"from=ECHOECHO&to=*TESTTST&messageId=0102030405060708&date=1524658864\
+                     &nonce=00&mac=00";
+        assert!(IncomingMessage::from_urlencoded_bytes(body).is_err());
+
+        // Invalid hex
+        let body = b"from=ECHOECHO&to=*TESTTST&messageId=0102030405060708&date=1524658864\
+                     &nonce=00&box=0g&mac=00";
+        assert!(IncomingMessage::from_urlencoded_bytes(body).is_err());
+
+        // Date out of range
+        let body = b"from=ECHOECHO&to=*TESTTST&messageId=0102030405060708&date=18446744073709551615\
+                     &nonce=00&box=00&mac=00";
+        assert!(IncomingMessage::from_urlencoded_bytes(body).is_err());
+    }
+
+}
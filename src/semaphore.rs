@@ -0,0 +1,87 @@
+//! A counting semaphore, used to limit concurrent requests.
+
+use std::sync::{Condvar, Mutex};
+
+
+/// A counting semaphore.
+#[derive(Debug)]
+pub(crate) struct Semaphore {
+    permits: Mutex<usize>,
+    available: Condvar,
+}
+
+/// A permit acquired from a [`Semaphore`](struct.Semaphore.html). The permit
+/// is released when this guard is dropped.
+pub(crate) struct SemaphoreGuard<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Semaphore {
+    /// Create a semaphore with the specified number of permits.
+    pub(crate) fn new(permits: usize) -> Self {
+        Semaphore {
+            permits: Mutex::new(permits),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Acquire a permit, blocking until one is available.
+    pub(crate) fn acquire<'a>(&'a self) -> SemaphoreGuard<'a> {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.available.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphoreGuard { semaphore: self }
+    }
+}
+
+impl<'a> Drop for SemaphoreGuard<'a> {
+    fn drop(&mut self) {
+        *self.semaphore.permits.lock().unwrap() += 1;
+        self.semaphore.available.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use super::Semaphore;
+
+    #[test]
+    fn test_semaphore_limits_concurrency() {
+        let semaphore = Arc::new(Semaphore::new(2));
+        let state = Arc::new(Mutex::new((0, 0))); // (current, max)
+        let threads: Vec<_> = (0..6).map(|_| {
+            let semaphore = semaphore.clone();
+            let state = state.clone();
+            thread::spawn(move || {
+                let _permit = semaphore.acquire();
+                {
+                    let mut state = state.lock().unwrap();
+                    state.0 += 1;
+                    state.1 = ::std::cmp::max(state.0, state.1);
+                }
+                thread::sleep(Duration::from_millis(20));
+                state.lock().unwrap().0 -= 1;
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let state = state.lock().unwrap();
+        assert_eq!(state.0, 0);
+        assert!(state.1 <= 2);
+    }
+
+    #[test]
+    fn test_semaphore_release() {
+        let semaphore = Semaphore::new(1);
+        drop(semaphore.acquire());
+        // Does not block, since the permit was released
+        let _permit = semaphore.acquire();
+    }
+
+}
@@ -0,0 +1,134 @@
+//! A minimal HTTP server emulating the gateway, used in tests.
+
+use std::borrow::Cow;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ::connection::{ClientConfig, Connection, DEFAULT_USER_AGENT};
+
+
+/// A request received by the [`TestServer`](struct.TestServer.html).
+#[derive(Debug, Clone)]
+pub(crate) struct Request {
+    pub(crate) method: String,
+    /// The path, including the query string
+    pub(crate) path: String,
+    /// The headers, with lowercase names
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl Request {
+    /// Return the value of the specified header (lowercase name).
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|h| h.0 == name).map(|h| h.1.as_str())
+    }
+
+    /// Return the body as string.
+    pub(crate) fn body_str(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// A HTTP server on localhost that answers requests with predefined
+/// responses, in order, and records the received requests.
+pub(crate) struct TestServer {
+    url: String,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl TestServer {
+    /// Start a server that answers one request per response.
+    pub(crate) fn start(responses: Vec<(u16, &'static str)>) -> TestServer {
+        TestServer::start_bytes(responses.into_iter().map(|(status, body)| (status, body.as_bytes().to_vec())).collect())
+    }
+
+    /// Start a server that answers one request per response, with binary
+    /// response bodies.
+    pub(crate) fn start_bytes(responses: Vec<(u16, Vec<u8>)>) -> TestServer {
+        TestServer::start_raw(responses.into_iter().map(|(status, body)| {
+            let mut response = format!(
+                "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status, body.len()
+            ).into_bytes();
+            response.extend_from_slice(&body);
+            response
+        }).collect())
+    }
+
+    /// Start a server that answers one request per raw HTTP response.
+    ///
+    /// The connection is closed after writing a response, this can be used
+    /// to send malformed or truncated responses.
+    pub(crate) fn start_raw(responses: Vec<Vec<u8>>) -> TestServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not bind test server");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let requests_clone = requests.clone();
+        thread::spawn(move || {
+            for response in responses {
+                let (stream, _) = match listener.accept() {
+                    Ok(conn) => conn,
+                    Err(_) => return,
+                };
+                let mut reader = BufReader::new(stream);
+                let request = match read_request(&mut reader) {
+                    Ok(request) => request,
+                    Err(_) => return,
+                };
+                requests_clone.lock().unwrap().push(request);
+                let mut stream = reader.into_inner();
+                let _ = stream.write_all(&response);
+            }
+        });
+        TestServer { url: url, requests: requests }
+    }
+
+    /// Return a connection to the server.
+    pub(crate) fn conn(&self) -> Connection {
+        Connection::new(Cow::Owned(self.url.clone()), Cow::Borrowed(DEFAULT_USER_AGENT), ClientConfig::default())
+            .unwrap()
+    }
+
+    /// Return the requests received so far.
+    pub(crate) fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid request");
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let (method, path) = {
+        let mut parts = line.split_whitespace();
+        match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+            _ => return Err(invalid()),
+        }
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_matches(|c| c == '\r' || c == '\n');
+        if line.is_empty() {
+            break;
+        }
+        let colon = line.find(':').ok_or_else(invalid)?;
+        headers.push((line[..colon].trim().to_lowercase(), line[colon + 1..].trim().to_string()));
+    }
+
+    let len = headers.iter()
+        .find(|h| h.0 == "content-length")
+        .and_then(|h| h.1.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+
+    Ok(Request { method: method, path: path, headers: headers, body: body })
+}
@@ -1,21 +1,32 @@
+use std::collections::HashMap;
 use std::fmt;
+use std::str::FromStr;
 use std::string::ToString;
 
-use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
 use mime::Mime;
+use serde::de::{self, Deserialize, Deserializer};
 use serde::ser::{Serialize, Serializer};
-use sodiumoxide::crypto::secretbox::Key;
+use sodiumoxide::crypto::secretbox::{self, Key};
 
 use ::errors::ApiError;
+use ::hex::{decode_hex_fixed, encode_hex};
 
 
 /// A message type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MessageType {
     Text,
     Image,
     Video,
     File,
+    GroupCreate,
+    GroupRename,
+    GroupSetPhoto,
+    VoipCallAnswer,
+    VoipCallHangup,
     DeliveryReceipt,
+    TypingIndicator,
+    DeleteMessage,
 }
 
 impl Into<u8> for MessageType {
@@ -25,13 +36,149 @@ impl Into<u8> for MessageType {
             MessageType::Image => 0x02,
             MessageType::Video => 0x13,
             MessageType::File => 0x17,
+            MessageType::GroupCreate => 0x4a,
+            MessageType::GroupRename => 0x4b,
+            MessageType::GroupSetPhoto => 0x50,
+            MessageType::VoipCallAnswer => 0x61,
+            MessageType::VoipCallHangup => 0x63,
             MessageType::DeliveryReceipt => 0x80,
+            MessageType::TypingIndicator => 0x90,
+            MessageType::DeleteMessage => 0x92,
         }
     }
 }
 
+/// All message types, in the order of their type bytes.
+static ALL_MESSAGE_TYPES: [MessageType; 12] = [
+    MessageType::Text,
+    MessageType::Image,
+    MessageType::Video,
+    MessageType::File,
+    MessageType::GroupCreate,
+    MessageType::GroupRename,
+    MessageType::GroupSetPhoto,
+    MessageType::VoipCallAnswer,
+    MessageType::VoipCallHangup,
+    MessageType::DeliveryReceipt,
+    MessageType::TypingIndicator,
+    MessageType::DeleteMessage,
+];
+
+impl MessageType {
+    /// Return all message types.
+    pub fn all() -> &'static [MessageType] {
+        &ALL_MESSAGE_TYPES
+    }
+
+    /// Return a readable name of the message type, e.g. for logging.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            MessageType::Text => "text",
+            MessageType::Image => "image",
+            MessageType::Video => "video",
+            MessageType::File => "file",
+            MessageType::GroupCreate => "group_create",
+            MessageType::GroupRename => "group_rename",
+            MessageType::GroupSetPhoto => "group_set_photo",
+            MessageType::VoipCallAnswer => "voip_call_answer",
+            MessageType::VoipCallHangup => "voip_call_hangup",
+            MessageType::DeliveryReceipt => "delivery_receipt",
+            MessageType::TypingIndicator => "typing_indicator",
+            MessageType::DeleteMessage => "delete_message",
+        }
+    }
+
+    /// Return the message type with the specified type byte, or `None` if
+    /// the type byte is unknown.
+    ///
+    /// This is the inverse of the `Into<u8>` conversion. (`TryFrom` is not
+    /// available in the Rust versions supported by this crate.)
+    pub fn from_u8(val: u8) -> Option<MessageType> {
+        ALL_MESSAGE_TYPES.iter().cloned().find(|&msgtype| {
+            let byte: u8 = msgtype.into();
+            byte == val
+        })
+    }
+}
+
+bitflags! {
+    /// The message flags defined by the Threema protocol.
+    ///
+    /// The gateway does not accept a raw flag byte. Only `NO_PUSH` and
+    /// `NO_DELIVERY_RECEIPTS` are honored when sending, each is mapped to its
+    /// own request parameter, `noPush` and `noDeliveryReceipts`. A flag that
+    /// is not set never adds a parameter, so the empty set sends a push
+    /// notification and allows delivery receipts, like the gateway does by
+    /// default. The other flags are set by the server and are provided for
+    /// completeness: `SEND_PUSH` in particular does not control the push
+    /// notification when sending.
+    ///
+    /// The default value is `SEND_PUSH`, matching the behavior of the gateway.
+    ///
+    /// Whether a push notification is sent is the only push setting the
+    /// gateway supports. There are no parameters for priority, sound or a
+    /// custom push text: the notification sound is chosen by the recipient,
+    /// and since the gateway cannot read end-to-end encrypted messages, the
+    /// notification only shows the sender. The sender name shown is the
+    /// public nickname of the gateway ID, which is configured in the gateway
+    /// administration and cannot be set per message.
+    pub struct MessageFlags: u8 {
+        /// Send a push notification to the recipient.
+        const SEND_PUSH = 0x01;
+        /// Do not queue the message on the server if the recipient is offline.
+        const NO_SERVER_QUEUING = 0x02;
+        /// Do not send a server acknowledgement.
+        const NO_SERVER_ACK = 0x04;
+        /// The message is a group message.
+        const GROUP_MESSAGE = 0x10;
+        /// Only queue the message for a short time on the server.
+        const SHORT_LIVED = 0x20;
+        /// Do not send a push notification to the recipient.
+        ///
+        /// This is not a protocol flag, it only sets the `noPush` request
+        /// parameter of the gateway.
+        const NO_PUSH = 0x40;
+        /// Ask the recipient not to send delivery receipts.
+        const NO_DELIVERY_RECEIPTS = 0x80;
+    }
+}
+
+impl Default for MessageFlags {
+    fn default() -> Self {
+        MessageFlags::SEND_PUSH
+    }
+}
+
+impl MessageFlags {
+    /// Return the request parameters corresponding to these flags.
+    pub(crate) fn to_params(&self) -> HashMap<String, String> {
+        let mut params = HashMap::new();
+        if self.contains(MessageFlags::NO_PUSH) {
+            params.insert("noPush".into(), "1".into());
+        }
+        if self.contains(MessageFlags::NO_DELIVERY_RECEIPTS) {
+            params.insert("noDeliveryReceipts".into(), "1".into());
+        }
+        params
+    }
+}
+
+impl Serialize for MessageFlags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.bits())
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageFlags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bits = u8::deserialize(deserializer)?;
+        MessageFlags::from_bits(bits)
+            .ok_or_else(|| de::Error::custom(format!("Invalid message flags: {:#04x}", bits)))
+    }
+}
+
 /// A file message.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMessage {
     #[serde(rename="b")]
     pub file_blob_id: BlobId,
@@ -39,10 +186,10 @@ pub struct FileMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub thumbnail_blob_id: Option<BlobId>,
     #[serde(rename="k")]
-    #[serde(serialize_with = "key_to_hex")]
-    pub blob_encryption_key: Key,
+    pub blob_encryption_key: BlobKey,
     #[serde(rename="m")]
     #[serde(serialize_with = "serialize_to_string")]
+    #[serde(deserialize_with = "deserialize_from_str")]
     pub mime_type: Mime,
     #[serde(rename="n")]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -53,23 +200,26 @@ pub struct FileMessage {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
     #[serde(rename="i")]
+    #[serde(default)]
     pub reserved: u8,
 }
 
 impl FileMessage {
     /// Create a new file message.
-    pub fn new(file_blob_id: BlobId,
-               thumbnail_blob_id: Option<BlobId>,
-               blob_encryption_key: Key,
-               mime_type: Mime,
-               file_name: Option<String>,
-               file_size_bytes: u32,
-               description: Option<String>)
-               -> Self {
+    ///
+    /// The key can be a `BlobKey` or a sodiumoxide `secretbox::Key`.
+    pub fn new<K: Into<BlobKey>>(file_blob_id: BlobId,
+                                 thumbnail_blob_id: Option<BlobId>,
+                                 blob_encryption_key: K,
+                                 mime_type: Mime,
+                                 file_name: Option<String>,
+                                 file_size_bytes: u32,
+                                 description: Option<String>)
+                                 -> Self {
         FileMessage {
             file_blob_id: file_blob_id,
             thumbnail_blob_id: thumbnail_blob_id,
-            blob_encryption_key: blob_encryption_key,
+            blob_encryption_key: blob_encryption_key.into(),
             mime_type: mime_type,
             file_name: file_name,
             file_size_bytes: file_size_bytes,
@@ -77,6 +227,72 @@ impl FileMessage {
             reserved: 0,
         }
     }
+
+    /// Guess the mime type from a file name.
+    ///
+    /// If the type cannot be determined from the file extension,
+    /// `application/octet-stream` is returned.
+    ///
+    /// This requires the `mime_guess` feature.
+    #[cfg(feature = "mime_guess")]
+    pub fn guess_mime_type(file_name: &str) -> Mime {
+        ::mime_guess::from_path(file_name).first_or_octet_stream()
+    }
+}
+
+/// A symmetric key used to encrypt the file and thumbnail blobs of a file
+/// message (libsodium secretbox).
+///
+/// The blob key is not related to the keys used to encrypt the message
+/// itself, which are the private key of the sender and the public key of the
+/// recipient. A new random blob key is generated for every file, and it is
+/// sent to the recipient inside the encrypted file message. Use
+/// [`encrypt_blob`](fn.encrypt_blob.html) and
+/// [`decrypt_blob`](fn.decrypt_blob.html) to encrypt and decrypt the file
+/// data with it.
+#[derive(PartialEq, Eq, Clone)]
+pub struct BlobKey(pub [u8; 32]);
+
+impl BlobKey {
+    /// Create a new BlobKey.
+    pub fn new(key: [u8; 32]) -> Self {
+        BlobKey(key)
+    }
+
+    /// Generate a new random BlobKey.
+    pub fn generate() -> Self {
+        BlobKey::from(secretbox::gen_key())
+    }
+
+    /// Return the key as libsodium secretbox key.
+    pub(crate) fn to_secretbox_key(&self) -> Key {
+        Key(self.0)
+    }
+}
+
+impl From<Key> for BlobKey {
+    fn from(key: Key) -> Self {
+        BlobKey(key.0)
+    }
+}
+
+impl fmt::Debug for BlobKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BlobKey(***)")
+    }
+}
+
+impl Serialize for BlobKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode_hex(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for BlobKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        decode_hex_fixed(&s).map(BlobKey).map_err(de::Error::custom)
+    }
 }
 
 /// A blob ID. Must contain exactly 16 lowercase hexadecimal characters.
@@ -91,27 +307,142 @@ impl BlobId {
 
     /// Create a new BlobId from a 32 character hexadecimal String.
     pub fn from_str(id: &str) -> Result<Self, ApiError> {
-        let bytes = HEXLOWER_PERMISSIVE.decode(id.as_bytes()).map_err(|_| ApiError::BadBlobId)?;
-        if bytes.len() != 16 {
-            return Err(ApiError::BadBlobId);
-        }
-        let mut arr = [0; 16];
-        for i in 0..bytes.len() {
-            arr[i] = bytes[i];
-        }
-        Ok(BlobId(arr))
+        decode_hex_fixed(id).map(BlobId).map_err(|_| ApiError::BadBlobId)
+    }
+
+    /// Return the raw bytes of the blob ID.
+    ///
+    /// In message payloads, the blob ID is embedded as these 16 bytes, in the
+    /// same order as in the hexadecimal representation. For example, the body
+    /// of an image message consists of the blob ID, the image size (little
+    /// endian `u32`) and the 24 byte nonce:
+    ///
+    /// ```
+    /// use threema_gateway::BlobId;
+    ///
+    /// let blob_id = BlobId::from_str("00112233445566778899aabbccddeeff").unwrap();
+    /// let size: u32 = 258;
+    /// let nonce = [0x42; 24];
+    ///
+    /// let mut payload = Vec::with_capacity(44);
+    /// payload.extend_from_slice(blob_id.as_bytes());
+    /// payload.extend_from_slice(&[size as u8, (size >> 8) as u8, (size >> 16) as u8, (size >> 24) as u8]);
+    /// payload.extend_from_slice(&nonce);
+    ///
+    /// assert_eq!(&payload[0..4], &[0x00, 0x11, 0x22, 0x33]);
+    /// assert_eq!(&payload[16..20], &[0x02, 0x01, 0x00, 0x00]);
+    /// ```
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
     }
 }
 
 impl fmt::Display for BlobId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", HEXLOWER.encode(&self.0))
+        write!(f, "{}", encode_hex(&self.0))
     }
 }
 
 impl Serialize for BlobId {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_str(&HEXLOWER.encode(&self.0))
+        serializer.serialize_str(&encode_hex(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for BlobId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        BlobId::from_str(&s).map_err(|_| de::Error::custom(format!("Invalid blob ID: {}", s)))
+    }
+}
+
+/// Whether a blob should be marked as done when downloading it.
+///
+/// Blobs are stored on the blob server until they expire. Once a blob is
+/// marked as done, the server may delete it right away, so that later
+/// downloads fail.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BlobDownloadMode {
+    /// Leave the blob on the server until it expires.
+    ///
+    /// Use this if the blob was sent to multiple recipients (e.g. to the
+    /// members of a group), which all need to download it.
+    Peek,
+    /// Mark the blob as done (`done=1`), allowing the server to delete it.
+    ///
+    /// Use this if you are the only recipient of the blob.
+    Consume,
+}
+
+/// A message ID. Must contain exactly 16 hexadecimal characters.
+///
+/// Message IDs are chosen by the sender of a message. They are used to refer
+/// to a message, for example in delivery receipts.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct MessageId(pub [u8; 8]);
+
+impl MessageId {
+    /// Create a new MessageId.
+    pub fn new(id: [u8; 8]) -> Self {
+        MessageId(id)
+    }
+
+    /// Create a new MessageId from a 16 character hexadecimal String.
+    pub fn from_str(id: &str) -> Result<Self, ApiError> {
+        decode_hex_fixed(id).map(MessageId).map_err(|_| ApiError::BadMessageId)
+    }
+}
+
+impl fmt::Display for MessageId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", encode_hex(&self.0))
+    }
+}
+
+impl Serialize for MessageId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode_hex(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for MessageId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        MessageId::from_str(&s).map_err(|_| de::Error::custom(format!("Invalid message ID: {}", s)))
+    }
+}
+
+/// A group ID.
+///
+/// Group IDs are chosen by the group creator. A group is identified by the
+/// combination of the creator's Threema ID and the group ID.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct GroupId(pub [u8; 8]);
+
+impl GroupId {
+    /// Create a new GroupId.
+    pub fn new(id: [u8; 8]) -> Self {
+        GroupId(id)
+    }
+}
+
+impl fmt::Display for GroupId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", encode_hex(&self.0))
+    }
+}
+
+impl Serialize for GroupId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode_hex(&self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for GroupId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        decode_hex_fixed(&s).map(GroupId)
+            .map_err(|_| de::Error::custom(format!("Invalid group ID: {}", s)))
     }
 }
 
@@ -121,8 +452,11 @@ fn serialize_to_string<S, T>(val: &T, serializer: S)
     serializer.serialize_str(&val.to_string())
 }
 
-fn key_to_hex<S: Serializer>(val: &Key, serializer: S) -> Result<S::Ok, S::Error> {
-    serializer.serialize_str(&HEXLOWER.encode(&val.0))
+fn deserialize_from_str<'de, D, T>(deserializer: D)
+        -> Result<T, D::Error>
+        where D: Deserializer<'de>, T: FromStr, T::Err: fmt::Display {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(de::Error::custom)
 }
 
 
@@ -131,7 +465,15 @@ mod test {
     use std::collections::HashMap;
     use serde_json as json;
     use sodiumoxide::crypto::secretbox::Key;
-    use super::{BlobId, FileMessage};
+    use super::{BlobId, BlobKey, FileMessage, GroupId, MessageFlags, MessageId, MessageType};
+
+    #[test]
+    #[cfg(feature = "mime_guess")]
+    fn test_guess_mime_type() {
+        assert_eq!(FileMessage::guess_mime_type("photo.JPG").to_string(), "image/jpeg");
+        assert_eq!(FileMessage::guess_mime_type("report.pdf").to_string(), "application/pdf");
+        assert_eq!(FileMessage::guess_mime_type("noextension").to_string(), "application/octet-stream");
+    }
 
     #[test]
     fn test_blob_id_from_str() {
@@ -149,7 +491,7 @@ mod test {
 
     #[test]
     fn test_serialize_to_string_minimal() {
-        let pk = Key([1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4]);
+        let pk = BlobKey([1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4]);
         let msg = FileMessage {
             file_blob_id: BlobId::from_str("0123456789abcdef0123456789abcdef").unwrap(),
             thumbnail_blob_id: None,
@@ -176,7 +518,7 @@ mod test {
 
     #[test]
     fn test_serialize_to_string_full() {
-        let pk = Key([1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4]);
+        let pk = BlobKey([1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4,1,2,3,4]);
         let msg = FileMessage {
             file_blob_id: BlobId::from_str("0123456789abcdef0123456789abcdef").unwrap(),
             thumbnail_blob_id: Some(BlobId::from_str("abcdef0123456789abcdef0123456789").unwrap()),
@@ -201,4 +543,114 @@ mod test {
         assert_eq!(deserialized.get("d").unwrap(), "This is a fancy file");
     }
 
+    #[test]
+    fn test_deserialize_file_message() {
+        let data = r#"{"b":"0123456789abcdef0123456789abcdef","k":"0102030401020304010203040102030401020304010203040102030401020304","m":"application/pdf","n":"secret.pdf","s":2048,"j":0}"#;
+        let msg: FileMessage = json::from_str(data).unwrap();
+        assert_eq!(msg.file_blob_id, BlobId::from_str("0123456789abcdef0123456789abcdef").unwrap());
+        assert_eq!(msg.thumbnail_blob_id, None);
+        assert_eq!(msg.blob_encryption_key.0[0..4], [1, 2, 3, 4]);
+        assert_eq!(msg.mime_type.to_string(), "application/pdf");
+        assert_eq!(msg.file_name, Some("secret.pdf".into()));
+        assert_eq!(msg.file_size_bytes, 2048);
+        assert_eq!(msg.description, None);
+        assert_eq!(msg.reserved, 0);
+    }
+
+    #[test]
+    fn test_deserialize_file_message_bad_key() {
+        let data = r#"{"b":"0123456789abcdef0123456789abcdef","k":"01020304","m":"application/pdf","s":2048}"#;
+        assert!(json::from_str::<FileMessage>(data).is_err());
+    }
+
+    #[test]
+    fn test_blob_key() {
+        let key = BlobKey::from(Key([7; 32]));
+        assert_eq!(key, BlobKey::new([7; 32]));
+        assert_eq!(key.to_secretbox_key(), Key([7; 32]));
+        assert_eq!(format!("{:?}", key), "BlobKey(***)");
+        assert!(BlobKey::generate() != BlobKey::generate());
+
+        let file = FileMessage::new(BlobId::new([2; 16]), None, Key([7; 32]), "text/plain".parse().unwrap(), None, 4, None);
+        assert_eq!(file.blob_encryption_key, key);
+    }
+
+    #[test]
+    fn test_message_id_from_str() {
+        assert_eq!(
+            MessageId::from_str("0001020304abcdEF").unwrap(),
+            MessageId::new([0, 1, 2, 3, 4, 0xab, 0xcd, 0xef])
+        );
+        assert!(MessageId::from_str("0001020304abcd").is_err());
+        assert!(MessageId::from_str("0001020304abcdefgh").is_err());
+        assert_eq!(MessageId::new([0, 1, 2, 3, 4, 0xab, 0xcd, 0xef]).to_string(), "0001020304abcdef");
+    }
+
+    #[test]
+    fn test_hex_output_lowercase() {
+        let blob_id = BlobId::from_str("00112233445566778899AABBCCDDEEFF").unwrap();
+        assert_eq!(blob_id.to_string(), "00112233445566778899aabbccddeeff");
+        assert_eq!(json::to_string(&blob_id).unwrap(), r#""00112233445566778899aabbccddeeff""#);
+
+        let message_id = MessageId::from_str("AABBCCDDEEFF0011").unwrap();
+        assert_eq!(message_id.to_string(), "aabbccddeeff0011");
+        assert_eq!(json::to_string(&message_id).unwrap(), r#""aabbccddeeff0011""#);
+
+        let group_id: GroupId = json::from_str(r#""AABBCCDDEEFF0011""#).unwrap();
+        assert_eq!(group_id.to_string(), "aabbccddeeff0011");
+        assert_eq!(json::to_string(&group_id).unwrap(), r#""aabbccddeeff0011""#);
+
+        let key_hex = "AABBCCDDEEFF00112233445566778899AABBCCDDEEFF00112233445566778899";
+        let blob_key: BlobKey = json::from_str(&format!("\"{}\"", key_hex)).unwrap();
+        assert_eq!(json::to_string(&blob_key).unwrap(), format!("\"{}\"", key_hex.to_lowercase()));
+    }
+
+    #[test]
+    fn test_message_flags_default() {
+        let flags = MessageFlags::default();
+        assert_eq!(flags.bits(), 0x01);
+        assert!(flags.to_params().is_empty());
+    }
+
+    #[test]
+    fn test_message_flags_params() {
+        assert!(MessageFlags::empty().to_params().is_empty());
+
+        let params = MessageFlags::NO_DELIVERY_RECEIPTS.to_params();
+        assert_eq!(params.len(), 1);
+        assert_eq!(params.get("noDeliveryReceipts").unwrap(), "1");
+
+        let params = MessageFlags::NO_PUSH.to_params();
+        assert_eq!(params.len(), 1);
+        assert_eq!(params.get("noPush").unwrap(), "1");
+
+        let params = (MessageFlags::NO_PUSH | MessageFlags::NO_DELIVERY_RECEIPTS).to_params();
+        assert_eq!(params.len(), 2);
+        assert_eq!(params.get("noPush").unwrap(), "1");
+        assert_eq!(params.get("noDeliveryReceipts").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_message_type_all() {
+        let all = MessageType::all();
+        assert_eq!(all.len(), 12);
+        for &msgtype in all {
+            assert!(!msgtype.name().is_empty());
+            let byte: u8 = msgtype.into();
+            assert_eq!(MessageType::from_u8(byte), Some(msgtype));
+        }
+        assert_eq!(MessageType::DeliveryReceipt.name(), "delivery_receipt");
+        assert_eq!(MessageType::from_u8(0x17), Some(MessageType::File));
+        assert_eq!(MessageType::from_u8(0x00), None);
+    }
+
+    #[test]
+    fn test_message_flags_serialize() {
+        let flags = MessageFlags::SEND_PUSH | MessageFlags::NO_DELIVERY_RECEIPTS;
+        assert_eq!(json::to_string(&flags).unwrap(), "129");
+        assert_eq!(json::from_str::<MessageFlags>("129").unwrap(), flags);
+        assert_eq!(json::from_str::<MessageFlags>("64").unwrap(), MessageFlags::NO_PUSH);
+        assert!(json::from_str::<MessageFlags>("8").is_err());
+    }
+
 }
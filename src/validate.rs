@@ -0,0 +1,89 @@
+//! Offline validation of E2E send configurations.
+
+use ::connection::{MAX_E2E_TEXT_LEN, check_control_chars, is_valid_threema_id, text_byte_length};
+use ::errors::{ApiError, ValidationError};
+use ::hex::decode_hex_fixed;
+
+
+/// Validate the parameters of an E2E text message, without sending it.
+///
+/// This checks that `to` is a valid Threema ID, that the hex encoded public
+/// key of the recipient and your own hex encoded private key are 32 bytes
+/// long, and that the text is neither empty, too long nor contains control
+/// characters. The length limit is the same that
+/// [`E2eApi::encrypt_text_msg`](struct.E2eApi.html#method.encrypt_text_msg)
+/// enforces. Nothing is sent to the gateway, so the recipient ID is not
+/// checked for existence. The nonce is generated when encrypting the
+/// message and is always valid.
+///
+/// Unlike the checks performed when sending, all problems are returned at
+/// once, which makes debugging a new setup easier.
+pub fn validate_e2e_send(to: &str, public_key: &str, private_key: &str, text: &str)
+                         -> Result<(), Vec<ValidationError>> {
+    let mut errors = Vec::new();
+    if !is_valid_threema_id(to) {
+        errors.push(ValidationError::BadRecipientId(to.to_string()));
+    }
+    if let Err(e) = decode_hex_fixed::<[u8; 32]>(public_key) {
+        errors.push(ValidationError::BadPublicKey(e.to_string()));
+    }
+    if let Err(e) = decode_hex_fixed::<[u8; 32]>(private_key) {
+        errors.push(ValidationError::BadPrivateKey(e.to_string()));
+    }
+    let len = text_byte_length(text);
+    if len == 0 {
+        errors.push(ValidationError::EmptyMessage);
+    } else if len > MAX_E2E_TEXT_LEN {
+        errors.push(ValidationError::MessageTooLong(len, MAX_E2E_TEXT_LEN));
+    }
+    if let Err(ApiError::ControlCharacter(c, index)) = check_control_chars(text) {
+        errors.push(ValidationError::ControlCharacter(c, index));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ::errors::ValidationError;
+    use ::connection::MAX_E2E_TEXT_LEN;
+    use super::validate_e2e_send;
+
+    const PUBLIC_KEY: &'static str = "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f";
+    const PRIVATE_KEY: &'static str = "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a";
+
+    #[test]
+    fn test_validate_e2e_send_valid() {
+        assert_eq!(validate_e2e_send("ECHOECHO", PUBLIC_KEY, PRIVATE_KEY, "Hello"), Ok(()));
+        assert_eq!(validate_e2e_send("*3MAGWID", PUBLIC_KEY, PRIVATE_KEY, "Line 1\nLine 2"), Ok(()));
+        let text = "a".repeat(MAX_E2E_TEXT_LEN);
+        assert_eq!(validate_e2e_send("ECHOECHO", PUBLIC_KEY, PRIVATE_KEY, &text), Ok(()));
+    }
+
+    #[test]
+    fn test_validate_e2e_send_all_errors() {
+        let errors = validate_e2e_send("ECHO", &PUBLIC_KEY[2..], "xyz", "Hi\u{0}").unwrap_err();
+        assert_eq!(errors, vec![
+            ValidationError::BadRecipientId("ECHO".into()),
+            ValidationError::BadPublicKey("Invalid length: expected 32 bytes, got 31".into()),
+            ValidationError::BadPrivateKey("invalid length at 2".into()),
+            ValidationError::ControlCharacter('\u{0}', 2),
+        ]);
+        assert_eq!(validate_e2e_send("echoecho", PUBLIC_KEY, PRIVATE_KEY, "Hi"),
+                   Err(vec![ValidationError::BadRecipientId("echoecho".into())]));
+        assert_eq!(validate_e2e_send("ECHO*CHO", PUBLIC_KEY, PRIVATE_KEY, "Hi"),
+                   Err(vec![ValidationError::BadRecipientId("ECHO*CHO".into())]));
+    }
+
+    #[test]
+    fn test_validate_e2e_send_text() {
+        assert_eq!(validate_e2e_send("ECHOECHO", PUBLIC_KEY, PRIVATE_KEY, ""),
+                   Err(vec![ValidationError::EmptyMessage]));
+        let text = "a".repeat(MAX_E2E_TEXT_LEN + 1);
+        assert_eq!(validate_e2e_send("ECHOECHO", PUBLIC_KEY, PRIVATE_KEY, &text),
+                   Err(vec![ValidationError::MessageTooLong(MAX_E2E_TEXT_LEN + 1, MAX_E2E_TEXT_LEN)]));
+    }
+}
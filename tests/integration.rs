@@ -0,0 +1,75 @@
+//! Integration tests against the real Threema Gateway.
+//!
+//! These tests are skipped unless the `THREEMA_INTEGRATION_TEST` environment
+//! variable is set. They need the following variables as well:
+//!
+//! - `THREEMA_FROM`: Your E2E gateway ID
+//! - `THREEMA_SECRET`: Your gateway secret
+//! - `THREEMA_PRIVATE_KEY`: Your hex encoded private key
+//!
+//! Messages are sent to `ECHOECHO`, which echoes them back. The echoed message
+//! is delivered to the callback URL of your gateway ID, which cannot be
+//! observed from here. Instead, encryption and decryption are verified with a
+//! message to yourself, using the public key the gateway has stored for your ID.
+//!
+//! Note that these tests cost credits.
+
+extern crate threema_gateway;
+
+use std::env;
+use threema_gateway::{ApiBuilder, E2eApi, IncomingMessage, Message, MessageId, RecipientKey};
+
+
+/// Return an `E2eApi` instance, or `None` if integration tests are disabled.
+fn api() -> Option<E2eApi> {
+    if env::var("THREEMA_INTEGRATION_TEST").is_err() {
+        println!("THREEMA_INTEGRATION_TEST is not set, skipping integration test");
+        return None;
+    }
+    let var = |name: &str| env::var(name).unwrap_or_else(|_| panic!("{} must be set", name));
+    let api = ApiBuilder::new(var("THREEMA_FROM"), var("THREEMA_SECRET"))
+        .with_private_key_str(&var("THREEMA_PRIVATE_KEY"))
+        .and_then(|builder| builder.into_e2e())
+        .expect("Could not create E2eApi");
+    Some(api)
+}
+
+#[test]
+fn test_lookup_echoecho_pubkey() {
+    let api = match api() { Some(api) => api, None => return };
+    let pubkey = api.lookup_pubkey("ECHOECHO").unwrap();
+    assert!(RecipientKey::from_str(&pubkey).is_ok());
+}
+
+#[test]
+fn test_send_text_to_echoecho() {
+    let api = match api() { Some(api) => api, None => return };
+    let pubkey = api.lookup_pubkey("ECHOECHO").unwrap();
+    let recipient_key = RecipientKey::from_str(&pubkey).unwrap();
+    let encrypted = api.encrypt_text_msg("Integration test", &recipient_key).unwrap();
+    let msg_id = api.send("ECHOECHO", &encrypted).unwrap();
+    assert!(MessageId::from_str(msg_id.trim()).is_ok());
+}
+
+#[test]
+fn test_encrypt_decrypt_roundtrip() {
+    let api = match api() { Some(api) => api, None => return };
+    let own_id = env::var("THREEMA_FROM").unwrap();
+    let own_key = RecipientKey::from_str(&api.lookup_pubkey(&own_id).unwrap()).unwrap();
+
+    let encrypted = api.encrypt_text_msg("Round trip", &own_key).unwrap();
+    let incoming = IncomingMessage {
+        from: own_id.clone(),
+        to: own_id.clone(),
+        message_id: MessageId::new([0; 8]),
+        date: 0,
+        nonce: encrypted.nonce.to_vec(),
+        box_data: encrypted.ciphertext.clone(),
+        mac: vec![],
+        nickname: None,
+    };
+    match api.decrypt_msg_from(&incoming).unwrap() {
+        Message::Text(text) => assert_eq!(text, "Round trip"),
+        other => panic!("Unexpected message: {:?}", other),
+    }
+}